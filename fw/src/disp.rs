@@ -2,9 +2,12 @@ use core::cell::RefCell;
 
 use cortex_m::interrupt::Mutex;
 use embedded_hal::digital::OutputPin;
+use hdsplib::hdsp::{self, Frame, Glyph, UdcBitmap, NUM_DIGITS, NUM_UDC};
+use hdsplib::display::ScrollConfig;
+use hdsplib::markup::{AnchoredEvent, Event, Markup, MAX_MARKUP_EVENTS, MAX_MARKUP_GLYPHS};
+use hdsplib::rsvp::{self, DisplayMode, Rsvp, RsvpConfig};
 use hdsplib::utils::udiv_ceil;
 use rp235x_hal::gpio::PinState;
-use rtt_target::rprintln;
 // use stm32f4xx_hal::{
 //     gpio::{Output, Pin},
 //     interrupt,
@@ -28,6 +31,10 @@ use hal::{
 use rp235x_hal as hal;
 
 pub const VCOM_PERIOD_MS: u32 = 250;
+/// Period at which everything is written to the display again, in case a
+/// glitch or a power dip cleared it. The display cannot be read back on this
+/// board to find out.
+pub const REASSERT_MS: u64 = 5000;
 
 pub struct Display<
     RstPin: PinId,
@@ -77,8 +84,35 @@ pub struct Display<
         Pin<D7Pin, FunctionSio<SioOutput>, PullDown>,
     >,
 
-    text: heapless::String<128>,
+    text: heapless::Vec<Glyph, MAX_MARKUP_GLYPHS>,
+    /// Brightness changes and pauses anchored to the text
+    events: heapless::Vec<AnchoredEvent, MAX_MARKUP_EVENTS>,
     text_scroll_pos: usize,
+    mode: DisplayMode,
+    rsvp: Rsvp,
+    /// Part of the text shown in word by word mode
+    rsvp_step: Option<rsvp::Step>,
+    /// When the text moves next, `None` right after it changed
+    next_step_ms: Option<u64>,
+    scroll: ScrollConfig,
+    /// Control word set by the host and the text, effects only change the one
+    /// of the frame
+    control: u8,
+    /// UDCs defined by the host
+    udcs: [UdcBitmap; NUM_UDC],
+    /// Digits flashing whatever the text shows
+    flash_mask: u8,
+    /// Reset the module before the next flush
+    pending_reset: bool,
+
+    /// Contents we want on the display
+    frame: Frame,
+    /// Contents last written to the display, only valid after a full write
+    shadow: Frame,
+    shadow_valid: bool,
+    next_reassert_ms: u64,
+    /// Full rewrites forced by `reassert` since boot
+    rewrites: u32,
 }
 
 impl<
@@ -162,8 +196,24 @@ impl<
             rd,
 
             data_bus: GpioBus8::new((d0, d1, d2, d3, d4, d5, d6, d7)),
-            text: heapless::String::new(),
+            text: heapless::Vec::new(),
+            events: heapless::Vec::new(),
             text_scroll_pos: 0,
+            mode: DisplayMode::Scroll,
+            rsvp: Rsvp::new(RsvpConfig::DEFAULT),
+            rsvp_step: None,
+            next_step_ms: None,
+            scroll: ScrollConfig::DEFAULT,
+            control: 0x00,
+            udcs: [hdsp::UDC_BLANK; NUM_UDC],
+            flash_mask: 0x00,
+            pending_reset: false,
+
+            frame: Frame::new(),
+            shadow: Frame::new(),
+            shadow_valid: false,
+            next_reassert_ms: REASSERT_MS,
+            rewrites: 0,
         }
     }
 
@@ -175,6 +225,9 @@ impl<
         self.rst.set_high().unwrap();
         self.ce.set_low().unwrap();
         delay.delay_us(1000);
+
+        // The reset clears every RAM of the module, everything must be written again
+        self.shadow_valid = false;
     }
 
     pub fn init(&mut self, delay: &mut impl embedded_hal::delay::DelayNs) {
@@ -197,12 +250,41 @@ impl<
 
         self.address_bus.set(0x18 | (pos & 0x07));
 
-        // Set the data
-        self.data_bus.set(data & 0x7F);
+        // Set the data, D7 selects a UDC instead of an ASCII character
+        self.data_bus.set(data);
+
+        self.latch_input(delay);
+    }
+
+    pub fn write_udc(&mut self, index: u8, bitmap: &UdcBitmap, delay: &mut impl embedded_hal::delay::DelayNs) {
+        // UDC address register
+        self.address_bus.set(0x00);
+        self.data_bus.set(index & 0x0F);
+        self.latch_input(delay);
+
+        // UDC RAM, A0-A2 select the row
+        for (row, &data) in bitmap.iter().enumerate() {
+            self.address_bus.set(0x08 | row as u8);
+            self.data_bus.set(data & hdsp::UDC_ROW_MASK);
+            self.latch_input(delay);
+        }
+    }
 
+    pub fn write_control_word(&mut self, control: u8, delay: &mut impl embedded_hal::delay::DelayNs) {
+        self.address_bus.set(0x10);
+        self.data_bus.set(control);
         self.latch_input(delay);
     }
 
+    pub fn write_flash(&mut self, pos: u8, enabled: bool, delay: &mut impl embedded_hal::delay::DelayNs) {
+        // FL low selects the flash RAM, A0-A2 select the digit
+        self.fl.set_low().unwrap();
+        self.address_bus.set(pos & 0x07);
+        self.data_bus.set(enabled as u8);
+        self.latch_input(delay);
+        self.fl.set_high().unwrap();
+    }
+
     pub fn latch_input(&mut self, delay: &mut impl embedded_hal::delay::DelayNs) {
         self.wr.set_low().unwrap();
         self.ce.set_low().unwrap();
@@ -213,53 +295,280 @@ impl<
 
     pub fn set_text<const N: usize>(&mut self, text: &heapless::String<N>) {
         self.text.clear();
-        self.text.push_str(text).unwrap();
+        self.events.clear();
+        for c in text.bytes() {
+            self.text.push(Glyph::new(c & 0x7F)).unwrap();
+        }
+        self.restart();
+    }
+
+    /// Replaces the text with a parsed markup message, starting from the
+    /// beginning and applying the events of the glyphs already visible
+    pub fn set_markup(&mut self, markup: &Markup) {
+        self.text.clone_from(&markup.glyphs);
+        self.events.clone_from(&markup.events);
+        self.restart();
+    }
+
+    /// Removes the text, the UDCs defined by the host and the flash attributes
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.events.clear();
+        self.udcs = [hdsp::UDC_BLANK; NUM_UDC];
+        self.flash_mask = 0x00;
+        self.restart();
+    }
+
+    /// Clears the display and puts every setting back to its default. The
+    /// module itself is reset before the next flush.
+    pub fn reset_defaults(&mut self) {
+        self.mode = DisplayMode::Scroll;
+        self.rsvp.config = RsvpConfig::DEFAULT;
+        self.scroll = ScrollConfig::DEFAULT;
+        self.control = 0x00;
+        self.clear();
+        self.pending_reset = true;
+    }
+
+    pub fn set_brightness(&mut self, level: u8) {
+        self.control = (self.control & !hdsp::CW_BRIGHTNESS_MASK) | hdsp::brightness_to_control(level);
+    }
+
+    /// Sets the control word, except for the clear bit
+    pub fn set_control(&mut self, control: u8) {
+        self.control = control & !hdsp::CW_CLEAR;
+    }
+
+    pub fn define_udc(&mut self, index: usize, bitmap: UdcBitmap) {
+        self.udcs[index] = bitmap;
+    }
+
+    /// Sets the digits flashing, one bit per digit with bit 0 the leftmost one
+    pub fn set_flash_mask(&mut self, digits: u8) {
+        self.flash_mask = digits;
+    }
+
+    pub fn set_scroll_config(&mut self, scroll: ScrollConfig) {
+        self.scroll = scroll;
+    }
+
+    /// Switches between scrolling and word by word display, restarting the text
+    pub fn set_mode(&mut self, mode: DisplayMode, config: RsvpConfig) {
+        self.mode = mode;
+        self.rsvp.config = config;
+        self.restart();
+    }
+
+    fn restart(&mut self) {
+        self.text_scroll_pos = 0;
+        self.rsvp.restart();
+        self.rsvp_step = None;
+        self.next_step_ms = None;
+
+        // Word by word, the events fire along with the first word
+        if self.mode == DisplayMode::Scroll {
+            self.fire_events(0..=NUM_DIGITS);
+        }
+    }
+
+    /// Replaces the text with a new version of the same message, keeping the
+    /// scroll position and without firing the events again
+    pub fn refresh_markup(&mut self, markup: &Markup) {
+        self.text.clone_from(&markup.glyphs);
+        self.events.clone_from(&markup.events);
+        if self.text_scroll_pos >= self.text.len() + NUM_DIGITS {
+            self.text_scroll_pos = 0;
+        }
+        // The word shown is gone, show the next one right away
+        if self.rsvp_step.is_some_and(|step| step.end() > self.text.len()) {
+            self.rsvp_step = None;
+            self.next_step_ms = None;
+        }
+    }
+
+    pub fn text(&self) -> &heapless::Vec<Glyph, MAX_MARKUP_GLYPHS> {
+        &self.text
+    }
+
+    /// Position of the leftmost digit in the text
+    pub fn scroll_pos(&self) -> usize {
+        self.text_scroll_pos
+    }
+
+    pub fn scroll_config(&self) -> ScrollConfig {
+        self.scroll
+    }
+
+    pub fn control(&self) -> u8 {
+        self.control
+    }
+
+    pub fn udcs(&self) -> &[UdcBitmap; NUM_UDC] {
+        &self.udcs
+    }
+
+    pub fn flash_mask(&self) -> u8 {
+        self.flash_mask
+    }
+
+    pub fn mode(&self) -> (DisplayMode, RsvpConfig) {
+        (self.mode, self.rsvp.config)
     }
 
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn frame_mut(&mut self) -> &mut Frame {
+        &mut self.frame
+    }
+
+    /// Renders the text and scroll position into the frame and writes it
     pub fn write(&mut self, delay: &mut impl embedded_hal::delay::DelayNs) {
-        let current_text = self.text.clone();
-
-        let mut text = "";
-        let mut empty_beginning = 0;
-        if self.text_scroll_pos >= self.text.len() {
-            let p = self.text_scroll_pos - self.text.len();
-            text = &current_text[0..p];
-            empty_beginning = 8 - p;
-        } else {
-            if self.text_scroll_pos + 8 > self.text.len() {
-                text = &current_text[self.text_scroll_pos..];
-            } else {
-                text = &current_text[self.text_scroll_pos..self.text_scroll_pos + 8];
+        self.render_text();
+        self.flush(delay);
+    }
+
+    /// Writes the parts of the frame that differ from what the display holds
+    pub fn flush(&mut self, delay: &mut impl embedded_hal::delay::DelayNs) {
+        if self.pending_reset {
+            self.pending_reset = false;
+            self.reset(delay);
+        }
+
+        let frame = self.frame;
+        let full = !self.shadow_valid;
+
+        if full || frame.control != self.shadow.control {
+            // Clearing is done through the frame so the shadow stays in sync
+            self.write_control_word(frame.control & !hdsp::CW_CLEAR, delay);
+        }
+
+        for i in 0..NUM_UDC {
+            if full || frame.udcs[i] != self.shadow.udcs[i] {
+                self.write_udc(i as u8, &frame.udcs[i], delay);
             }
         }
 
-        for i in 0..empty_beginning {
-            self.write_char(i as u8, ' ' as u8, delay);
+        for i in 0..NUM_DIGITS {
+            let flash = frame.flash & (1 << i) != 0;
+            if full || flash != (self.shadow.flash & (1 << i) != 0) {
+                self.write_flash(i as u8, flash, delay);
+            }
         }
-        for (i, &c) in text.as_bytes().iter().enumerate() {
-            self.write_char((i + empty_beginning) as u8, c as u8, delay);
+
+        for i in 0..NUM_DIGITS {
+            if full || frame.chars[i] != self.shadow.chars[i] {
+                self.write_char(i as u8, frame.chars[i], delay);
+            }
         }
-        
-        for i in (text.len() + empty_beginning)..8 {
-            self.write_char(i as u8, ' ' as u8, delay);
+
+        self.shadow = self.frame;
+        self.shadow_valid = true;
+    }
+
+    /// Moves the text forward when it is due, without blocking. Returns true
+    /// when the text was shown through and starts over.
+    pub fn tick(&mut self, now_ms: u64) -> bool {
+        match (self.mode, self.next_step_ms) {
+            (_, Some(due)) if now_ms < due => false,
+            // A new text is shown for a full step before scrolling
+            (DisplayMode::Scroll, None) => {
+                self.next_step_ms = Some(now_ms + self.scroll.step_ms as u64);
+                false
+            }
+            (DisplayMode::Scroll, Some(_)) => {
+                // Texts that fit are not scrolled
+                if self.text.len() <= NUM_DIGITS {
+                    return false;
+                }
+                let pause_ms = self.scroll_step();
+                self.next_step_ms = Some(now_ms + self.scroll.step_ms as u64 + pause_ms as u64);
+                self.text_scroll_pos == 0
+            }
+            (DisplayMode::Rsvp, _) => {
+                let previous = self.rsvp_step;
+                let dwell_ms = self.rsvp_step();
+                self.next_step_ms = Some(now_ms + dwell_ms as u64);
+                // Back to the first word, or to the only one
+                matches!((previous, self.rsvp_step), (Some(previous), Some(step)) if step.start <= previous.start)
+            }
         }
+    }
+
+    /// Shows the next word and fires the events reached. Returns how long the
+    /// word should stay, in ms.
+    fn rsvp_step(&mut self) -> u32 {
+        let previous = self.rsvp_step;
+        self.rsvp_step = self.rsvp.next(&self.text);
+
+        let Some(step) = self.rsvp_step else {
+            return self.scroll.step_ms as u32;
+        };
+
+        // Events between the previous step and this one, including the ones
+        // in the spaces skipped
+        let first = match previous {
+            Some(previous) if previous.end() <= step.start => previous.end() + 1,
+            _ => 0,
+        };
+        step.dwell_ms + self.fire_events(first..=step.end())
+    }
 
-        // if self.text_scroll_pos + 8 > self.text.len() {
-        //     text = &self.text[self.text_scroll_pos..];
-        // } else if self.text_scroll_pos < self.text.len() {
+    /// Makes the next flush write everything again every `REASSERT_MS`
+    pub fn reassert(&mut self, now_ms: u64) {
+        if now_ms < self.next_reassert_ms {
+            return;
+        }
+        self.next_reassert_ms = now_ms + REASSERT_MS;
+        self.shadow_valid = false;
+        self.rewrites = self.rewrites.wrapping_add(1);
+    }
 
-        // } else {
-        //     text = &self.text[self.text_scroll_pos..self.text_scroll_pos + 8];
-        // }
+    pub fn rewrites(&self) -> u32 {
+        self.rewrites
+    }
 
-        // let text = self.text[self.text_scroll_pos..self.text_scroll_pos+8].as_bytes();
+    /// Renders the visible part of the text into the frame
+    pub fn render_text(&mut self) {
+        self.frame.control = self.control;
+        self.frame.udcs = self.udcs;
+
+        if self.mode == DisplayMode::Rsvp {
+            match self.rsvp_step {
+                Some(step) => step.render(&self.text, &mut self.frame),
+                None => {
+                    self.frame.chars = [b' '; NUM_DIGITS];
+                    self.frame.flash = 0x00;
+                }
+            }
+        } else {
+            self.render_scroll();
+        }
 
+        // The flash RAM only has an effect with flashing enabled
+        self.frame.flash |= self.flash_mask;
+        if self.frame.flash != 0x00 {
+            self.frame.control |= hdsp::CW_FLASH;
+        }
     }
 
-    pub fn scroll(&mut self, delay: &mut impl embedded_hal::delay::DelayNs) {
-        self.text_scroll_pos += 1;
-        if self.text_scroll_pos >= self.text.len() + 8 {
-            self.text_scroll_pos = 0;
+    /// Renders the visible part of the scrolling text into the frame
+    fn render_scroll(&mut self) {
+        // The text scrolls as if it was followed by a blank display, so it
+        // leaves on the left before coming back from the right
+        let period = self.text.len() + NUM_DIGITS;
+
+        for i in 0..NUM_DIGITS {
+            let idx = (self.text_scroll_pos + i) % period;
+            let glyph = self.text.get(idx).copied().unwrap_or(Glyph::new(b' '));
+
+            self.frame.chars[i] = glyph.code;
+            if glyph.flash {
+                self.frame.flash |= 1 << i;
+            } else {
+                self.frame.flash &= !(1 << i);
+            }
         }
 
         // 12345678 90000000 // 0
@@ -272,10 +581,44 @@ impl<
         // 89000000 00000000 // 7
         // 90000000 00000000 // 8
         // 00000000 00000000 // 9
+    }
 
+    /// Moves the text one digit to the left and fires the markup events
+    /// reached. Returns how long scrolling should pause, in ms.
+    pub fn scroll_step(&mut self) -> u32 {
+        self.text_scroll_pos += 1;
+        if self.text_scroll_pos >= self.text.len() + NUM_DIGITS {
+            self.text_scroll_pos = 0;
+            return self.scroll.end_pause_ms as u32 + self.fire_events(0..=NUM_DIGITS);
+        }
+
+        let anchor = self.text_scroll_pos + NUM_DIGITS;
+        self.fire_events(anchor..=anchor)
+    }
+
+    pub fn scroll(&mut self, delay: &mut impl embedded_hal::delay::DelayNs) {
+        let pause_ms = self.scroll_step();
 
         self.write(delay);
-        delay.delay_ms(100);
+        delay.delay_ms(100 + pause_ms);
+    }
+
+    /// Applies the events anchored in `anchors`, returns the total pause in ms
+    fn fire_events(&mut self, anchors: core::ops::RangeInclusive<usize>) -> u32 {
+        let mut pause_ms = 0;
+        let mut brightness = None;
+
+        for event in self.events.iter().filter(|e| anchors.contains(&e.anchor)) {
+            match event.event {
+                Event::Brightness(level) => brightness = Some(level),
+                Event::Pause(ms) => pause_ms += ms as u32,
+            }
+        }
+
+        if let Some(level) = brightness {
+            self.set_brightness(level);
+        }
+        pause_ms
     }
 }
 
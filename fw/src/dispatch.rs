@@ -0,0 +1,635 @@
+//! State of the device and the dispatcher applying the host packets to it.
+
+use hdsplib::anim::{Animation, Animations};
+use hdsplib::auth::{Key, Verifier};
+use hdsplib::batch::{Batch, BatchOp, DryRun, Staged};
+use hdsplib::diag::{self, DiagReport, DiagResult, Diagnostics};
+use hdsplib::event::Event;
+use hdsplib::fragment::Reassembler;
+use hdsplib::hdsp::{NUM_DIGITS, NUM_UDC};
+use hdsplib::info::{self, DeviceInfo, DisplayModel};
+use hdsplib::markup::{self, Markup};
+use hdsplib::message::{Message, MessageError};
+use hdsplib::packet::{Command, PacketView};
+use hdsplib::query::{Query, State};
+use hdsplib::reliable::{NackCode, Receiver, Response};
+use hdsplib::schedule::{self, Dimmer, Schedule, MAX_SCHEDULE_SIZE};
+use hdsplib::sync::{Timeline, TimeReply};
+use hdsplib::template::TemplateEngine;
+use hdsplib::transition::Transitions;
+use hdsplib::widget::Widgets;
+use rp235x_hal::rom_data;
+use rp235x_hal::timer::{CopyableTimer0, Timer};
+use rtt_target::rprintln;
+
+use crate::storage;
+use crate::usb;
+use crate::BoardDisplay;
+
+/// Largest message the host can send in fragments
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+pub type MessageReassembler = Reassembler<MAX_MESSAGE_SIZE>;
+
+/// Bytes of commands a batch can stage
+pub const MAX_BATCH_SIZE: usize = 2048;
+/// Bytes of commands which can wait for their time
+pub const MAX_TIMELINE_SIZE: usize = 2048;
+
+pub struct Device {
+    pub display: BoardDisplay,
+    pub widgets: Widgets,
+    pub animations: Animations,
+    pub templates: TemplateEngine,
+    pub transitions: Transitions,
+    pub diagnostics: Diagnostics,
+    pub dimmer: Dimmer,
+    /// Answers to the last numbered packets, to spot the ones sent again
+    receiver: Receiver,
+    /// Once a key is set, only authenticated commands are applied
+    auth: Verifier,
+    /// Mask of the events the host subscribed to
+    events: u16,
+    /// Commands waiting for the host to commit them
+    batch: Batch<MAX_BATCH_SIZE>,
+    /// Commands waiting for a time of the device, see `hdsplib::sync`
+    timeline: Timeline<MAX_TIMELINE_SIZE>,
+    /// Time of the device in microseconds, read when answering the host
+    timer: Timer<CopyableTimer0>,
+    /// Random characters are shown until the host sends a text
+    pub demo: bool,
+}
+
+impl Device {
+    pub fn new(display: BoardDisplay, timer: Timer<CopyableTimer0>) -> Self {
+        let schedule = storage::read(storage::TAG_SCHEDULE).and_then(Schedule::from_bytes);
+        let key = storage::read(storage::TAG_AUTH_KEY).and_then(|key| Key::try_from(key).ok());
+        let session = next_auth_session(key.is_some());
+
+        Self {
+            display,
+            widgets: Widgets::new(),
+            animations: Animations::new(),
+            templates: TemplateEngine::new(),
+            transitions: Transitions::new(),
+            diagnostics: Diagnostics::new(),
+            dimmer: Dimmer::new(schedule.unwrap_or_default()),
+            receiver: Receiver::new(),
+            auth: Verifier::new(key, session),
+            events: 0,
+            batch: Batch::new(),
+            timeline: Timeline::new(),
+            timer,
+            demo: true,
+        }
+    }
+
+    /// Advances everything driven by time
+    pub fn tick(&mut self, temp_c: i32, now_ms: u64) {
+        // All applied before the next frame is drawn, the ones due together
+        // show together. A batch made invalid since it was queued is dropped
+        // whole.
+        let now_us = self.timer.get_counter().ticks();
+        while let Some(due) = self.timeline.take_due(now_us) {
+            // A single command is applied or refused whole anyway
+            let batch = due.iter().nth(1).is_some();
+            if batch && self.dry_run(&due, now_ms, temp_c).is_err() {
+                rprintln!("Queued batch dropped");
+                continue;
+            }
+            for (command, payload) in due.iter() {
+                let result = Message::from_command(command, payload)
+                    .map_err(|_| NackCode::InvalidPayload)
+                    .and_then(|message| self.apply(message, now_ms, temp_c));
+                if let Err(e) = result {
+                    rprintln!("Queued command {} failed: {:?}", command, e);
+                }
+            }
+        }
+
+        if let Some(pattern) = self.diagnostics.tick(now_ms) {
+            report_diagnostics(pattern);
+        }
+
+        self.animations.tick(now_ms);
+
+        if let Some(m) = self.templates.tick(temp_c, now_ms) {
+            self.display.refresh_markup(&m);
+        }
+
+        if self.batch.tick(now_ms) {
+            rprintln!("Batch left open, rolled back");
+        }
+
+        if self.display.tick(now_ms) {
+            self.notify(Event::ScrollDone);
+        }
+    }
+
+    /// Sends an event to the host, if it subscribed to it
+    pub fn notify(&self, event: Event) {
+        if self.events & event.kind().mask() != 0 {
+            usb::send(&event.to_packet());
+        }
+    }
+
+    /// Renders the text and every layer drawn over it into the display frame
+    pub fn render(&mut self, now_ms: u64) {
+        let brightness = self.dimmer.brightness(&self.templates.clock, now_ms);
+
+        self.display.render_text();
+        let frame = self.display.frame_mut();
+        self.widgets.render(frame);
+        self.animations.render(frame);
+        self.transitions.apply(frame, now_ms);
+        schedule::apply(brightness, frame);
+        self.diagnostics.render(frame);
+    }
+
+    /// Shows a new message, with the transition selected by the host
+    fn show(&mut self, markup: &Markup, now_ms: u64) {
+        self.demo = false;
+        self.transitions.start(*self.display.frame(), now_ms);
+        self.display.set_markup(markup);
+    }
+
+    /// Puts the display and everything drawn on it back to the state at boot,
+    /// dropping the queued commands. The clock and the brightness schedule
+    /// are kept.
+    fn reset(&mut self) {
+        self.timeline.clear();
+        self.display.reset_defaults();
+        self.widgets = Widgets::new();
+        self.animations = Animations::new();
+        self.templates.clear();
+        self.transitions = Transitions::new();
+        self.diagnostics.stop();
+    }
+
+    /// Answer to a query, from what the display was told to show
+    fn state(&self, query: Query) -> State {
+        let display = &self.display;
+
+        match query {
+            Query::Text => State::Text(display.text().clone()),
+            Query::Scroll => State::Scroll {
+                config: display.scroll_config(),
+                pos: display.scroll_pos() as u16,
+            },
+            Query::Control => State::Control {
+                control: display.control(),
+                brightness: display.frame().brightness(),
+            },
+            Query::Udcs => State::Udcs(*display.udcs()),
+            Query::Flash => State::Flash(display.flash_mask()),
+            Query::Mode => {
+                let (mode, config) = display.mode();
+                State::Mode(mode, config)
+            }
+            Query::Schedule => State::Schedule(self.dimmer.schedule.clone()),
+            Query::Auth => State::Auth {
+                locked: self.auth.is_locked(),
+                session: self.auth.session(),
+                counter: self.auth.last_counter(),
+            },
+            Query::Rewrites => State::Rewrites(display.rewrites()),
+        }
+    }
+
+    /// Applies a packet from the host, answering it if it is numbered. A
+    /// packet already answered is not applied again. Fragments go to
+    /// `reassembler`, the message is applied with the last one.
+    ///
+    /// Once a key is set, only the answers to authenticated packets are
+    /// remembered, so that nobody else can take the seqs of the host.
+    pub fn receive(&mut self, packet: &PacketView, reassembler: &mut MessageReassembler, now_ms: u64, temp_c: i32) {
+        // A new host may number its packets like the previous one did
+        if self.is_hello(packet) {
+            self.receiver.clear();
+        }
+
+        let Some(seq) = packet.seq() else {
+            let _ = self.handle_packet(packet, reassembler, now_ms, temp_c);
+            return;
+        };
+
+        let trusted = !self.auth.is_locked() || Command::try_from(packet.command()) == Ok(Command::CMD_AUTH);
+        let response = match self.receiver.duplicate(seq).filter(|_| trusted) {
+            Some(response) => {
+                rprintln!("Duplicate packet {}", seq);
+                response
+            }
+            None => {
+                let result = self.handle_packet(packet, reassembler, now_ms, temp_c);
+                let response = Response { seq, result };
+                if trusted && result != Err(NackCode::Unauthorized) {
+                    self.receiver.record(response);
+                }
+                response
+            }
+        };
+        usb::send(&response.to_packet());
+    }
+
+    /// Whether the packet starts a session, which takes `CMD_HELLO` wrapped
+    /// in `CMD_AUTH` once a key is set. Its counter is left for applying it.
+    fn is_hello(&self, packet: &PacketView) -> bool {
+        let hello = |command| Command::try_from(command) == Ok(Command::CMD_HELLO);
+        match Command::try_from(packet.command()) {
+            _ if !self.auth.is_locked() => hello(packet.command()),
+            Ok(Command::CMD_AUTH) => self.auth.check(packet.payload()).is_ok_and(|(command, _)| hello(command)),
+            _ => false,
+        }
+    }
+
+    /// Once a key is set, fragments only go to `reassembler` wrapped in
+    /// `CMD_AUTH`, so that nobody else can drop the message of the host
+    fn handle_packet(
+        &mut self,
+        packet: &PacketView,
+        reassembler: &mut MessageReassembler,
+        now_ms: u64,
+        temp_c: i32,
+    ) -> Result<(), NackCode> {
+        let authenticated = Command::try_from(packet.command()) == Ok(Command::CMD_AUTH);
+        let (command, payload) = match authenticated {
+            true => self.auth.open(packet.payload()).map_err(|e| {
+                rprintln!("Unauthenticated command: {:?}", e);
+                NackCode::Unauthorized
+            })?,
+            false => (packet.command(), packet.payload()),
+        };
+
+        if Command::try_from(command) != Ok(Command::CMD_FRAGMENT) {
+            return self.handle_command(command, payload, authenticated, now_ms, temp_c);
+        }
+        if self.auth.is_locked() && !authenticated {
+            rprintln!("Fragment not authenticated");
+            return Err(NackCode::Unauthorized);
+        }
+
+        match reassembler.push(payload, now_ms) {
+            Ok(Some((command, message))) => self.handle_command(command, message, authenticated, now_ms, temp_c),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                rprintln!("Invalid fragment: {:?}", e);
+                Err(NackCode::InvalidPayload)
+            }
+        }
+    }
+
+    /// Applies a command, from a single packet or from a reassembled message,
+    /// or stages it in the batch open
+    fn handle_command(
+        &mut self,
+        command: u8,
+        payload: &[u8],
+        authenticated: bool,
+        now_ms: u64,
+        temp_c: i32,
+    ) -> Result<(), NackCode> {
+        let result = self.dispatch_command(command, payload, authenticated, now_ms, temp_c);
+        // No part of a batch with a command refused is applied. Once a key is
+        // set, only the host can make it fail.
+        if result.is_err() && (authenticated || !self.auth.is_locked()) {
+            self.batch.fail();
+        }
+        result
+    }
+
+    /// Once a key is set, only the read only commands are applied without
+    /// `CMD_AUTH`
+    fn dispatch_command(
+        &mut self,
+        command: u8,
+        payload: &[u8],
+        authenticated: bool,
+        now_ms: u64,
+        temp_c: i32,
+    ) -> Result<(), NackCode> {
+        match Message::from_command(command, payload) {
+            Ok(message) if self.auth.is_locked() && !authenticated && !message.is_read_only() => {
+                rprintln!("Command {} not authenticated", command);
+                Err(NackCode::Unauthorized)
+            }
+            Ok(message)
+                if self.batch.is_open()
+                    && !message.is_read_only()
+                    && !matches!(message, Message::Batch(_) | Message::At { .. }) =>
+            {
+                // Flash writes cannot be undone if the batch fails later
+                if matches!(message, Message::SetSchedule(_) | Message::SetKey(_)) {
+                    rprintln!("Command {} not allowed in a batch", command);
+                    return Err(NackCode::Failed);
+                }
+                self.batch.stage(command, payload, now_ms).map_err(|e| {
+                    rprintln!("Command {} not staged: {:?}", command, e);
+                    NackCode::Failed
+                })
+            }
+            Ok(message) => self.apply(message, now_ms, temp_c),
+            Err(MessageError::UnknownCommand(command)) => {
+                rprintln!("Unknown command {}", command);
+                Err(NackCode::UnknownCommand)
+            }
+            Err(e) => {
+                rprintln!("Invalid command {}: {:?}", command, e);
+                Err(NackCode::InvalidPayload)
+            }
+        }
+    }
+
+    /// Opens, commits or drops a batch
+    fn handle_batch(&mut self, op: BatchOp, now_ms: u64, temp_c: i32) -> Result<(), NackCode> {
+        match op {
+            BatchOp::Begin => self.batch.begin(now_ms),
+            BatchOp::Rollback => self.batch.rollback(),
+            BatchOp::Commit => {
+                let staged = self.batch.commit().map_err(|e| {
+                    rprintln!("Batch not committed: {:?}", e);
+                    NackCode::Failed
+                })?;
+                self.dry_run(&staged, now_ms, temp_c)?;
+                // All applied before the next frame is drawn
+                for (command, payload) in staged.iter() {
+                    let message = Message::from_command(command, payload).map_err(|_| NackCode::InvalidPayload)?;
+                    self.apply(message, now_ms, temp_c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries the commands of a batch on a copy of the state, so that none
+    /// is applied if one would fail
+    fn dry_run<const N: usize>(&self, staged: &Staged<N>, now_ms: u64, temp_c: i32) -> Result<(), NackCode> {
+        DryRun::new(&self.widgets, &self.animations, &self.templates)
+            .run(staged, temp_c, now_ms)
+            .map_err(|command| {
+                rprintln!("Batch not committed, command {} would fail", command);
+                NackCode::Failed
+            })
+    }
+
+    /// Queues a command for a time of the device. A batch commit queues the
+    /// commands of the batch open, once tried on the state as it is now.
+    fn queue_at(
+        &mut self,
+        device_us: u64,
+        command: u8,
+        payload: &[u8],
+        now_ms: u64,
+        temp_c: i32,
+    ) -> Result<(), NackCode> {
+        let queued = match Message::from_command(command, payload) {
+            Ok(Message::Batch(BatchOp::Commit)) => {
+                let staged = self.batch.commit().map_err(|e| {
+                    rprintln!("Batch not committed: {:?}", e);
+                    NackCode::Failed
+                })?;
+                self.dry_run(&staged, now_ms, temp_c)?;
+                self.timeline.push_staged(device_us, &staged)
+            }
+            // Only a commit can wait, and commands are queued once
+            Ok(Message::Batch(_) | Message::At { .. }) => return Err(NackCode::InvalidPayload),
+            Ok(_) => self.timeline.push(device_us, command, payload),
+            Err(MessageError::UnknownCommand(_)) => return Err(NackCode::UnknownCommand),
+            Err(_) => return Err(NackCode::InvalidPayload),
+        };
+
+        if !queued {
+            rprintln!("No room to queue command {}", command);
+            return Err(NackCode::Failed);
+        }
+        Ok(())
+    }
+
+    /// Applies a message from the host
+    fn apply(&mut self, message: Message, now_ms: u64, temp_c: i32) -> Result<(), NackCode> {
+        let ok = match message {
+            Message::SetText(text) => match Markup::plain(text) {
+                Ok(m) => {
+                    self.templates.clear();
+                    self.show(&m, now_ms);
+                    true
+                }
+                Err(e) => {
+                    rprintln!("Invalid text: {}", e);
+                    false
+                }
+            },
+            Message::SetMarkup(text) => match markup::parse(text) {
+                Ok(m) => {
+                    self.templates.clear();
+                    self.show(&m, now_ms);
+                    true
+                }
+                Err(e) => {
+                    rprintln!("Invalid markup: {}", e);
+                    false
+                }
+            },
+            Message::SetTemplate(text) => match self.templates.set(text, temp_c, now_ms) {
+                Ok(m) => {
+                    self.show(&m, now_ms);
+                    true
+                }
+                Err(e) => {
+                    rprintln!("Invalid template: {}", e);
+                    false
+                }
+            },
+            Message::SetBrightness(level) => {
+                self.display.set_brightness(level);
+                true
+            }
+            Message::SetControl(control) => {
+                self.display.set_control(control);
+                true
+            }
+            Message::DefineUdc(udc) => {
+                self.display.define_udc(udc.index as usize, udc.bitmap);
+                true
+            }
+            Message::SetFlash(digits) => {
+                self.display.set_flash_mask(digits);
+                true
+            }
+            Message::ScrollConfig(scroll) => {
+                self.display.set_scroll_config(scroll);
+                true
+            }
+            Message::Clear => {
+                self.demo = false;
+                self.templates.clear();
+                self.display.clear();
+                true
+            }
+            Message::Reset => {
+                self.demo = false;
+                self.reset();
+                true
+            }
+            Message::Query(query) => usb::send(&self.state(query).to_packet()),
+            Message::Hello => usb::send(&device_info().to_packet()),
+            Message::SetTime(secs) => {
+                self.templates.clock.set(secs, now_ms);
+                true
+            }
+            Message::Counter { index, op, value } => self.templates.update_counter(index as usize, op, value),
+            Message::SetSchedule(schedule) => {
+                // Stored in the older encoding, read back at the next boot
+                let mut buf = [0; MAX_SCHEDULE_SIZE];
+                let size = schedule.to_bytes(&mut buf);
+                self.dimmer.schedule = schedule;
+                if !storage::write(storage::TAG_SCHEDULE, &buf[..size]) {
+                    return Err(NackCode::Failed);
+                }
+                true
+            }
+            Message::SetKey(key) => {
+                if self.auth.is_locked() {
+                    rprintln!("Key already set");
+                    return Err(NackCode::Unauthorized);
+                }
+                // The session of this boot was not kept while there was no
+                // key. The device is locked only once the key is in flash.
+                let session = self.auth.session().to_le_bytes();
+                if !storage::write(storage::TAG_AUTH_KEY, &key)
+                    || !storage::write(storage::TAG_AUTH_SESSION, &session)
+                {
+                    return Err(NackCode::Failed);
+                }
+                self.auth.set_key(key)
+            }
+            Message::Batch(op) => return self.handle_batch(op, now_ms, temp_c),
+            Message::At {
+                device_us,
+                command,
+                payload,
+            } => return self.queue_at(device_us, command, payload, now_ms, temp_c),
+            Message::TimeRequest(host_us) => {
+                let device_us = self.timer.get_counter().ticks();
+                usb::send(&TimeReply { host_us, device_us }.to_packet())
+            }
+            Message::Subscribe(mask) => {
+                self.events = mask;
+                true
+            }
+            Message::ScheduleOverride { brightness, minutes } => {
+                self.dimmer.hold(brightness, minutes, now_ms);
+                true
+            }
+            Message::DiagStart { step_ms, loops } => {
+                self.diagnostics.start(step_ms, loops, now_ms);
+                true
+            }
+            Message::DiagStop => {
+                self.diagnostics.stop();
+                true
+            }
+            Message::SetMode { mode, config } => {
+                self.display.set_mode(mode, config);
+                true
+            }
+            Message::SetTransition { spec, once } => {
+                self.transitions.configure(spec, once);
+                true
+            }
+            Message::WidgetConfig(config) => self.widgets.configure(config),
+            Message::WidgetValue { id, value } => self.widgets.set_value(id, value),
+            Message::WidgetRemove(id) => self.widgets.remove(id),
+            Message::AnimUpload { id, udc, frames } => match Animation::from_frames(id, udc, &frames) {
+                Some(animation) => self.animations.upload(animation),
+                None => false,
+            },
+            Message::AnimStart(id) => self.animations.start(id, now_ms),
+            Message::AnimStop(id) => self.animations.stop(id),
+            Message::AnimBind { id, digits } => self.animations.bind(id, digits),
+            // Sent by the device, or raw packets it has no use for. This
+            // firmware has no vendor commands.
+            Message::Response(_)
+            | Message::Info(_)
+            | Message::State(_)
+            | Message::DiagReport(_)
+            | Message::Event(_)
+            | Message::TimeReply(_)
+            | Message::Raw { .. }
+            | Message::Vendor { .. } => {
+                rprintln!("Unknown command");
+                return Err(NackCode::UnknownCommand);
+            }
+        };
+
+        if !ok {
+            rprintln!("Invalid message");
+            return Err(NackCode::InvalidPayload);
+        }
+        Ok(())
+    }
+}
+
+/// Session of the authenticated mode for this boot, one more than the last.
+/// It is only kept while there is a key, so that the commands of a previous
+/// boot are refused.
+fn next_auth_session(locked: bool) -> u32 {
+    let last = storage::read(storage::TAG_AUTH_SESSION).and_then(|session| session.try_into().ok());
+    let session = last.map_or(0, u32::from_le_bytes).wrapping_add(1);
+    if locked && !storage::write(storage::TAG_AUTH_SESSION, &session.to_le_bytes()) {
+        rprintln!("Authentication session not saved");
+    }
+    session
+}
+
+/// Reports the result of a diagnostics pattern over RTT and USB
+fn report_diagnostics(pattern: diag::Pattern) {
+    // The data bus is write only on this board, nothing can be read back
+    let result = match pattern.needs_read_back() {
+        true => DiagResult::NotChecked,
+        false => DiagResult::Shown,
+    };
+
+    rprintln!("Diagnostics: {} done, {:?}", pattern.name(), result);
+    usb::send(&DiagReport { pattern, result }.to_packet());
+}
+
+/// What the device is, for `CMD_HELLO`
+fn device_info() -> DeviceInfo {
+    let unique_id = match rom_data::sys_info_api::chip_info() {
+        Ok(Some(chip)) => {
+            let mut id = [0; 8];
+            id[..4].copy_from_slice(&chip.device_id.to_le_bytes());
+            id[4..].copy_from_slice(&chip.wafer_id.to_le_bytes());
+            id
+        }
+        _ => [0; 8],
+    };
+
+    DeviceInfo {
+        protocol_version: info::PROTOCOL_VERSION,
+        firmware_version: heapless::String::try_from(env!("CARGO_PKG_VERSION")).unwrap_or_default(),
+        unique_id,
+        display_model: DisplayModel::Hdsp253x,
+        modules: 1,
+        digits: NUM_DIGITS as u8,
+        udcs: NUM_UDC as u8,
+        capabilities: info::CAP_CRC
+            | info::CAP_ACK
+            | info::CAP_FRAGMENTS
+            | info::CAP_MARKUP
+            | info::CAP_TEMPLATES
+            | info::CAP_WIDGETS
+            | info::CAP_ANIMATIONS
+            | info::CAP_TRANSITIONS
+            | info::CAP_RSVP
+            | info::CAP_DIAGNOSTICS
+            | info::CAP_SCHEDULE
+            | info::CAP_CLOCK
+            | info::CAP_QUERY
+            | info::CAP_COMPRESSION
+            | info::CAP_AUTH
+            | info::CAP_EVENTS
+            | info::CAP_BATCH
+            | info::CAP_SYNC,
+    }
+}
@@ -1,15 +1,21 @@
 #![no_std]
 #![no_main]
 
-use embedded_hal::{delay::DelayNs, digital::{OutputPin, StatefulOutputPin}};
+use embedded_hal::{delay::DelayNs, digital::{InputPin, OutputPin, StatefulOutputPin}};
+use embedded_hal_0_2::adc::OneShot;
 use hal::fugit::*;
+use dispatch::{Device, MessageReassembler};
+use hdsplib::diag;
+use hdsplib::event::{Debouncer, Event};
 use panic_halt as _;
-use rp235x_hal::{self as hal, pio::PIOExt, Clock};
+use rp235x_hal::{self as hal, gpio::bank0::*, pio::PIOExt, Clock};
 use rtt_target::{rprintln, rtt_init_print};
 // use rp235x_hal::pac::Interrupt;
 // use hal::fugit::RateExtU32;
 
 mod disp;
+mod dispatch;
+mod storage;
 mod uart;
 mod usb;
 
@@ -19,6 +25,12 @@ pub static IMAGE_DEF: hal::block::ImageDef = hal::block::ImageDef::secure_exe();
 
 const XTAL_FREQ_HZ: u32 = 12_000_000u32;
 
+/// The display with the pins it is wired to on this board
+type BoardDisplay = disp::Display<
+    Gpio16, Gpio17, Gpio18, Gpio19, Gpio20, Gpio21, Gpio22, Gpio26, Gpio27, Gpio28,
+    Gpio15, Gpio13, Gpio12, Gpio11, Gpio10, Gpio9, Gpio8, Gpio7, Gpio6, Gpio5,
+>;
+
 #[hal::entry]
 fn main() -> ! {
 
@@ -62,6 +74,9 @@ fn main() -> ! {
     );
     usb::init(pac.USB, pac.USB_DPRAM, clocks.usb_clock, &mut pac.RESETS);
 
+    let mut adc = hal::Adc::new(pac.ADC, &mut pac.RESETS);
+    let mut temp_sensor = adc.take_temp_sensor().unwrap();
+
     // RST: 16
     // FL: 17
     // A0: 18
@@ -105,7 +120,10 @@ fn main() -> ! {
     let d6 = pins.gpio6.into_push_pull_output();
     let d7 = pins.gpio5.into_push_pull_output();
 
-    let mut display = disp::Display::new(rst, fl, a0, a1, a2, a3, a4, cls, clk, wr, ce, rd, d0, d1, d2, d3, d4, d5, d6, d7);
+    // Holding the button at boot starts diagnostics, it pulls GPIO14 low
+    let mut diag_button = pins.gpio14.into_pull_up_input();
+
+    let mut display: BoardDisplay = disp::Display::new(rst, fl, a0, a1, a2, a3, a4, cls, clk, wr, ce, rd, d0, d1, d2, d3, d4, d5, d6, d7);
     display.init(&mut delay);
     // display.write_char(2, 0x0, &mut delay);
     // display.write_text("hola?", &mut delay);
@@ -124,26 +142,67 @@ fn main() -> ! {
     display.write(&mut delay);
     delay.delay_ms(150);
 
+    let mut device = Device::new(display, delay);
+    let mut reader = usb::PacketReader::new();
+    let mut reassembler = MessageReassembler::new();
 
+    if diag_button.is_low().unwrap() {
+        rprintln!("Diagnostics button held, starting diagnostics");
+        device.diagnostics.start(diag::DEFAULT_STEP_MS, 0, delay.get_counter().ticks() / 1000);
+    }
 
+    let mut next_text_ms = 0;
+    let mut button = Debouncer::new(diag_button.is_low().unwrap());
+    let mut oversize_errors = 0;
 
     loop {
         // display.scroll(&mut delay);
         // delay.delay_ms(50);
 
-        display.set_text(&s);
-        display.write(&mut delay);
-        delay.delay_ms(100);
-
-
-        s.remove(0);
-        s.push(((lcg.next() & 0x7F) as u8) as char).unwrap();
+        // Nothing below blocks, everything is paced by the timer
+        let now_ms = delay.get_counter().ticks() / 1000;
+        let temp_c = read_temp_c(&mut adc, &mut temp_sensor);
+
+        while let Some(packet) = reader.poll() {
+            device.receive(&packet, &mut reassembler, now_ms, temp_c);
+        }
+        if let Some(id) = reassembler.tick(now_ms) {
+            rprintln!("Message {} incomplete, dropped", id);
+        }
+        if reader.oversize_errors != oversize_errors {
+            oversize_errors = reader.oversize_errors;
+            device.notify(Event::RxOverflow(oversize_errors.min(u16::MAX as u32) as u16));
+        }
+        if let Some(event) = button.update(diag_button.is_low().unwrap(), now_ms) {
+            device.notify(event);
+        }
+
+        device.tick(temp_c, now_ms);
+
+        if device.demo && now_ms >= next_text_ms {
+            next_text_ms = now_ms + 100;
+
+            s.remove(0);
+            s.push(((lcg.next() & 0x7F) as u8) as char).unwrap();
+            device.display.set_text(&s);
+        }
+
+        device.render(now_ms);
+        device.display.reassert(now_ms);
+        device.display.flush(&mut delay);
+        delay.delay_ms(1);
 
         // rprintln!("Random number: {}", ((lcg.next() & 0xFF) as u8) as char);
     }
 
 }
 
+/// Internal temperature in degrees Celsius, from the RP2350 datasheet formula
+fn read_temp_c(adc: &mut hal::Adc, temp_sensor: &mut hal::adc::TempSense) -> i32 {
+    let raw: u16 = adc.read(temp_sensor).unwrap();
+    let volts = raw as f32 * 3.3 / 4096.0;
+    (27.0 - (volts - 0.706) / 0.001721) as i32
+}
 
 /// Program metadata for `picotool info`
 #[link_section = ".bi_entries"]
@@ -0,0 +1,117 @@
+//! Settings kept in the last sector of the flash.
+//!
+//! The sector starts with a magic number followed by records, each one being
+//! a tag, a little endian length and the data. The first erased byte (0xFF)
+//! ends the list. Writing a record rewrites the whole sector.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use rp235x_hal::rom_data;
+use rtt_target::rprintln;
+
+/// Size of a flash erase sector
+const SECTOR_SIZE: usize = 4096;
+/// Size of a flash program page
+const PAGE_SIZE: usize = 256;
+/// Offset of the settings in flash. 2 MiB is the smallest flash on the boards
+/// we use, the firmware is far from reaching its end.
+const SETTINGS_OFFSET: u32 = 2 * 1024 * 1024 - SECTOR_SIZE as u32;
+/// Address of the flash in the XIP window
+const XIP_BASE: u32 = 0x1000_0000;
+
+const MAGIC: [u8; 4] = *b"HDS1";
+const RECORD_HEADER_SIZE: usize = 3;
+const ERASED: u8 = 0xFF;
+
+/// Brightness schedule, see `hdsplib::schedule`
+pub const TAG_SCHEDULE: u8 = 0x01;
+/// Key of the authenticated mode, see `hdsplib::auth`
+pub const TAG_AUTH_KEY: u8 = 0x02;
+/// Session of the last boot with a key, a little endian `u32`
+pub const TAG_AUTH_SESSION: u8 = 0x03;
+
+/// Sector being written, too large for the stack of every write
+static G_SECTOR_BUFFER: Mutex<RefCell<[u8; SECTOR_SIZE]>> = Mutex::new(RefCell::new([0; SECTOR_SIZE]));
+
+fn sector() -> &'static [u8] {
+    // The flash is memory mapped, and only changes through `write`
+    unsafe { core::slice::from_raw_parts((XIP_BASE + SETTINGS_OFFSET) as *const u8, SECTOR_SIZE) }
+}
+
+/// Iterates over the `(tag, data)` records of a sector
+fn records(sector: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pos = match sector.starts_with(&MAGIC) {
+        true => MAGIC.len(),
+        // Never written, or from another firmware
+        false => sector.len(),
+    };
+
+    core::iter::from_fn(move || {
+        let header = sector.get(pos..pos + RECORD_HEADER_SIZE)?;
+        if header[0] == ERASED {
+            return None;
+        }
+
+        let len = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let data = sector.get(pos + RECORD_HEADER_SIZE..pos + RECORD_HEADER_SIZE + len)?;
+        pos += RECORD_HEADER_SIZE + len;
+        Some((header[0], data))
+    })
+}
+
+/// Data of the record `tag`, if it was ever written
+pub fn read(tag: u8) -> Option<&'static [u8]> {
+    records(sector()).find(|(t, _)| *t == tag).map(|(_, data)| data)
+}
+
+/// Replaces the record `tag` with `data`, keeping the other records
+pub fn write(tag: u8, data: &[u8]) -> bool {
+    cortex_m::interrupt::free(|cs| {
+        let mut buf = G_SECTOR_BUFFER.borrow(cs).borrow_mut();
+        buf.fill(ERASED);
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        let mut size = MAGIC.len();
+
+        let new_record = core::iter::once((tag, data));
+        for (t, d) in records(sector()).filter(|(t, _)| *t != tag).chain(new_record) {
+            let end = size + RECORD_HEADER_SIZE + d.len();
+            if end > SECTOR_SIZE {
+                rprintln!("Settings do not fit in flash");
+                return false;
+            }
+
+            let len = (d.len() as u16).to_le_bytes();
+            buf[size..size + RECORD_HEADER_SIZE].copy_from_slice(&[t, len[0], len[1]]);
+            buf[size + RECORD_HEADER_SIZE..end].copy_from_slice(d);
+            size = end;
+        }
+
+        let program_size = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        unsafe { write_sector(&buf[..program_size]) };
+        true
+    })
+}
+
+/// Erases the settings sector and programs `data` into it. Runs from RAM
+/// since the flash cannot be read while it is written.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_sector(data: &[u8]) {
+    // The ROM functions are looked up before the flash leaves XIP mode
+    let connect_internal_flash = rom_data::connect_internal_flash::ptr();
+    let flash_exit_xip = rom_data::flash_exit_xip::ptr();
+    let flash_range_erase = rom_data::flash_range_erase::ptr();
+    let flash_range_program = rom_data::flash_range_program::ptr();
+    let flash_flush_cache = rom_data::flash_flush_cache::ptr();
+    let flash_enter_cmd_xip = rom_data::flash_enter_cmd_xip::ptr();
+
+    connect_internal_flash();
+    flash_exit_xip();
+    // 0x20 is the 4 KiB sector erase command
+    flash_range_erase(SETTINGS_OFFSET, SECTOR_SIZE, SECTOR_SIZE as u32, 0x20);
+    flash_range_program(SETTINGS_OFFSET, data.as_ptr(), data.len());
+    flash_flush_cache();
+    // Slow serial reads, good enough for a firmware this size
+    flash_enter_cmd_xip();
+}
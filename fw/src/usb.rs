@@ -1,4 +1,5 @@
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use panic_halt as _;
 use cortex_m::interrupt::Mutex;
@@ -9,6 +10,8 @@ use usb_device::{class_prelude::*, prelude::*};
 use usbd_serial::SerialPort;
 
 use hdsplib::circ_buff::CircBuff;
+use hdsplib::packet::{FrameDecoder, FrameError, Packet, PacketView};
+use rtt_target::rprintln;
 
 type MutRefOption<T> = Mutex<RefCell<Option<T>>>;
 
@@ -21,6 +24,15 @@ const RECV_BUFFER_MAX_SIZE: usize = 14000;
 static G_RECV_BUFFER: MutRefOption<CircBuff<u8, RECV_BUFFER_MAX_SIZE>> =
     Mutex::new(RefCell::new(None));
 
+const SEND_BUFFER_MAX_SIZE: usize = 2048;
+
+static G_SEND_BUFFER: MutRefOption<CircBuff<u8, SEND_BUFFER_MAX_SIZE>> =
+    Mutex::new(RefCell::new(None));
+
+/// Whether the last packet from the host had a CRC, replies use the same
+/// format. New hosts are assumed until one tells otherwise.
+static HOST_USES_CRC: AtomicBool = AtomicBool::new(true);
+
 pub fn init(
     pac_usb: pac::USB,
     pac_usb_dpram: pac::USB_DPRAM,
@@ -58,13 +70,110 @@ pub fn init(
         *G_USB_DEVICE.borrow(cs).borrow_mut() = Some(usb_dev);
 
         *G_RECV_BUFFER.borrow(cs).borrow_mut() = Some(CircBuff::new());
+
+        *G_SEND_BUFFER.borrow(cs).borrow_mut() = Some(CircBuff::new());
+    });
+
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
+    }
+}
+
+/// Decodes the bytes received over USB into packets
+pub struct PacketReader {
+    decoder: FrameDecoder,
+    /// Frames dropped because they were not valid COBS or packets
+    pub framing_errors: u32,
+    /// Frames dropped because of a CRC mismatch
+    pub crc_errors: u32,
+    /// Frames dropped because they were too long
+    pub oversize_errors: u32,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            framing_errors: 0,
+            crc_errors: 0,
+            oversize_errors: 0,
+        }
+    }
+
+    /// Returns the next complete packet in the receive buffer, if any. It is
+    /// borrowed from the decoder, until the next call.
+    pub fn poll(&mut self) -> Option<PacketView<'_>> {
+        let crc = cortex_m::interrupt::free(|cs| {
+            let mut recv_buffer = G_RECV_BUFFER.borrow(cs).borrow_mut();
+            let recv_buffer = recv_buffer.as_mut()?;
+
+            while let Some(byte) = recv_buffer.pop() {
+                let Some(result) = self.decoder.push_in_place(byte) else {
+                    continue;
+                };
+
+                match result {
+                    Ok(crc) => return Some(crc),
+                    Err(FrameError::Framing) => {
+                        self.framing_errors += 1;
+                        rprintln!("Invalid packet framing");
+                    }
+                    Err(FrameError::Crc) => {
+                        self.crc_errors += 1;
+                        rprintln!("Packet CRC mismatch");
+                    }
+                    Err(FrameError::Oversize) => {
+                        self.oversize_errors += 1;
+                        rprintln!("Packet too long, dropping it");
+                    }
+                }
+            }
+
+            None
+        })?;
+
+        HOST_USES_CRC.store(crc, Ordering::Relaxed);
+        Some(self.decoder.packet())
+    }
+}
+
+/// Queues a packet to the host. Returns false if it does not fit in the send
+/// buffer, the packet is then dropped as a whole.
+pub fn send(packet: &Packet) -> bool {
+    send_view(&packet.view())
+}
+
+/// Queues a packet to the host, encoding it straight into the send buffer
+pub fn send_view(packet: &PacketView) -> bool {
+    let crc = HOST_USES_CRC.load(Ordering::Relaxed);
+    // Sized from the payload length, the frame is only encoded once
+    let size = packet.max_encoded_len(crc);
+
+    let queued = cortex_m::interrupt::free(|cs| {
+        let mut send_buffer = G_SEND_BUFFER.borrow(cs).borrow_mut();
+        let Some(send_buffer) = send_buffer.as_mut() else {
+            return false;
+        };
+
+        if send_buffer.remaining() < size {
+            return false;
+        }
+        packet.write_frame(crc, send_buffer).is_ok()
     });
+
+    // The interrupt writes the queued bytes to the serial port
+    cortex_m::peripheral::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+    queued
 }
 
 #[interrupt]
 fn USBCTRL_IRQ() {
     static mut USB_SERIAL: Option<SerialPort<UsbBus>> = None;
     static mut USB_DEVICE: Option<UsbDevice<UsbBus>> = None;
+    // Bytes taken from the send buffer and not written yet
+    static mut PENDING: [u8; 64] = [0; 64];
+    static mut PENDING_START: usize = 0;
+    static mut PENDING_END: usize = 0;
 
     // On the first execution of the function, USB_DEVICE is none, and gets populated
     // with the value from the global variable. On subsequent runs it is populated.
@@ -107,4 +216,21 @@ fn USBCTRL_IRQ() {
             }
         });
     }
+
+    if *PENDING_START == *PENDING_END {
+        *PENDING_START = 0;
+        *PENDING_END = cortex_m::interrupt::free(|cs| {
+            let mut send_buffer = G_SEND_BUFFER.borrow(cs).borrow_mut();
+            let send_buffer = send_buffer.as_mut().unwrap();
+            let count = send_buffer.size().min(PENDING.len());
+            send_buffer.read_exact(count, &mut PENDING[..]).unwrap_or(0)
+        });
+    }
+
+    if *PENDING_START < *PENDING_END {
+        // Nothing is written until the host opens the port
+        if let Ok(count) = serial.write(&PENDING[*PENDING_START..*PENDING_END]) {
+            *PENDING_START += count;
+        }
+    }
 }
@@ -0,0 +1,326 @@
+//! Looping sprite animations played back from UDC frame sequences.
+//!
+//! Each animation owns a UDC slot. Playback rewrites the bitmap of that slot
+//! with the current frame, so every digit bound to the animation follows it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{self, Frame, UdcBitmap, NUM_DIGITS, NUM_UDC, UDC_ROWS};
+use crate::packet::{Command, Packet};
+
+/// Number of animations that can be stored at the same time
+pub const MAX_ANIMATIONS: usize = 4;
+/// Maximum number of frames of an animation
+pub const MAX_ANIM_FRAMES: usize = 16;
+
+/// Size of the fixed part of the `CMD_ANIM_UPLOAD` payload, the frames follow
+const UPLOAD_HEADER_SIZE: usize = 3;
+/// Size of a frame in the `CMD_ANIM_UPLOAD` payload: duration and bitmap
+const UPLOAD_FRAME_SIZE: usize = 2 + UDC_ROWS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnimFrame {
+    pub bitmap: UdcBitmap,
+    pub duration_ms: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Animation {
+    pub id: u8,
+    /// UDC slot the frames are played into
+    pub udc: u8,
+    frames: [AnimFrame; MAX_ANIM_FRAMES],
+    n_frames: usize,
+    /// One bit per digit showing the animation, bit 0 being the leftmost digit
+    digits: u8,
+    playing: bool,
+    current: usize,
+    next_frame_ms: u64,
+}
+
+impl Animation {
+    pub fn new(id: u8, udc: u8) -> Self {
+        Self {
+            id,
+            udc,
+            frames: [AnimFrame {
+                bitmap: hdsp::UDC_BLANK,
+                duration_ms: 0,
+            }; MAX_ANIM_FRAMES],
+            n_frames: 0,
+            digits: 0x00,
+            playing: false,
+            current: 0,
+            next_frame_ms: 0,
+        }
+    }
+
+    /// Animation playing `frames` into UDC slot `udc`, if both are valid
+    pub fn from_frames(id: u8, udc: u8, frames: &[AnimFrame]) -> Option<Self> {
+        if frames.is_empty() || frames.len() > MAX_ANIM_FRAMES || udc as usize >= NUM_UDC {
+            return None;
+        }
+
+        let mut animation = Animation::new(id, udc);
+        for frame in frames {
+            animation.push_frame(*frame);
+        }
+        Some(animation)
+    }
+
+    /// Appends a frame, returns false if the animation is full
+    pub fn push_frame(&mut self, frame: AnimFrame) -> bool {
+        if self.n_frames == MAX_ANIM_FRAMES {
+            return false;
+        }
+        self.frames[self.n_frames] = frame;
+        self.n_frames += 1;
+        true
+    }
+
+    pub fn frames(&self) -> &[AnimFrame] {
+        &self.frames[..self.n_frames]
+    }
+
+    pub fn digits(&self) -> u8 {
+        self.digits
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = [0x00; UPLOAD_HEADER_SIZE + MAX_ANIM_FRAMES * UPLOAD_FRAME_SIZE];
+        payload[0] = self.id;
+        payload[1] = self.udc;
+        payload[2] = self.n_frames as u8;
+
+        let chunks = payload[UPLOAD_HEADER_SIZE..].chunks_mut(UPLOAD_FRAME_SIZE);
+        for (frame, chunk) in self.frames().iter().zip(chunks) {
+            chunk[..2].copy_from_slice(&frame.duration_ms.to_le_bytes());
+            chunk[2..].copy_from_slice(&frame.bitmap);
+        }
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_ANIM_UPLOAD.into());
+        packet.set_payload(&payload[..UPLOAD_HEADER_SIZE + self.n_frames * UPLOAD_FRAME_SIZE]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() < UPLOAD_HEADER_SIZE {
+            return None;
+        }
+
+        let n_frames = payload[2] as usize;
+        let frames = &payload[UPLOAD_HEADER_SIZE..];
+        if n_frames == 0 || n_frames > MAX_ANIM_FRAMES || frames.len() != n_frames * UPLOAD_FRAME_SIZE {
+            return None;
+        }
+        if payload[1] as usize >= NUM_UDC {
+            return None;
+        }
+
+        let mut animation = Animation::new(payload[0], payload[1]);
+        for chunk in frames.chunks(UPLOAD_FRAME_SIZE) {
+            let mut bitmap = hdsp::UDC_BLANK;
+            bitmap.copy_from_slice(&chunk[2..]);
+            animation.push_frame(AnimFrame {
+                bitmap,
+                duration_ms: u16::from_le_bytes([chunk[0], chunk[1]]),
+            });
+        }
+
+        Some(animation)
+    }
+
+    /// Advances the animation to the frame due at `now_ms`.
+    /// Returns true if the frame changed.
+    pub fn tick(&mut self, now_ms: u64) -> bool {
+        if !self.playing || self.n_frames < 2 || now_ms < self.next_frame_ms {
+            return false;
+        }
+
+        self.current = (self.current + 1) % self.n_frames;
+        // Catch up from now rather than from the missed deadline so a long
+        // stall of the main loop does not play a burst of frames
+        self.next_frame_ms = now_ms + self.frames[self.current].duration_ms as u64;
+        true
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        if self.n_frames == 0 {
+            return;
+        }
+
+        frame.udcs[self.udc as usize] = self.frames[self.current].bitmap;
+        for i in 0..NUM_DIGITS {
+            if self.digits & (1 << i) != 0 {
+                frame.chars[i] = hdsp::udc_char(self.udc);
+            }
+        }
+    }
+}
+
+/// `CMD_ANIM_START` packet
+pub fn start_to_packet(id: u8) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_ANIM_START.into());
+    packet.set_payload(&[id]);
+    packet
+}
+
+/// `CMD_ANIM_STOP` packet
+pub fn stop_to_packet(id: u8) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_ANIM_STOP.into());
+    packet.set_payload(&[id]);
+    packet
+}
+
+/// `CMD_ANIM_BIND` packet showing animation `id` on the digits set in `digits`
+pub fn bind_to_packet(id: u8, digits: u8) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_ANIM_BIND.into());
+    packet.set_payload(&[id, digits]);
+    packet
+}
+
+/// Set of uploaded animations
+#[derive(Clone)]
+pub struct Animations {
+    slots: [Option<Animation>; MAX_ANIMATIONS],
+}
+
+impl Animations {
+    pub fn new() -> Self {
+        Self {
+            slots: [None; MAX_ANIMATIONS],
+        }
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Animation> {
+        self.slots.iter().flatten().find(|a| a.id == id)
+    }
+
+    fn get_mut(&mut self, id: u8) -> Option<&mut Animation> {
+        self.slots.iter_mut().flatten().find(|a| a.id == id)
+    }
+
+    /// Stores an animation, replacing the one with the same id. The
+    /// replacement keeps the digits it was bound to but is stopped.
+    /// Returns false if there is no free slot.
+    pub fn upload(&mut self, mut animation: Animation) -> bool {
+        if let Some(old) = self.get_mut(animation.id) {
+            animation.digits = old.digits;
+            *old = animation;
+            return true;
+        }
+
+        match self.slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(animation);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn start(&mut self, id: u8, now_ms: u64) -> bool {
+        match self.get_mut(id) {
+            Some(animation) => {
+                animation.playing = true;
+                animation.current = 0;
+                animation.next_frame_ms = now_ms + animation.frames[0].duration_ms as u64;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops the animation on its current frame
+    pub fn stop(&mut self, id: u8) -> bool {
+        match self.get_mut(id) {
+            Some(animation) => {
+                animation.playing = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Binds the animation to the digits set in `digits`, 0 unbinds it.
+    /// A digit can only show one animation, it is taken from any other one.
+    pub fn bind(&mut self, id: u8, digits: u8) -> bool {
+        if self.get(id).is_none() {
+            return false;
+        }
+
+        for animation in self.slots.iter_mut().flatten() {
+            if animation.id == id {
+                animation.digits = digits;
+            } else {
+                animation.digits &= !digits;
+            }
+        }
+        true
+    }
+
+    /// Advances every animation, returns true if any frame changed
+    pub fn tick(&mut self, now_ms: u64) -> bool {
+        let mut changed = false;
+        for animation in self.slots.iter_mut().flatten() {
+            changed |= animation.tick(now_ms);
+        }
+        changed
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        for animation in self.slots.iter().flatten() {
+            animation.render(frame);
+        }
+    }
+}
+
+impl Default for Animations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_animations() {
+    let mut spinner = Animation::new(3, 15);
+    for (i, duration_ms) in [100, 200, 100].into_iter().enumerate() {
+        let mut bitmap = hdsp::UDC_BLANK;
+        bitmap[i] = 0x1F;
+        assert!(spinner.push_frame(AnimFrame { bitmap, duration_ms }));
+    }
+
+    let packet = spinner.to_packet();
+    assert_eq!(packet.command(), Command::CMD_ANIM_UPLOAD.into());
+    let decoded = Animation::from_payload(packet.get_payload()).unwrap();
+    assert_eq!(decoded.frames(), spinner.frames());
+
+    let mut animations = Animations::new();
+    assert!(animations.upload(decoded));
+    assert!(animations.bind(3, 0b1000_0001));
+    assert!(animations.start(3, 1000));
+
+    assert!(!animations.tick(1099));
+    assert!(animations.tick(1100));
+    // The second frame lasts 200 ms
+    assert!(!animations.tick(1299));
+    assert!(animations.tick(1300));
+
+    let mut frame = Frame::new();
+    animations.render(&mut frame);
+    assert_eq!(frame.chars[0], hdsp::udc_char(15));
+    assert_eq!(frame.chars[7], hdsp::udc_char(15));
+    assert_eq!(frame.chars[1], b' ');
+    assert_eq!(frame.udcs[15][2], 0x1F);
+
+    assert!(animations.stop(3));
+    assert!(!animations.tick(5000));
+}
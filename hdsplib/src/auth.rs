@@ -0,0 +1,264 @@
+//! Authenticated commands, for devices which must only obey their host.
+//!
+//! A device is unlocked until the host provisions a key with `CMD_SET_KEY`.
+//! The key is kept in flash and cannot be replaced once set. From then on
+//! the device only applies commands wrapped in `CMD_AUTH`, except the ones
+//! which change nothing, see `Message::is_read_only`.
+//!
+//! A `CMD_AUTH` payload is `[counter, command, payload..., tag]`: a little
+//! endian 32 bit counter, the command wrapped with its payload, and
+//! HMAC-SHA256 truncated to `TAG_SIZE` bytes over the session, the counter,
+//! the command and the payload. The device accepts each counter once, in
+//! increasing order, so a command cannot be replayed. The session is
+//! different at each boot of the device, so the commands of a previous boot
+//! cannot be replayed either, and the host reads it back with `Query::Auth`
+//! along with the last counter accepted.
+//!
+//! A command too large for a packet is split with `fragment::fragments`,
+//! and each `CMD_FRAGMENT` is wrapped in `CMD_AUTH` on its own, so that the
+//! device only puts together the fragments of its host.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+
+pub const KEY_SIZE: usize = 32;
+/// Size of the truncated HMAC ending a `CMD_AUTH` payload
+pub const TAG_SIZE: usize = 8;
+/// Counter, command and tag around the wrapped payload
+pub const AUTH_OVERHEAD: usize = 4 + 1 + TAG_SIZE;
+/// Largest payload `Signer::seal_packet` wraps, room is left for a sequence
+/// number
+pub const MAX_AUTH_PAYLOAD_SIZE: usize = MAX_PACKET_PAYLOAD_SIZE - 1 - AUTH_OVERHEAD;
+
+pub type Key = [u8; KEY_SIZE];
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Too short to hold a counter, a command and a tag
+    Malformed,
+    /// Tag not matching, wrong key or altered command
+    BadTag,
+    /// Counter not above the last one accepted
+    Replayed,
+    /// No key provisioned on the device
+    NoKey,
+}
+
+/// HMAC over the session, the counter, the command and the payload
+fn mac(key: &Key, session: u32, counter: u32, command: u8, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&session.to_le_bytes());
+    mac.update(&counter.to_le_bytes());
+    mac.update(&[command]);
+    mac.update(payload);
+    mac
+}
+
+/// `CMD_SET_KEY` packet, only accepted by a device which has no key yet
+pub fn key_to_packet(key: &Key) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SET_KEY.into());
+    packet.set_payload(key);
+    packet
+}
+
+/// Wraps the commands of the host
+pub struct Signer {
+    key: Key,
+    session: u32,
+    counter: u32,
+}
+
+impl Signer {
+    /// Signs for the device `session`, continuing after `last_counter`, both
+    /// read with `Query::Auth`
+    pub fn new(key: Key, session: u32, last_counter: u32) -> Self {
+        Self {
+            key,
+            session,
+            counter: last_counter,
+        }
+    }
+
+    /// Counter of the last command wrapped
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Writes the `CMD_AUTH` payload wrapping `command` into `out`. Returns
+    /// its size, `None` if `out` is too small or the counter has run out.
+    pub fn seal(&mut self, command: u8, payload: &[u8], out: &mut [u8]) -> Option<usize> {
+        let size = payload.len() + AUTH_OVERHEAD;
+        if size > out.len() {
+            return None;
+        }
+        let counter = self.counter.checked_add(1)?;
+
+        let tag = mac(&self.key, self.session, counter, command, payload).finalize().into_bytes();
+        out[..4].copy_from_slice(&counter.to_le_bytes());
+        out[4] = command;
+        out[5..size - TAG_SIZE].copy_from_slice(payload);
+        out[size - TAG_SIZE..size].copy_from_slice(&tag[..TAG_SIZE]);
+
+        self.counter = counter;
+        Some(size)
+    }
+
+    /// `CMD_AUTH` packet wrapping `command`, `None` if the payload is larger
+    /// than `MAX_AUTH_PAYLOAD_SIZE`
+    pub fn seal_packet(&mut self, command: u8, payload: &[u8]) -> Option<Packet> {
+        let mut buf = [0; MAX_AUTH_PAYLOAD_SIZE + AUTH_OVERHEAD];
+        let size = self.seal(command, payload, &mut buf)?;
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_AUTH.into());
+        packet.set_payload(&buf[..size]);
+        Some(packet)
+    }
+}
+
+/// Checks the commands on the device
+pub struct Verifier {
+    key: Option<Key>,
+    session: u32,
+    last_counter: u32,
+}
+
+impl Verifier {
+    /// `key` as read from flash, `session` unique to this boot
+    pub fn new(key: Option<Key>, session: u32) -> Self {
+        Self {
+            key,
+            session,
+            last_counter: 0,
+        }
+    }
+
+    /// Whether a key is set, and only authenticated commands are applied
+    pub fn is_locked(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Sets the key, unless there already is one
+    pub fn set_key(&mut self, key: Key) -> bool {
+        match self.key {
+            Some(_) => false,
+            None => {
+                self.key = Some(key);
+                true
+            }
+        }
+    }
+
+    pub fn session(&self) -> u32 {
+        self.session
+    }
+
+    /// Counter of the last command accepted, 0 if none was
+    pub fn last_counter(&self) -> u32 {
+        self.last_counter
+    }
+
+    /// Checks a `CMD_AUTH` payload, returning the command it wraps and its
+    /// payload. Its counter cannot be used again.
+    pub fn open<'a>(&mut self, payload: &'a [u8]) -> Result<(u8, &'a [u8]), AuthError> {
+        let (counter, command, wrapped) = self.verify(payload)?;
+        self.last_counter = counter;
+        Ok((command, wrapped))
+    }
+
+    /// Checks a `CMD_AUTH` payload like `open`, leaving its counter unused
+    pub fn check<'a>(&self, payload: &'a [u8]) -> Result<(u8, &'a [u8]), AuthError> {
+        self.verify(payload).map(|(_, command, wrapped)| (command, wrapped))
+    }
+
+    fn verify<'a>(&self, payload: &'a [u8]) -> Result<(u32, u8, &'a [u8]), AuthError> {
+        let key = self.key.as_ref().ok_or(AuthError::NoKey)?;
+        if payload.len() < AUTH_OVERHEAD {
+            return Err(AuthError::Malformed);
+        }
+
+        let (body, tag) = payload.split_at(payload.len() - TAG_SIZE);
+        let counter = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+        let (command, wrapped) = (body[4], &body[5..]);
+
+        mac(key, self.session, counter, command, wrapped)
+            .verify_truncated_left(tag)
+            .map_err(|_| AuthError::BadTag)?;
+        // Only once the tag is checked, a forged counter must not move it
+        if counter <= self.last_counter {
+            return Err(AuthError::Replayed);
+        }
+        Ok((counter, command, wrapped))
+    }
+}
+
+#[test]
+fn test_auth() {
+    use crate::fragment;
+
+    let key = [0x5A; KEY_SIZE];
+    let mut signer = Signer::new(key, 7, 0);
+    let mut verifier = Verifier::new(None, 7);
+
+    let packet = signer.seal_packet(Command::CMD_SET_TEXT.into(), b"HELLO").unwrap();
+    assert_eq!(Command::try_from(packet.command()), Ok(Command::CMD_AUTH));
+    assert_eq!(verifier.open(packet.get_payload()), Err(AuthError::NoKey));
+    assert!(!verifier.is_locked());
+
+    // The key is set once only
+    assert!(verifier.set_key(key));
+    assert!(!verifier.set_key([0x00; KEY_SIZE]));
+    assert!(verifier.is_locked());
+
+    // Checking leaves the counter for the command to be applied
+    let checked = verifier.check(packet.get_payload());
+    assert_eq!(checked, Ok((Command::CMD_SET_TEXT.into(), &b"HELLO"[..])));
+    assert_eq!(verifier.last_counter(), 0);
+    let opened = verifier.open(packet.get_payload());
+    assert_eq!(opened, checked);
+    assert_eq!(verifier.last_counter(), 1);
+    assert_eq!(verifier.open(packet.get_payload()), Err(AuthError::Replayed));
+    assert_eq!(verifier.check(packet.get_payload()), Err(AuthError::Replayed));
+
+    // Every byte is covered by the tag
+    let next = signer.seal_packet(Command::CMD_CLEAR.into(), &[]).unwrap();
+    for i in 0..next.get_payload().len() {
+        let mut altered = next;
+        altered.data[1 + i] ^= 0x01;
+        assert_eq!(verifier.open(altered.get_payload()), Err(AuthError::BadTag));
+    }
+    assert_eq!(verifier.open(next.get_payload()), Ok((Command::CMD_CLEAR.into(), &[][..])));
+    assert_eq!(verifier.open(&[1, 2, 3]), Err(AuthError::Malformed));
+
+    // Commands of another session or key are refused
+    let mut old_session = Signer::new(key, 6, verifier.last_counter());
+    let packet = old_session.seal_packet(Command::CMD_CLEAR.into(), &[]).unwrap();
+    assert_eq!(verifier.open(packet.get_payload()), Err(AuthError::BadTag));
+    let mut other_key = Signer::new([0xA5; KEY_SIZE], 7, verifier.last_counter());
+    let packet = other_key.seal_packet(Command::CMD_CLEAR.into(), &[]).unwrap();
+    assert_eq!(verifier.open(packet.get_payload()), Err(AuthError::BadTag));
+
+    // Packets keep room for a sequence number, larger commands are fragmented
+    let mut packet = signer.seal_packet(0x14, &[0x41; MAX_AUTH_PAYLOAD_SIZE]).unwrap();
+    packet.set_seq(9);
+    assert_eq!(verifier.open(packet.get_payload()).map(|(_, p)| p.len()), Ok(MAX_AUTH_PAYLOAD_SIZE));
+    assert!(signer.seal_packet(0x14, &[0x41; MAX_AUTH_PAYLOAD_SIZE + 1]).is_none());
+    let mut buf = [0; 1024];
+    let size = signer.seal(0x14, &[0x41; 600], &mut buf).unwrap();
+    assert_eq!(verifier.open(&buf[..size]).map(|(_, p)| p.len()), Ok(600));
+
+    // Every fragment of a larger command is wrapped
+    for fragment in fragment::fragments(3, Command::CMD_SET_MARKUP, &[b'A'; 600]).unwrap() {
+        let packet = signer.seal_packet(fragment.command(), fragment.get_payload()).unwrap();
+        let opened = verifier.open(packet.get_payload());
+        assert_eq!(opened, Ok((Command::CMD_FRAGMENT.into(), fragment.get_payload())));
+    }
+
+    let mut exhausted = Signer::new(key, 7, u32::MAX);
+    assert!(exhausted.seal_packet(0x14, b"A").is_none());
+}
@@ -0,0 +1,432 @@
+//! Batches of commands applied at once, so that changes made by several
+//! commands never show half done.
+//!
+//! `CMD_BATCH [BEGIN]` opens a batch: the commands which follow are checked
+//! and staged instead of applied, until `CMD_BATCH [COMMIT]` applies them all
+//! before the next frame is drawn. `CMD_BATCH [ROLLBACK]` drops them.
+//! `CMD_HELLO`, `CMD_QUERY` and `CMD_TIME_REQUEST` are still answered right
+//! away, and `CMD_AT` queues its command right away, see `sync`.
+//!
+//! A command refused while staging fails the batch: the commands staged are
+//! dropped, and the following ones refused until the host ends the batch, so
+//! no part of it is applied on its own. A batch left open for
+//! `BATCH_TIMEOUT_MS` without a new command is rolled back.
+//!
+//! Some commands can only fail once applied, a markup which does not parse
+//! or an animation which was never uploaded. `DryRun` tries the whole batch
+//! on a copy of the state they depend on before any is applied. Commands
+//! writing to flash cannot be undone, they are refused in a batch.
+
+use serde::{Deserialize, Serialize};
+
+use crate::anim::{Animation, Animations};
+use crate::markup::{self, Markup};
+use crate::message::Message;
+use crate::packet::{Command, Packet};
+use crate::template::TemplateEngine;
+use crate::widget::Widgets;
+
+/// Time without a new command after which an open batch is rolled back
+pub const BATCH_TIMEOUT_MS: u64 = 2000;
+/// Command and little endian length before each staged payload
+const RECORD_HEADER_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Begin = 0,
+    Commit = 1,
+    Rollback = 2,
+}
+
+impl TryFrom<u8> for BatchOp {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(BatchOp::Begin),
+            1 => Ok(BatchOp::Commit),
+            2 => Ok(BatchOp::Rollback),
+            _ => Err(()),
+        }
+    }
+}
+
+impl BatchOp {
+    pub fn to_packet(self) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_BATCH.into());
+        packet.set_payload(&[self as u8]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [op] => BatchOp::try_from(*op).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchError {
+    /// No batch open
+    NotOpen,
+    /// A command of the batch was refused, it must be rolled back
+    Failed,
+    /// No room left for the command
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BatchState {
+    Closed,
+    Open,
+    Failed,
+}
+
+/// Commands staged on the device, `N` bytes of them
+pub struct Batch<const N: usize> {
+    state: BatchState,
+    staged: Staged<N>,
+    /// When the last command was staged
+    last_ms: u64,
+}
+
+impl<const N: usize> Batch<N> {
+    pub fn new() -> Self {
+        Self {
+            state: BatchState::Closed,
+            staged: Staged::new(),
+            last_ms: 0,
+        }
+    }
+
+    /// Whether commands are staged rather than applied, failed batches
+    /// included
+    pub fn is_open(&self) -> bool {
+        self.state != BatchState::Closed
+    }
+
+    /// Opens a batch, dropping the one open if any
+    pub fn begin(&mut self, now_ms: u64) {
+        self.state = BatchState::Open;
+        self.staged.bytes.clear();
+        self.last_ms = now_ms;
+    }
+
+    /// Stages a command. One which does not fit fails the batch.
+    pub fn stage(&mut self, command: u8, payload: &[u8], now_ms: u64) -> Result<(), BatchError> {
+        match self.state {
+            BatchState::Closed => return Err(BatchError::NotOpen),
+            BatchState::Failed => return Err(BatchError::Failed),
+            BatchState::Open => {}
+        }
+        self.last_ms = now_ms;
+
+        if !self.staged.push(command, payload) {
+            self.fail();
+            return Err(BatchError::Full);
+        }
+        Ok(())
+    }
+
+    /// Drops the commands staged and refuses the following ones, until the
+    /// batch ends
+    pub fn fail(&mut self) {
+        if self.state == BatchState::Open {
+            self.state = BatchState::Failed;
+            self.staged.bytes.clear();
+        }
+    }
+
+    /// Closes the batch, handing out the commands to apply
+    pub fn commit(&mut self) -> Result<Staged<N>, BatchError> {
+        let state = self.state;
+        self.state = BatchState::Closed;
+        match state {
+            BatchState::Open => Ok(core::mem::take(&mut self.staged)),
+            BatchState::Failed => Err(BatchError::Failed),
+            BatchState::Closed => Err(BatchError::NotOpen),
+        }
+    }
+
+    pub fn rollback(&mut self) {
+        self.state = BatchState::Closed;
+        self.staged.bytes.clear();
+    }
+
+    /// Rolls back a batch left open too long. Returns true when it did.
+    pub fn tick(&mut self, now_ms: u64) -> bool {
+        if !self.is_open() || now_ms < self.last_ms + BATCH_TIMEOUT_MS {
+            return false;
+        }
+        self.rollback();
+        true
+    }
+}
+
+impl<const N: usize> Default for Batch<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Commands of a committed batch, or due at the same time
+pub struct Staged<const N: usize> {
+    bytes: heapless::Vec<u8, N>,
+}
+
+impl<const N: usize> Staged<N> {
+    pub fn new() -> Self {
+        Self { bytes: heapless::Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Adds a command after the others. Returns false if it does not fit.
+    pub fn push(&mut self, command: u8, payload: &[u8]) -> bool {
+        let start = self.bytes.len();
+        let len = (payload.len() as u16).to_le_bytes();
+        let fits = payload.len() <= u16::MAX as usize
+            && self.bytes.extend_from_slice(&[command, len[0], len[1]]).is_ok()
+            && self.bytes.extend_from_slice(payload).is_ok();
+        if !fits {
+            self.bytes.truncate(start);
+        }
+        fits
+    }
+
+    /// `(command, payload)` in the order they were staged
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &[u8])> {
+        let mut rest = &self.bytes[..];
+        core::iter::from_fn(move || {
+            let (header, data) = rest.split_at_checked(RECORD_HEADER_SIZE)?;
+            let len = u16::from_le_bytes([header[1], header[2]]) as usize;
+            let (payload, next) = data.split_at(len);
+            rest = next;
+            Some((header[0], payload))
+        })
+    }
+}
+
+impl<const N: usize> Default for Staged<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copy of the state the commands of a batch can fail on, to try them all
+/// before applying any
+pub struct DryRun {
+    widgets: Widgets,
+    animations: Animations,
+    templates: TemplateEngine,
+}
+
+impl DryRun {
+    pub fn new(widgets: &Widgets, animations: &Animations, templates: &TemplateEngine) -> Self {
+        Self {
+            widgets: widgets.clone(),
+            animations: animations.clone(),
+            templates: templates.clone(),
+        }
+    }
+
+    /// Applies `message` to the copy, as the device does to its state.
+    /// Returns false if applying it to the device would fail.
+    pub fn check(&mut self, message: &Message, temp_c: i32, now_ms: u64) -> bool {
+        match message {
+            Message::SetText(text) => {
+                self.templates.clear();
+                Markup::plain(text).is_ok()
+            }
+            Message::SetMarkup(text) => {
+                self.templates.clear();
+                markup::parse(text).is_ok()
+            }
+            Message::SetTemplate(text) => self.templates.set(text, temp_c, now_ms).is_ok(),
+            Message::Clear => {
+                self.templates.clear();
+                true
+            }
+            // The clock and the counters are kept
+            Message::Reset => {
+                self.widgets = Widgets::new();
+                self.animations = Animations::new();
+                self.templates.clear();
+                true
+            }
+            Message::SetTime(secs) => {
+                self.templates.clock.set(*secs, now_ms);
+                true
+            }
+            Message::Counter { index, op, value } => self.templates.update_counter(*index as usize, *op, *value),
+            Message::WidgetConfig(config) => self.widgets.configure(*config),
+            Message::WidgetValue { id, value } => self.widgets.set_value(*id, *value),
+            Message::WidgetRemove(id) => self.widgets.remove(*id),
+            Message::AnimUpload { id, udc, frames } => {
+                Animation::from_frames(*id, *udc, frames).is_some_and(|a| self.animations.upload(a))
+            }
+            Message::AnimStart(id) => self.animations.start(*id, now_ms),
+            Message::AnimStop(id) => self.animations.stop(*id),
+            Message::AnimBind { id, digits } => self.animations.bind(*id, *digits),
+            Message::SetBrightness(_)
+            | Message::SetControl(_)
+            | Message::DefineUdc(_)
+            | Message::SetFlash(_)
+            | Message::ScrollConfig(_)
+            | Message::SetTransition { .. }
+            | Message::SetMode { .. }
+            | Message::DiagStart { .. }
+            | Message::DiagStop
+            | Message::ScheduleOverride { .. }
+            | Message::Subscribe(_) => true,
+            // Written to flash
+            Message::SetSchedule(_) | Message::SetKey(_) => false,
+            // Not applied by the device from a batch
+            Message::Hello
+            | Message::Query(_)
+            | Message::TimeRequest(_)
+            | Message::Batch(_)
+            | Message::At { .. }
+            | Message::Response(_)
+            | Message::Info(_)
+            | Message::State(_)
+            | Message::DiagReport(_)
+            | Message::Event(_)
+            | Message::TimeReply(_)
+            | Message::Raw { .. }
+            | Message::Vendor { .. } => false,
+        }
+    }
+
+    /// Tries every command of `staged` in order. Returns the first one which
+    /// would fail.
+    pub fn run<const N: usize>(&mut self, staged: &Staged<N>, temp_c: i32, now_ms: u64) -> Result<(), u8> {
+        for (command, payload) in staged.iter() {
+            match Message::from_command(command, payload) {
+                Ok(message) if self.check(&message, temp_c, now_ms) => {}
+                _ => return Err(command),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_batch() {
+    assert_eq!(BatchOp::from_payload(BatchOp::Commit.to_packet().get_payload()), Some(BatchOp::Commit));
+    assert_eq!(BatchOp::from_payload(&[3]), None);
+
+    let mut batch = Batch::<32>::new();
+    assert_eq!(batch.stage(0x14, b"A", 0), Err(BatchError::NotOpen));
+    assert!(batch.commit().is_err());
+
+    batch.begin(0);
+    assert!(batch.is_open());
+    batch.stage(0x14, b"HELLO", 10).unwrap();
+    batch.stage(0x1A, &[], 20).unwrap();
+    batch.stage(0x18, &[0x81], 30).unwrap();
+    let staged = batch.commit().unwrap();
+    let commands: heapless::Vec<(u8, &[u8]), 4> = staged.iter().collect();
+    assert_eq!(&commands[..], &[(0x14, &b"HELLO"[..]), (0x1A, &[][..]), (0x18, &[0x81][..])]);
+    assert!(!batch.is_open());
+    assert_eq!(batch.commit().err(), Some(BatchError::NotOpen));
+
+    // A command not fitting fails the whole batch until it is rolled back
+    batch.begin(100);
+    batch.stage(0x14, &[0x41; 20], 100).unwrap();
+    assert_eq!(batch.stage(0x14, &[0x41; 20], 110), Err(BatchError::Full));
+    assert_eq!(batch.stage(0x1A, &[], 120), Err(BatchError::Failed));
+    assert!(batch.is_open());
+    assert_eq!(batch.commit().err(), Some(BatchError::Failed));
+
+    batch.begin(200);
+    batch.stage(0x1A, &[], 200).unwrap();
+    batch.rollback();
+    assert!(!batch.is_open());
+
+    // Rolled back once the host stops sending
+    batch.begin(300);
+    batch.stage(0x1A, &[], 1000).unwrap();
+    assert!(!batch.tick(1000 + BATCH_TIMEOUT_MS - 1));
+    assert!(batch.tick(1000 + BATCH_TIMEOUT_MS));
+    assert!(!batch.is_open());
+}
+
+#[test]
+fn test_batch_dry_run() {
+    use crate::anim::{self, AnimFrame};
+    use crate::hdsp;
+    use crate::schedule::Schedule;
+    use crate::template;
+    use crate::widget;
+
+    let widgets = Widgets::new();
+    let animations = Animations::new();
+    let templates = TemplateEngine::new();
+    let dry_run = |staged: &Staged<256>| DryRun::new(&widgets, &animations, &templates).run(staged, 20, 0);
+
+    // The text would be shown before the markup is found invalid
+    let mut batch = Batch::<256>::new();
+    batch.begin(0);
+    batch.stage(Command::CMD_SET_TEXT.into(), b"HELLO", 0).unwrap();
+    batch.stage(Command::CMD_SET_MARKUP.into(), b"{zz}", 0).unwrap();
+    let staged = batch.commit().unwrap();
+    assert_eq!(dry_run(&staged), Err(Command::CMD_SET_MARKUP.into()));
+
+    // Commands relying on earlier ones of the batch pass, the state itself
+    // is left as it was
+    let frame = AnimFrame {
+        bitmap: hdsp::UDC_BLANK,
+        duration_ms: 100,
+    };
+    let mut staged = Staged::<256>::new();
+    staged.push(Command::CMD_SET_TEXT.into(), b"HELLO");
+    let upload = Animation::from_frames(7, 0, &[frame]).unwrap().to_packet();
+    staged.push(upload.command(), upload.get_payload());
+    staged.push(Command::CMD_ANIM_START.into(), &[7]);
+    assert_eq!(dry_run(&staged), Ok(()));
+    assert!(animations.get(7).is_none());
+
+    let mut staged = Staged::<256>::new();
+    let start = anim::start_to_packet(7);
+    staged.push(start.command(), start.get_payload());
+    assert_eq!(dry_run(&staged), Err(Command::CMD_ANIM_START.into()));
+
+    let mut staged = Staged::<256>::new();
+    let remove = widget::remove_to_packet(1);
+    staged.push(remove.command(), remove.get_payload());
+    assert_eq!(dry_run(&staged), Err(Command::CMD_WIDGET_REMOVE.into()));
+
+    // A reset drops the widget the next command sets
+    let mut configured = Widgets::new();
+    assert!(configured.configure(widget::WidgetConfig::new(1, widget::WidgetKind::Bar, 0, 8, 0, 40)));
+    let mut staged = Staged::<256>::new();
+    staged.push(Command::CMD_RESET.into(), &[]);
+    let value = widget::value_to_packet(1, 20);
+    staged.push(value.command(), value.get_payload());
+    let mut dry_run = DryRun::new(&configured, &animations, &templates);
+    assert_eq!(dry_run.run(&staged, 20, 0), Err(Command::CMD_WIDGET_VALUE.into()));
+    assert!(configured.get(1).is_some());
+
+    // A text stops the template, the counters are still updated
+    let mut counting = TemplateEngine::new();
+    counting.set("{c1}", 20, 0).unwrap();
+    let mut staged = Staged::<256>::new();
+    staged.push(Command::CMD_SET_TEXT.into(), b"HELLO");
+    let counter = Message::Counter { index: 1, op: template::COUNTER_SET, value: 5 }.to_packet().unwrap();
+    staged.push(counter.command(), counter.get_payload());
+    let mut dry_run = DryRun::new(&widgets, &animations, &counting);
+    assert_eq!(dry_run.run(&staged, 20, 0), Ok(()));
+    assert_eq!(dry_run.templates.counters[1], 5);
+    assert!(dry_run.templates.tick(20, 10_000).is_none());
+    assert_eq!(counting.counters[1], 0);
+
+    // Flash writes cannot be undone
+    let mut dry_run = DryRun::new(&widgets, &animations, &templates);
+    assert!(!dry_run.check(&Message::SetSchedule(Schedule::new()), 20, 0));
+    assert!(dry_run.check(&Message::SetBrightness(3), 20, 0));
+}
@@ -1,4 +1,4 @@
-use crate::{packet::{self, Command, Packet}, utils::udiv_ceil};
+use crate::{packet::{self, Command, Packet, PacketView}, utils::udiv_ceil};
 
 /// Number of bytes in a screen buffer
 pub const SCREEN_BUFFER_NBYTES: usize = 240 * 50;
@@ -56,6 +56,63 @@ pub fn screen_buffer_to_packets(screen_buffer: [[u8; 50]; 240], selected_lines:
     (packets, n_packets)
 }
 
+/// Writes the frames of `screen_buffer_to_packets` to `writer` one packet at a
+/// time, without building the payload or the packets first. Returns the
+/// number of bytes written.
+pub fn write_screen_buffer<W: embedded_io::Write>(
+    writer: &mut W,
+    screen_buffer: &[[u8; 50]; 240],
+    selected_lines: &[u8],
+    crc: bool,
+) -> Result<usize, W::Error> {
+    let n_lines_changed = selected_lines.len();
+    let payload_size = 1 + n_lines_changed + n_lines_changed * 50;
+
+    // Byte of the whole payload, as laid out by screen_buffer_to_packets
+    let payload_byte = |i: usize| match i {
+        0 => n_lines_changed as u8,
+        i if i <= n_lines_changed => selected_lines[i - 1],
+        i => {
+            let offset = i - 1 - n_lines_changed;
+            screen_buffer[selected_lines[offset / 50] as usize][offset % 50]
+        }
+    };
+
+    let mut written = 0;
+    let mut payload = [0x00; packet::MAX_PACKET_PAYLOAD_SIZE];
+    for start in (0..payload_size).step_by(packet::MAX_PACKET_PAYLOAD_SIZE) {
+        let chunk = &mut payload[..(payload_size - start).min(packet::MAX_PACKET_PAYLOAD_SIZE)];
+        for (i, byte) in chunk.iter_mut().enumerate() {
+            *byte = payload_byte(start + i);
+        }
+        written += PacketView::new(Command::CMD_SCREEN_BUFFER.into(), chunk).write_frame(crc, writer)?;
+    }
+
+    Ok(written)
+}
+
+#[test]
+fn test_write_screen_buffer() {
+    let mut screen_buffer = [[0; 50]; 240];
+    for (i, line) in screen_buffer.iter_mut().enumerate() {
+        line.fill(i as u8);
+    }
+    let selected_lines = [3, 0, 200, 7, 9, 100];
+
+    let (packets, n_packets) = screen_buffer_to_packets(screen_buffer, &selected_lines);
+    let mut expected = [0x00; 4 * packet::MAX_ENCODED_SIZE];
+    let mut size = 0;
+    for packet in &packets[..n_packets] {
+        let (encoded, encoded_size) = packet.to_frame(true);
+        expected[size..size + encoded_size].copy_from_slice(&encoded[..encoded_size]);
+        size += encoded_size;
+    }
+
+    let mut buf = [0x00; 4 * packet::MAX_ENCODED_SIZE];
+    assert_eq!(write_screen_buffer(&mut &mut buf[..], &screen_buffer, &selected_lines, true), Ok(size));
+    assert_eq!(buf[..size], expected[..size]);
+}
+
 // #[test]
 // fn test_screen_buffer_to_packets() {
 //     let screen_buffer = [[0; 50]; 240];
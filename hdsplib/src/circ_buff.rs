@@ -59,4 +59,27 @@ impl<T: Copy + Default, const N: usize> CircBuff<T, N> {
     pub fn remaining(&self) -> usize {
         N - self.size() - 1
     }
-}
\ No newline at end of file
+}
+
+/// Bytes are written up to the free space, unread ones are never overwritten
+impl<const N: usize> embedded_io::ErrorType for CircBuff<u8, N> {
+    type Error = embedded_io::ErrorKind;
+}
+
+impl<const N: usize> embedded_io::Write for CircBuff<u8, N> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = buf.len().min(self.remaining());
+        if size == 0 && !buf.is_empty() {
+            return Err(embedded_io::ErrorKind::OutOfMemory);
+        }
+
+        for &byte in &buf[..size] {
+            self.push(byte);
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
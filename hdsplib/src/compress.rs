@@ -0,0 +1,275 @@
+//! Compression of the messages sent in fragments, for large uploads such as
+//! animations, font packs and playlists.
+//!
+//! The format is LZ77 with a window of `WINDOW_SIZE` bytes. It is a series of
+//! tokens, each starting with a control byte `n`:
+//! - `0x00..=0x7F`: `n + 1` literal bytes follow
+//! - `0x80..=0xFF`: `(n & 0x7F) + MIN_MATCH` bytes are copied from `distance`
+//!   bytes back, the next byte being `distance - 1`. The copy may overlap the
+//!   bytes it produces.
+//!
+//! A run of the same byte is a copy from 1 byte back: `compress_rle` only
+//! looks for those, which is quick and enough for bitmaps, `compress_lz`
+//! looks through the whole window. Both are read by `Decompressor`, which
+//! needs no memory but the window whatever the size of the message.
+//!
+//! Compressed messages are sent with `fragment::compressed_fragments`, to
+//! devices announcing `info::CAP_COMPRESSION`.
+
+/// How far back a copy can reach
+pub const WINDOW_SIZE: usize = 256;
+/// Shortest copy, shorter ones are sent as literals
+pub const MIN_MATCH: usize = 3;
+/// Longest copy
+pub const MAX_MATCH: usize = MIN_MATCH + 0x7F;
+/// Longest run of literals after a control byte
+const MAX_LITERALS: usize = 0x80;
+const MATCH_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressError {
+    /// No room left for the output
+    Full,
+    /// A copy reaches back before the start of the data
+    BadDistance,
+    /// The data ends in the middle of a token
+    Truncated,
+}
+
+/// Output of the compressors
+struct Output<'a> {
+    buf: &'a mut [u8],
+    size: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), CompressError> {
+        let out = self.buf.get_mut(self.size..self.size + bytes.len()).ok_or(CompressError::Full)?;
+        out.copy_from_slice(bytes);
+        self.size += bytes.len();
+        Ok(())
+    }
+
+    fn literals(&mut self, literals: &[u8]) -> Result<(), CompressError> {
+        for chunk in literals.chunks(MAX_LITERALS) {
+            self.push(&[(chunk.len() - 1) as u8])?;
+            self.push(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length of the copy from `distance` bytes back matching the data at `pos`
+fn match_len(input: &[u8], pos: usize, distance: usize) -> usize {
+    input[pos..]
+        .iter()
+        .zip(&input[pos - distance..])
+        .take(MAX_MATCH)
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Compresses `input` into `output` with the copies `longest_match` finds,
+/// as `(length, distance)`
+fn compress_with(
+    input: &[u8],
+    output: &mut [u8],
+    longest_match: impl Fn(&[u8], usize) -> Option<(usize, usize)>,
+) -> Result<usize, CompressError> {
+    let mut out = Output { buf: output, size: 0 };
+    let mut literal_start = 0;
+    let mut pos = 0;
+
+    while pos < input.len() {
+        match longest_match(input, pos).filter(|&(len, _)| len >= MIN_MATCH) {
+            Some((len, distance)) => {
+                out.literals(&input[literal_start..pos])?;
+                out.push(&[MATCH_FLAG | (len - MIN_MATCH) as u8, (distance - 1) as u8])?;
+                pos += len;
+                literal_start = pos;
+            }
+            None => pos += 1,
+        }
+    }
+    out.literals(&input[literal_start..])?;
+
+    Ok(out.size)
+}
+
+/// Compresses the runs of `input` into `output`. Returns the compressed size.
+pub fn compress_rle(input: &[u8], output: &mut [u8]) -> Result<usize, CompressError> {
+    compress_with(input, output, |input, pos| match pos {
+        0 => None,
+        _ => Some((match_len(input, pos, 1), 1)),
+    })
+}
+
+/// Compresses `input` into `output`, looking for copies through the whole
+/// window. Slower than `compress_rle`, meant for the host. Returns the
+/// compressed size.
+pub fn compress_lz(input: &[u8], output: &mut [u8]) -> Result<usize, CompressError> {
+    compress_with(input, output, |input, pos| {
+        (1..=pos.min(WINDOW_SIZE))
+            .map(|distance| (match_len(input, pos, distance), distance))
+            .max_by_key(|&(len, distance)| (len, usize::MAX - distance))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Control,
+    /// Number of literal bytes still to come
+    Literals(u8),
+    /// Length of the copy, without `MIN_MATCH`, waiting for its distance
+    Distance(u8),
+}
+
+/// Decompresses data as it comes, a piece at a time
+pub struct Decompressor {
+    /// Last bytes output, for the copies
+    window: [u8; WINDOW_SIZE],
+    /// Number of bytes output so far
+    size: usize,
+    token: Token,
+}
+
+impl Decompressor {
+    pub fn new() -> Self {
+        Self {
+            window: [0; WINDOW_SIZE],
+            size: 0,
+            token: Token::Control,
+        }
+    }
+
+    /// Starts over with new data
+    pub fn reset(&mut self) {
+        self.size = 0;
+        self.token = Token::Control;
+    }
+
+    /// Number of bytes output so far
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the data so far ends between two tokens, as the whole data
+    /// must
+    pub fn is_done(&self) -> bool {
+        self.token == Token::Control
+    }
+
+    fn put(&mut self, byte: u8, output: &mut impl FnMut(u8) -> bool) -> Result<(), CompressError> {
+        if !output(byte) {
+            return Err(CompressError::Full);
+        }
+        self.window[self.size % WINDOW_SIZE] = byte;
+        self.size += 1;
+        Ok(())
+    }
+
+    /// Decompresses the next piece of the data, handing each byte to
+    /// `output`, which returns false when it has no room left
+    pub fn push(&mut self, input: &[u8], mut output: impl FnMut(u8) -> bool) -> Result<(), CompressError> {
+        for &byte in input {
+            self.token = match self.token {
+                Token::Control if byte & MATCH_FLAG == 0 => Token::Literals(byte + 1),
+                Token::Control => Token::Distance(byte & !MATCH_FLAG),
+                Token::Literals(remaining) => {
+                    self.put(byte, &mut output)?;
+                    match remaining {
+                        1 => Token::Control,
+                        _ => Token::Literals(remaining - 1),
+                    }
+                }
+                Token::Distance(len) => {
+                    let distance = byte as usize + 1;
+                    if distance > self.size {
+                        return Err(CompressError::BadDistance);
+                    }
+                    for _ in 0..len as usize + MIN_MATCH {
+                        let byte = self.window[(self.size - distance) % WINDOW_SIZE];
+                        self.put(byte, &mut output)?;
+                    }
+                    Token::Control
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decompresses the whole of `input` into `output`. Returns the decompressed
+/// size.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, CompressError> {
+    let mut decompressor = Decompressor::new();
+    let mut size = 0;
+    decompressor.push(input, |byte| match output.get_mut(size) {
+        Some(out) => {
+            *out = byte;
+            size += 1;
+            true
+        }
+        None => false,
+    })?;
+
+    match decompressor.is_done() {
+        true => Ok(size),
+        false => Err(CompressError::Truncated),
+    }
+}
+
+#[test]
+fn test_compress() {
+    use crate::random::LCG;
+
+    // A bitmap with long runs, text with repeated words, noise
+    let mut bitmap = [0x00; 700];
+    bitmap[100..300].fill(0x1F);
+    bitmap[450..452].fill(0x11);
+    let text = b"the quick brown fox jumps over the lazy dog, the quick brown fox jumps again";
+    let mut lcg = LCG::new(42);
+    let mut noise = [0x00; 300];
+    for byte in &mut noise {
+        *byte = (lcg.next() >> 24) as u8;
+    }
+
+    let mut compressed = [0x00; 1024];
+    let mut decompressed = [0x00; 1024];
+    for input in [&bitmap[..], &text[..], &noise[..], &[], &[7]] {
+        for compress in [compress_rle, compress_lz] {
+            let size = compress(input, &mut compressed).unwrap();
+            assert_eq!(decompress(&compressed[..size], &mut decompressed), Ok(input.len()));
+            assert_eq!(&decompressed[..input.len()], input);
+
+            // Piece by piece, as fragments come
+            let mut decompressor = Decompressor::new();
+            let mut out = heapless::Vec::<u8, 1024>::new();
+            for piece in compressed[..size].chunks(7) {
+                decompressor.push(piece, |byte| out.push(byte).is_ok()).unwrap();
+            }
+            assert!(decompressor.is_done());
+            assert_eq!(&out[..], input);
+        }
+    }
+
+    let rle = compress_rle(&bitmap, &mut compressed).unwrap();
+    assert!(rle < 30);
+    let lz = compress_lz(text, &mut compressed).unwrap();
+    assert!(lz < text.len() * 3 / 4);
+    // Incompressible data grows by a control byte every 128 bytes at most
+    assert!(compress_lz(&noise, &mut compressed).unwrap() <= noise.len() + 3);
+
+    assert_eq!(compress_rle(&bitmap, &mut compressed[..10]), Err(CompressError::Full));
+    assert_eq!(decompress(&compressed[..rle], &mut decompressed[..100]), Err(CompressError::Full));
+    // Copy before the start of the data, token cut short
+    assert_eq!(decompress(&[0x80, 0x00], &mut decompressed), Err(CompressError::BadDistance));
+    assert_eq!(decompress(&[0x00, 0x41, 0x81], &mut decompressed), Err(CompressError::Truncated));
+    assert_eq!(decompress(&[0x03, 0x41], &mut decompressed), Err(CompressError::Truncated));
+}
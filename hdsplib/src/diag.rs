@@ -0,0 +1,321 @@
+//! Diagnostics mode for incoming inspection and repair.
+//!
+//! Test patterns are shown one after the other on every digit so dead pixels,
+//! stuck rows and broken control lines are easy to spot. A report is sent at
+//! the end of each pattern with the result of the checks that need reading
+//! the display back.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{self, Frame, UdcBitmap, CW_BLINK, CW_FLASH, CW_SELF_TEST, MAX_BRIGHTNESS, NUM_DIGITS, UDC_COLUMNS, UDC_ROWS};
+use crate::packet::{Command, Packet};
+
+/// Default time each step of a pattern is shown
+pub const DEFAULT_STEP_MS: u16 = 500;
+/// Time the display needs to run its internal self test
+pub const SELF_TEST_MS: u64 = 5000;
+
+/// `CMD_DIAG` action stopping diagnostics
+pub const DIAG_STOP: u8 = 0;
+/// `CMD_DIAG` action starting diagnostics
+pub const DIAG_START: u8 = 1;
+
+const ALL_ON: UdcBitmap = [hdsp::UDC_ROW_MASK; UDC_ROWS];
+const CHECKERBOARD: UdcBitmap = [0x15, 0x0A, 0x15, 0x0A, 0x15, 0x0A, 0x15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pattern {
+    /// Every pixel of every digit on
+    AllOn = 0,
+    Checkerboard = 1,
+    CheckerboardInverse = 2,
+    /// A single pixel walking through each digit
+    WalkingPixel = 3,
+    /// Every ROM character, a page of 8 at a time
+    RomChars = 4,
+    /// Every pixel on at every brightness level
+    Brightness = 5,
+    Flash = 6,
+    Blink = 7,
+    /// Internal self test of the display
+    SelfTest = 8,
+}
+
+/// Patterns in the order they are shown
+pub const PATTERNS: [Pattern; 9] = [
+    Pattern::AllOn,
+    Pattern::Checkerboard,
+    Pattern::CheckerboardInverse,
+    Pattern::WalkingPixel,
+    Pattern::RomChars,
+    Pattern::Brightness,
+    Pattern::Flash,
+    Pattern::Blink,
+    Pattern::SelfTest,
+];
+
+impl Pattern {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pattern::AllOn => "all on",
+            Pattern::Checkerboard => "checkerboard",
+            Pattern::CheckerboardInverse => "inverse checkerboard",
+            Pattern::WalkingPixel => "walking pixel",
+            Pattern::RomChars => "ROM characters",
+            Pattern::Brightness => "brightness",
+            Pattern::Flash => "flash",
+            Pattern::Blink => "blink",
+            Pattern::SelfTest => "self test",
+        }
+    }
+
+    /// Number of steps of the pattern
+    pub fn steps(self) -> usize {
+        match self {
+            Pattern::WalkingPixel => UDC_ROWS * UDC_COLUMNS,
+            Pattern::RomChars => 128 / NUM_DIGITS,
+            Pattern::Brightness => MAX_BRIGHTNESS as usize + 1,
+            _ => 1,
+        }
+    }
+
+    /// Whether checking the pattern needs reading the display back
+    pub fn needs_read_back(self) -> bool {
+        self == Pattern::SelfTest
+    }
+
+    /// Replaces the contents of `frame` with `step` of the pattern
+    pub fn render(self, step: usize, frame: &mut Frame) {
+        *frame = Frame::new();
+
+        let udc = match self {
+            Pattern::AllOn | Pattern::Brightness | Pattern::Flash | Pattern::Blink => ALL_ON,
+            Pattern::Checkerboard => CHECKERBOARD,
+            Pattern::CheckerboardInverse => CHECKERBOARD.map(|row| !row & hdsp::UDC_ROW_MASK),
+            Pattern::WalkingPixel => {
+                let mut bitmap = hdsp::UDC_BLANK;
+                bitmap[step / UDC_COLUMNS] = hdsp::udc_column_mask(step % UDC_COLUMNS);
+                bitmap
+            }
+            Pattern::RomChars => {
+                for (i, c) in frame.chars.iter_mut().enumerate() {
+                    *c = (step * NUM_DIGITS + i) as u8;
+                }
+                return;
+            }
+            Pattern::SelfTest => {
+                frame.control |= CW_SELF_TEST;
+                return;
+            }
+        };
+
+        frame.udcs[0] = udc;
+        frame.chars = [hdsp::udc_char(0); NUM_DIGITS];
+
+        match self {
+            Pattern::Brightness => frame.set_brightness(step as u8),
+            Pattern::Flash => {
+                frame.flash = 0xFF;
+                frame.control |= CW_FLASH;
+            }
+            Pattern::Blink => frame.control |= CW_BLINK,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiagResult {
+    Pass = 0,
+    Fail = 1,
+    /// The display cannot be read back on this board
+    NotChecked = 2,
+    /// Shown to be looked at, the pattern has nothing the device can check
+    Shown = 3,
+}
+
+/// Sent by the device at the end of each pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagReport {
+    pub pattern: Pattern,
+    pub result: DiagResult,
+}
+
+impl DiagReport {
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_DIAG_REPORT.into());
+        packet.set_payload(&[self.pattern as u8, self.result as u8]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [pattern, result] => Some(Self {
+                pattern: *PATTERNS.get(*pattern as usize)?,
+                result: match result {
+                    0 => DiagResult::Pass,
+                    1 => DiagResult::Fail,
+                    2 => DiagResult::NotChecked,
+                    3 => DiagResult::Shown,
+                    _ => return None,
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// `CMD_DIAG` packet starting diagnostics. With `loops` 0 the patterns are
+/// repeated until diagnostics are stopped.
+pub fn start_to_packet(step_ms: u16, loops: u8) -> Packet {
+    let step_ms = step_ms.to_le_bytes();
+
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_DIAG.into());
+    packet.set_payload(&[DIAG_START, step_ms[0], step_ms[1], loops]);
+    packet
+}
+
+pub fn stop_to_packet() -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_DIAG.into());
+    packet.set_payload(&[DIAG_STOP]);
+    packet
+}
+
+/// Runs the patterns one step at a time
+pub struct Diagnostics {
+    running: bool,
+    step_ms: u16,
+    /// Loops left, 0 runs forever
+    loops: u8,
+    pattern: usize,
+    step: usize,
+    next_step_ms: u64,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self {
+            running: false,
+            step_ms: DEFAULT_STEP_MS,
+            loops: 1,
+            pattern: 0,
+            step: 0,
+            next_step_ms: 0,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn pattern(&self) -> Pattern {
+        PATTERNS[self.pattern]
+    }
+
+    pub fn start(&mut self, step_ms: u16, loops: u8, now_ms: u64) {
+        self.running = true;
+        self.step_ms = step_ms.max(1);
+        self.loops = loops;
+        self.pattern = 0;
+        self.step = 0;
+        self.next_step_ms = now_ms + self.step_duration_ms();
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    fn step_duration_ms(&self) -> u64 {
+        match self.pattern() {
+            Pattern::SelfTest => SELF_TEST_MS,
+            _ => self.step_ms as u64,
+        }
+    }
+
+    /// Moves to the next step when due. Returns the pattern just completed,
+    /// if any.
+    pub fn tick(&mut self, now_ms: u64) -> Option<Pattern> {
+        if !self.running || now_ms < self.next_step_ms {
+            return None;
+        }
+
+        let current = self.pattern();
+        self.step += 1;
+        if self.step < current.steps() {
+            self.next_step_ms = now_ms + self.step_duration_ms();
+            return None;
+        }
+
+        self.step = 0;
+        self.pattern += 1;
+        if self.pattern == PATTERNS.len() {
+            self.pattern = 0;
+            match self.loops {
+                0 => {}
+                1 => self.running = false,
+                _ => self.loops -= 1,
+            }
+        }
+        self.next_step_ms = now_ms + self.step_duration_ms();
+        Some(current)
+    }
+
+    /// Replaces the contents of `frame` with the current step
+    pub fn render(&self, frame: &mut Frame) {
+        if self.running {
+            self.pattern().render(self.step, frame);
+        }
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let mut frame = Frame::new();
+    Pattern::WalkingPixel.render(7, &mut frame);
+    assert_eq!(frame.udcs[0], [0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(frame.chars, [hdsp::udc_char(0); NUM_DIGITS]);
+
+    Pattern::RomChars.render(8, &mut frame);
+    assert_eq!(&frame.chars, b"@ABCDEFG");
+
+    Pattern::CheckerboardInverse.render(0, &mut frame);
+    assert_eq!(frame.udcs[0][0], 0x0A);
+
+    Pattern::Brightness.render(0, &mut frame);
+    assert_eq!(frame.brightness(), 0);
+
+    let mut diag = Diagnostics::new();
+    diag.start(100, 1, 0);
+    let steps: usize = PATTERNS.iter().map(|p| p.steps()).sum();
+    let mut completed = 0;
+    let mut now_ms = 0;
+    while diag.is_running() {
+        now_ms += 100;
+        if diag.tick(now_ms).is_some() {
+            completed += 1;
+        }
+    }
+    assert_eq!(completed, PATTERNS.len());
+    assert_eq!(now_ms, (steps as u64 - 1) * 100 + SELF_TEST_MS);
+
+    let report = DiagReport {
+        pattern: Pattern::SelfTest,
+        result: DiagResult::NotChecked,
+    };
+    assert_eq!(DiagReport::from_payload(report.to_packet().get_payload()), Some(report));
+    let report = DiagReport {
+        pattern: Pattern::Checkerboard,
+        result: DiagResult::Shown,
+    };
+    assert_eq!(DiagReport::from_payload(report.to_packet().get_payload()), Some(report));
+    assert_eq!(DiagReport::from_payload(&[0, 4]), None);
+}
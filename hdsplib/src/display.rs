@@ -0,0 +1,148 @@
+//! Commands acting directly on the display state: text, brightness, control
+//! word, UDCs, flash attributes, scrolling, clearing and resetting.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{UdcBitmap, MAX_BRIGHTNESS, NUM_UDC, UDC_ROWS, UDC_ROW_MASK};
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+
+fn packet(command: Command, payload: &[u8]) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(command.into());
+    packet.set_payload(payload);
+    packet
+}
+
+/// `CMD_SET_TEXT` packet with character RAM codes, a code with `UDC_FLAG`
+/// set selects a UDC. Longer texts are cut to what fits in a packet.
+pub fn text_to_packet(text: &[u8]) -> Packet {
+    packet(Command::CMD_SET_TEXT, &text[..text.len().min(MAX_PACKET_PAYLOAD_SIZE)])
+}
+
+/// `CMD_SET_BRIGHTNESS` packet, 0 being blank and 7 full brightness
+pub fn brightness_to_packet(level: u8) -> Packet {
+    packet(Command::CMD_SET_BRIGHTNESS, &[level])
+}
+
+pub fn brightness_from_payload(payload: &[u8]) -> Option<u8> {
+    match payload {
+        [level] if *level <= MAX_BRIGHTNESS => Some(*level),
+        _ => None,
+    }
+}
+
+/// `CMD_SET_CONTROL` packet with a raw control word. The clear bit is
+/// ignored, use `clear_to_packet` instead.
+pub fn control_to_packet(control: u8) -> Packet {
+    packet(Command::CMD_SET_CONTROL, &[control])
+}
+
+/// Bitmap of a UDC slot defined by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdcDefinition {
+    pub index: u8,
+    pub bitmap: UdcBitmap,
+}
+
+impl UdcDefinition {
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = [0; 1 + UDC_ROWS];
+        payload[0] = self.index;
+        payload[1..].copy_from_slice(&self.bitmap);
+        packet(Command::CMD_DEFINE_UDC, &payload)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let (&index, rows) = payload.split_first()?;
+        if index as usize >= NUM_UDC || rows.len() != UDC_ROWS {
+            return None;
+        }
+
+        let mut bitmap = [0; UDC_ROWS];
+        for (data, row) in bitmap.iter_mut().zip(rows) {
+            *data = row & UDC_ROW_MASK;
+        }
+        Some(Self { index, bitmap })
+    }
+}
+
+/// `CMD_SET_FLASH` packet with one bit per digit, bit 0 being the leftmost
+/// one. The digits flash whatever the text shows.
+pub fn flash_to_packet(digits: u8) -> Packet {
+    packet(Command::CMD_SET_FLASH, &[digits])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrollConfig {
+    /// Time between two scroll steps
+    pub step_ms: u16,
+    /// Pause when the text starts over
+    pub end_pause_ms: u16,
+}
+
+impl ScrollConfig {
+    pub const DEFAULT: ScrollConfig = ScrollConfig {
+        step_ms: 100,
+        end_pause_ms: 0,
+    };
+
+    pub fn to_packet(&self) -> Packet {
+        let step_ms = self.step_ms.to_le_bytes();
+        let end_pause_ms = self.end_pause_ms.to_le_bytes();
+        packet(
+            Command::CMD_SCROLL_CONFIG,
+            &[step_ms[0], step_ms[1], end_pause_ms[0], end_pause_ms[1]],
+        )
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [s0, s1, p0, p1] => Some(Self {
+                step_ms: u16::from_le_bytes([*s0, *s1]).max(1),
+                end_pause_ms: u16::from_le_bytes([*p0, *p1]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ScrollConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// `CMD_CLEAR` packet, removing the text, the UDCs and the flash attributes
+pub fn clear_to_packet() -> Packet {
+    packet(Command::CMD_CLEAR, &[])
+}
+
+/// `CMD_RESET` packet, resetting the display module and every setting of the
+/// display to its default
+pub fn reset_to_packet() -> Packet {
+    packet(Command::CMD_RESET, &[])
+}
+
+#[test]
+fn test_display_commands() {
+    let udc = UdcDefinition {
+        index: 3,
+        bitmap: [0x1F, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1F],
+    };
+    assert_eq!(UdcDefinition::from_payload(udc.to_packet().get_payload()), Some(udc));
+    assert_eq!(UdcDefinition::from_payload(&[16, 0, 0, 0, 0, 0, 0, 0]), None);
+
+    let scroll = ScrollConfig {
+        step_ms: 80,
+        end_pause_ms: 1000,
+    };
+    assert_eq!(ScrollConfig::from_payload(scroll.to_packet().get_payload()), Some(scroll));
+
+    assert_eq!(brightness_from_payload(brightness_to_packet(7).get_payload()), Some(7));
+    assert_eq!(brightness_from_payload(&[8]), None);
+
+    let packet = text_to_packet(b"HELLO");
+    assert_eq!(Command::try_from(packet.command()), Ok(Command::CMD_SET_TEXT));
+    assert_eq!(packet.get_payload(), b"HELLO");
+    assert!(clear_to_packet().get_payload().is_empty());
+}
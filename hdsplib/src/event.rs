@@ -0,0 +1,172 @@
+//! Events the device sends on its own, in `CMD_EVENT` packets framed like
+//! its answers.
+//!
+//! Nothing is sent until the host subscribes with `CMD_SUBSCRIBE`, giving the
+//! mask of the events it wants, so hosts which do not know about events
+//! never see one. A new subscription replaces the previous one, 0 stops
+//! them all.
+//!
+//! Kinds 1, 3 and 4 are reserved for a change of playlist item, a failed
+//! self test and a missing display module. This board has no playlist, and
+//! cannot read the display back to find out about the other two, so it never
+//! sends them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{Command, Packet};
+
+/// Time the button must stay in a state before it counts
+pub const DEBOUNCE_MS: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    ScrollDone = 0,
+    Button = 2,
+    RxOverflow = 5,
+}
+
+/// Subscription to every event, reserved ones included
+pub const ALL_EVENTS: u16 = (1 << 6) - 1;
+
+impl EventKind {
+    /// Bit of the event in a subscription mask
+    pub fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// The text was shown through and starts over
+    ScrollDone,
+    Button { pressed: bool },
+    /// Frames from the host dropped so far because they were longer than any
+    /// packet
+    RxOverflow(u16),
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::ScrollDone => EventKind::ScrollDone,
+            Event::Button { .. } => EventKind::Button,
+            Event::RxOverflow(_) => EventKind::RxOverflow,
+        }
+    }
+
+    /// Encoded as `[kind, data...]`
+    pub fn to_packet(&self) -> Packet {
+        let kind = self.kind() as u8;
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_EVENT.into());
+        match self {
+            Event::ScrollDone => packet.set_payload(&[kind]),
+            Event::Button { pressed } => packet.set_payload(&[kind, *pressed as u8]),
+            Event::RxOverflow(dropped) => {
+                let dropped = dropped.to_le_bytes();
+                packet.set_payload(&[kind, dropped[0], dropped[1]]);
+            }
+        }
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let kind = |kind: EventKind| kind as u8;
+
+        let event = match payload {
+            [k] if *k == kind(EventKind::ScrollDone) => Event::ScrollDone,
+            [k, pressed @ (0 | 1)] if *k == kind(EventKind::Button) => Event::Button { pressed: *pressed == 1 },
+            [k, lo, hi] if *k == kind(EventKind::RxOverflow) => Event::RxOverflow(u16::from_le_bytes([*lo, *hi])),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// `CMD_SUBSCRIBE` packet, `mask` having the bit of each event wanted
+pub fn subscribe_to_packet(mask: u16) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SUBSCRIBE.into());
+    packet.set_payload(&mask.to_le_bytes());
+    packet
+}
+
+pub fn subscribe_from_payload(payload: &[u8]) -> Option<u16> {
+    match payload {
+        [lo, hi] => Some(u16::from_le_bytes([*lo, *hi])),
+        _ => None,
+    }
+}
+
+/// Turns the level read on a button into presses and releases, ignoring
+/// the bounces of the contacts
+pub struct Debouncer {
+    pressed: bool,
+    /// Level last read, and since when
+    level: bool,
+    since_ms: u64,
+}
+
+impl Debouncer {
+    /// Starts from the level at boot, which is not reported
+    pub fn new(pressed: bool) -> Self {
+        Self {
+            pressed,
+            level: pressed,
+            since_ms: 0,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Feeds the level read. Returns the event once the button settles in a
+    /// new state.
+    pub fn update(&mut self, pressed: bool, now_ms: u64) -> Option<Event> {
+        if pressed != self.level {
+            self.level = pressed;
+            self.since_ms = now_ms;
+        }
+
+        if self.level == self.pressed || now_ms - self.since_ms < DEBOUNCE_MS {
+            return None;
+        }
+        self.pressed = self.level;
+        Some(Event::Button { pressed })
+    }
+}
+
+#[test]
+fn test_events() {
+    let events = [
+        Event::ScrollDone,
+        Event::Button { pressed: true },
+        Event::RxOverflow(300),
+    ];
+    for event in events {
+        let packet = event.to_packet();
+        assert_eq!(Command::try_from(packet.command()), Ok(Command::CMD_EVENT));
+        assert_eq!(Event::from_payload(packet.get_payload()), Some(event));
+        assert_ne!(event.kind().mask() & ALL_EVENTS, 0);
+    }
+    assert_eq!(Event::from_payload(&[EventKind::Button as u8, 2]), None);
+    assert_eq!(Event::from_payload(&[EventKind::ScrollDone as u8, 0]), None);
+    assert_eq!(Event::from_payload(&[1, 0]), None);
+    assert_eq!(Event::from_payload(&[6]), None);
+
+    let mask = EventKind::Button.mask() | EventKind::RxOverflow.mask();
+    assert_eq!(subscribe_from_payload(subscribe_to_packet(mask).get_payload()), Some(0b10_0100));
+
+    // Bounces shorter than DEBOUNCE_MS are ignored
+    let mut button = Debouncer::new(false);
+    assert_eq!(button.update(true, 100), None);
+    assert_eq!(button.update(false, 105), None);
+    assert_eq!(button.update(true, 110), None);
+    assert_eq!(button.update(true, 125), None);
+    assert_eq!(button.update(true, 130), Some(Event::Button { pressed: true }));
+    assert_eq!(button.update(true, 200), None);
+    assert!(button.is_pressed());
+    assert_eq!(button.update(false, 300), None);
+    assert_eq!(button.update(false, 320), Some(Event::Button { pressed: false }));
+}
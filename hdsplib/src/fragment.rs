@@ -0,0 +1,368 @@
+//! Messages larger than a packet, split into `CMD_FRAGMENT` packets.
+//!
+//! Each fragment carries the command of the whole message, a message id, its
+//! index and the total length of the message, followed by up to
+//! `FRAGMENT_DATA_SIZE` bytes of it. The receiver puts the fragments back in
+//! place whatever order they come in, and hands out the message once every
+//! fragment is there.
+//!
+//! A lost fragment is not asked for again: numbering the fragments with
+//! `Packet::set_seq` gets each one acknowledged, and the host resends the
+//! missing ones. A message left incomplete is dropped after
+//! `REASSEMBLY_TIMEOUT_MS`.
+//!
+//! A message compressed with `compress` is sent with `compressed_fragments`,
+//! to devices announcing `info::CAP_COMPRESSION`. The receiver decompresses
+//! the fragments as they come, so they have to come in order, and the total
+//! length is that of the compressed message.
+
+use crate::auth::MAX_AUTH_PAYLOAD_SIZE;
+use crate::compress::{CompressError, Decompressor};
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+
+/// Command, message id, fragment index and total length
+const HEADER_SIZE: usize = 5;
+/// Bytes of the message in a fragment, room is left for a sequence number
+/// and for wrapping the fragment in `CMD_AUTH`
+pub const FRAGMENT_DATA_SIZE: usize = MAX_AUTH_PAYLOAD_SIZE - HEADER_SIZE;
+/// Maximum number of fragments in a message
+pub const MAX_FRAGMENTS: usize = 256;
+/// Largest message the format can carry, receivers usually take less
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAGMENTS * FRAGMENT_DATA_SIZE;
+/// Time without a new fragment after which an incomplete message is dropped
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 2000;
+/// Set in the command byte of the fragments of a compressed message
+pub const FRAGMENT_FLAG_COMPRESSED: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// Header too short, fragment index or size not matching the total length
+    Malformed,
+    /// Message larger than what the receiver can hold
+    TooLarge,
+    /// Fragment of a compressed message coming before the previous ones
+    OutOfOrder,
+    /// Compressed message which does not decompress
+    Corrupt,
+}
+
+fn fragment_count(total: usize) -> usize {
+    total.div_ceil(FRAGMENT_DATA_SIZE).max(1)
+}
+
+/// Splits `message` into the fragments of message `id`, which the receiver
+/// applies as `command` once complete
+pub fn fragments(id: u8, command: Command, message: &[u8]) -> Result<Fragments<'_>, FragmentError> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(FragmentError::TooLarge);
+    }
+
+    Ok(Fragments {
+        id,
+        command: command.into(),
+        message,
+        index: 0,
+    })
+}
+
+/// Splits the compressed message `compressed` into the fragments of message
+/// `id`, which the receiver decompresses and applies as `command`
+pub fn compressed_fragments(id: u8, command: Command, compressed: &[u8]) -> Result<Fragments<'_>, FragmentError> {
+    let mut fragments = fragments(id, command, compressed)?;
+    fragments.command |= FRAGMENT_FLAG_COMPRESSED;
+    Ok(fragments)
+}
+
+/// Iterator over the `CMD_FRAGMENT` packets of a message
+pub struct Fragments<'a> {
+    id: u8,
+    command: u8,
+    message: &'a [u8],
+    index: usize,
+}
+
+impl Iterator for Fragments<'_> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        if self.index >= fragment_count(self.message.len()) {
+            return None;
+        }
+
+        let start = self.index * FRAGMENT_DATA_SIZE;
+        let end = (start + FRAGMENT_DATA_SIZE).min(self.message.len());
+        let total = (self.message.len() as u16).to_le_bytes();
+
+        let mut payload = [0; MAX_PACKET_PAYLOAD_SIZE];
+        payload[..HEADER_SIZE].copy_from_slice(&[self.command, self.id, self.index as u8, total[0], total[1]]);
+        payload[HEADER_SIZE..HEADER_SIZE + end - start].copy_from_slice(&self.message[start..end]);
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_FRAGMENT.into());
+        packet.set_payload(&payload[..HEADER_SIZE + end - start]);
+
+        self.index += 1;
+        Some(packet)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pending {
+    command: u8,
+    id: u8,
+    total: usize,
+}
+
+impl Pending {
+    fn is_compressed(&self) -> bool {
+        self.command & FRAGMENT_FLAG_COMPRESSED != 0
+    }
+}
+
+/// Puts back together one message of up to `N` bytes at a time, `N` being
+/// the size after decompression for compressed ones
+pub struct Reassembler<const N: usize> {
+    buf: [u8; N],
+    pending: Option<Pending>,
+    /// One bit per fragment received
+    received: [u32; MAX_FRAGMENTS / 32],
+    last_ms: u64,
+    decompressor: Decompressor,
+    /// Fragments of the compressed message decompressed so far
+    decompressed: usize,
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; N],
+            pending: None,
+            received: [0; MAX_FRAGMENTS / 32],
+            last_ms: 0,
+            decompressor: Decompressor::new(),
+            decompressed: 0,
+        }
+    }
+
+    /// Adds the payload of a `CMD_FRAGMENT` packet. Returns the command and
+    /// the message once its last fragment is in.
+    ///
+    /// A fragment of another message drops the one in progress, the host only
+    /// sends one at a time.
+    pub fn push(&mut self, payload: &[u8], now_ms: u64) -> Result<Option<(u8, &[u8])>, FragmentError> {
+        let [command, id, index, lo, hi, data @ ..] = payload else {
+            return Err(FragmentError::Malformed);
+        };
+        let header = Pending {
+            command: *command,
+            id: *id,
+            total: u16::from_le_bytes([*lo, *hi]) as usize,
+        };
+        let index = *index as usize;
+
+        let limit = match header.is_compressed() {
+            true => MAX_MESSAGE_SIZE,
+            false => N.min(MAX_MESSAGE_SIZE),
+        };
+        if header.total > limit {
+            return Err(FragmentError::TooLarge);
+        }
+        let start = index * FRAGMENT_DATA_SIZE;
+        if index >= fragment_count(header.total) || data.len() != (header.total - start).min(FRAGMENT_DATA_SIZE) {
+            return Err(FragmentError::Malformed);
+        }
+
+        self.tick(now_ms);
+        if self.pending != Some(header) {
+            self.pending = Some(header);
+            self.received = [0; MAX_FRAGMENTS / 32];
+            self.decompressor.reset();
+            self.decompressed = 0;
+        }
+        self.last_ms = now_ms;
+
+        if header.is_compressed() {
+            return self.push_compressed(header, index, data);
+        }
+
+        self.buf[start..start + data.len()].copy_from_slice(data);
+        self.received[index / 32] |= 1 << (index % 32);
+
+        let count: u32 = self.received.iter().map(|r| r.count_ones()).sum();
+        if count as usize != fragment_count(header.total) {
+            return Ok(None);
+        }
+
+        self.pending = None;
+        Ok(Some((header.command, &self.buf[..header.total])))
+    }
+
+    /// Decompresses the next fragment of a compressed message. A fragment sent
+    /// again is ignored.
+    fn push_compressed(&mut self, header: Pending, index: usize, data: &[u8]) -> Result<Option<(u8, &[u8])>, FragmentError> {
+        if index < self.decompressed {
+            return Ok(None);
+        }
+        if index > self.decompressed {
+            return Err(FragmentError::OutOfOrder);
+        }
+
+        // Straight into the message, the decompressor keeps its own window
+        let buf = &mut self.buf;
+        let mut size = self.decompressor.size();
+        let result = self.decompressor.push(data, |byte| match buf.get_mut(size) {
+            Some(out) => {
+                *out = byte;
+                size += 1;
+                true
+            }
+            None => false,
+        });
+        if let Err(e) = result {
+            self.pending = None;
+            return Err(match e {
+                CompressError::Full => FragmentError::TooLarge,
+                _ => FragmentError::Corrupt,
+            });
+        }
+
+        self.decompressed += 1;
+        if self.decompressed != fragment_count(header.total) {
+            return Ok(None);
+        }
+
+        self.pending = None;
+        if !self.decompressor.is_done() {
+            return Err(FragmentError::Corrupt);
+        }
+        Ok(Some((header.command & !FRAGMENT_FLAG_COMPRESSED, &self.buf[..size])))
+    }
+
+    /// Drops the message in progress if it waited too long for a fragment.
+    /// Returns its id if it did.
+    pub fn tick(&mut self, now_ms: u64) -> Option<u8> {
+        let pending = self.pending?;
+        if now_ms < self.last_ms + REASSEMBLY_TIMEOUT_MS {
+            return None;
+        }
+
+        self.pending = None;
+        Some(pending.id)
+    }
+}
+
+impl<const N: usize> Default for Reassembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_fragments() {
+    let mut message = [0; 600];
+    for (i, byte) in message.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let mut packets = heapless::Vec::<Packet, 4>::new();
+    for packet in fragments(7, Command::CMD_SET_MARKUP, &message).unwrap() {
+        assert_eq!(packet.command(), Command::CMD_FRAGMENT as u8);
+        packets.push(packet).unwrap();
+    }
+    assert_eq!(packets.len(), 3);
+
+    // Out of order, with a duplicate
+    let mut reassembler: Reassembler<1024> = Reassembler::new();
+    assert_eq!(reassembler.push(packets[2].get_payload(), 0), Ok(None));
+    assert_eq!(reassembler.push(packets[0].get_payload(), 10), Ok(None));
+    assert_eq!(reassembler.push(packets[0].get_payload(), 20), Ok(None));
+    let (command, decoded) = reassembler.push(packets[1].get_payload(), 30).unwrap().unwrap();
+    assert_eq!(command, Command::CMD_SET_MARKUP as u8);
+    assert_eq!(decoded, &message[..]);
+
+    // A missing fragment lets the message expire
+    assert_eq!(reassembler.push(packets[0].get_payload(), 100), Ok(None));
+    assert_eq!(reassembler.tick(100 + REASSEMBLY_TIMEOUT_MS - 1), None);
+    assert_eq!(reassembler.tick(100 + REASSEMBLY_TIMEOUT_MS), Some(7));
+    assert_eq!(reassembler.push(packets[1].get_payload(), 5000), Ok(None));
+    assert_eq!(reassembler.push(packets[2].get_payload(), 5000), Ok(None));
+
+    let mut small: Reassembler<512> = Reassembler::new();
+    assert_eq!(small.push(packets[0].get_payload(), 0), Err(FragmentError::TooLarge));
+    assert_eq!(small.push(&[0x0A, 7, 0, 4], 0), Err(FragmentError::Malformed));
+    // Index past the end of the message
+    assert_eq!(small.push(&[0x0A, 7, 1, 3, 0, 1, 2, 3], 0), Err(FragmentError::Malformed));
+
+    let mut empty = fragments(1, Command::CMD_CLEAR, &[]).unwrap();
+    let (command, decoded) = small.push(empty.next().unwrap().get_payload(), 0).unwrap().unwrap();
+    assert_eq!((command, decoded), (Command::CMD_CLEAR as u8, &[][..]));
+    assert!(empty.next().is_none());
+
+    // Numbered to be acknowledged, and framed, full fragments included
+    let mut reassembler: Reassembler<1024> = Reassembler::new();
+    let mut result = None;
+    for (seq, mut packet) in fragments(8, Command::CMD_SET_MARKUP, &message).unwrap().enumerate() {
+        packet.set_seq(seq as u8);
+        let (frame, size) = packet.to_frame(true);
+        let (received, _) = Packet::from_frame(&frame[..size]).unwrap();
+        assert_eq!(received.seq(), Some(seq as u8));
+        result = reassembler.push(received.get_payload(), 0).unwrap();
+    }
+    assert_eq!(result, Some((Command::CMD_SET_MARKUP as u8, &message[..])));
+}
+
+#[test]
+fn test_compressed_fragments() {
+    use crate::compress;
+
+    // Large once decompressed, only the receiver buffer has to hold it all
+    let mut message = [0x00; 3000];
+    for (i, byte) in message.iter_mut().enumerate() {
+        *byte = (i / 100) as u8;
+    }
+    let mut compressed = [0x00; 1024];
+    let size = compress::compress_lz(&message, &mut compressed).unwrap();
+    let compressed = &compressed[..size];
+
+    // Noise hardly compresses, it takes several fragments
+    let mut noise = [0x00; 600];
+    let mut lcg = crate::random::LCG::new(7);
+    for byte in &mut noise {
+        *byte = (lcg.next() >> 24) as u8;
+    }
+    let mut noisy = [0x00; 700];
+    let noisy_size = compress::compress_rle(&noise, &mut noisy).unwrap();
+    let mut packets = heapless::Vec::<Packet, 4>::new();
+    for packet in compressed_fragments(3, Command::CMD_SET_MARKUP, &noisy[..noisy_size]).unwrap() {
+        packets.push(packet).unwrap();
+    }
+    assert_eq!(packets.len(), 3);
+
+    let mut reassembler: Reassembler<4096> = Reassembler::new();
+    assert_eq!(reassembler.push(packets[0].get_payload(), 0), Ok(None));
+    // Sent again, ignored
+    assert_eq!(reassembler.push(packets[0].get_payload(), 0), Ok(None));
+    assert_eq!(reassembler.push(packets[2].get_payload(), 0), Err(FragmentError::OutOfOrder));
+    assert_eq!(reassembler.push(packets[1].get_payload(), 0), Ok(None));
+    let (command, decoded) = reassembler.push(packets[2].get_payload(), 0).unwrap().unwrap();
+    assert_eq!(command, Command::CMD_SET_MARKUP as u8);
+    assert_eq!(decoded, &noise[..]);
+
+    let mut fragments = compressed_fragments(4, Command::CMD_MESSAGE, compressed).unwrap();
+    let packet = fragments.next().unwrap();
+    assert!(fragments.next().is_none());
+    let (command, decoded) = reassembler.push(packet.get_payload(), 0).unwrap().unwrap();
+    assert_eq!(command, Command::CMD_MESSAGE as u8);
+    assert_eq!(decoded, &message[..]);
+
+    // Larger than the receiver once decompressed
+    let mut small: Reassembler<1024> = Reassembler::new();
+    assert_eq!(small.push(packet.get_payload(), 0), Err(FragmentError::TooLarge));
+
+    // Copy reaching before the start of the message
+    let mut bad = compressed_fragments(5, Command::CMD_MESSAGE, &[0x80, 0x10]).unwrap();
+    assert_eq!(small.push(bad.next().unwrap().get_payload(), 0), Err(FragmentError::Corrupt));
+    // Message ending in the middle of a token
+    let mut bad = compressed_fragments(6, Command::CMD_MESSAGE, &[0x01, 0x41]).unwrap();
+    assert_eq!(small.push(bad.next().unwrap().get_payload(), 0), Err(FragmentError::Corrupt));
+}
@@ -0,0 +1,128 @@
+//! Memory model of the HDSP 253X 8 character 5x7 dot matrix display.
+
+use serde::{Deserialize, Serialize};
+
+/// Number of digits in a display module
+pub const NUM_DIGITS: usize = 8;
+/// Number of user defined characters (UDC) slots
+pub const NUM_UDC: usize = 16;
+/// Rows in a UDC bitmap
+pub const UDC_ROWS: usize = 7;
+/// Columns in a UDC bitmap
+pub const UDC_COLUMNS: usize = 5;
+/// Mask of the valid bits of a UDC row
+pub const UDC_ROW_MASK: u8 = 0x1F;
+
+/// Bit set in a character RAM byte to select a UDC instead of an ASCII glyph
+pub const UDC_FLAG: u8 = 0x80;
+
+/// Brightness bits of the control word. 0 is 100%, 7 is blank.
+pub const CW_BRIGHTNESS_MASK: u8 = 0x07;
+/// Flash enable bit of the control word
+pub const CW_FLASH: u8 = 0x08;
+/// Blink enable bit of the control word
+pub const CW_BLINK: u8 = 0x10;
+/// Self test result bit of the control word (read only)
+pub const CW_SELF_TEST_RESULT: u8 = 0x20;
+/// Self test start bit of the control word
+pub const CW_SELF_TEST: u8 = 0x40;
+/// Clears the character and flash RAM when set in the control word
+pub const CW_CLEAR: u8 = 0x80;
+
+/// Highest brightness level, 0 being blank
+pub const MAX_BRIGHTNESS: u8 = 7;
+
+/// One UDC bitmap, a row per byte with the leftmost column in bit 4.
+pub type UdcBitmap = [u8; UDC_ROWS];
+
+/// Bitmap with every pixel off
+pub const UDC_BLANK: UdcBitmap = [0x00; UDC_ROWS];
+
+/// Character RAM code along with its flash attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Glyph {
+    /// ASCII character, or a UDC when `UDC_FLAG` is set
+    pub code: u8,
+    pub flash: bool,
+}
+
+impl Glyph {
+    pub fn new(code: u8) -> Self {
+        Self { code, flash: false }
+    }
+}
+
+/// Character RAM code selecting the UDC `index`
+pub fn udc_char(index: u8) -> u8 {
+    UDC_FLAG | (index & 0x0F)
+}
+
+/// Row mask lighting `column` of a UDC, 0 being the leftmost one
+pub fn udc_column_mask(column: usize) -> u8 {
+    0x10 >> column
+}
+
+/// Converts a brightness level (0 blank, 7 full) into control word bits
+pub fn brightness_to_control(level: u8) -> u8 {
+    MAX_BRIGHTNESS - level.min(MAX_BRIGHTNESS)
+}
+
+/// Converts the brightness bits of a control word into a level (0 blank, 7 full)
+pub fn control_to_brightness(control: u8) -> u8 {
+    MAX_BRIGHTNESS - (control & CW_BRIGHTNESS_MASK)
+}
+
+/// Complete contents of a display module: character RAM, UDC RAM, flash RAM
+/// and the control word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub chars: [u8; NUM_DIGITS],
+    pub udcs: [UdcBitmap; NUM_UDC],
+    /// One bit per digit, bit 0 being the leftmost digit
+    pub flash: u8,
+    pub control: u8,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self {
+            chars: [b' '; NUM_DIGITS],
+            udcs: [UDC_BLANK; NUM_UDC],
+            flash: 0x00,
+            control: 0x00,
+        }
+    }
+
+    /// Writes `text` starting at digit `pos`, clipping at the end of the display
+    pub fn put_str(&mut self, pos: usize, text: &[u8]) {
+        for (i, &c) in text.iter().enumerate() {
+            if pos + i >= NUM_DIGITS {
+                break;
+            }
+            self.chars[pos + i] = c & 0x7F;
+        }
+    }
+
+    /// Bitmap shown by character RAM code `code`
+    pub fn bitmap(&self, code: u8) -> UdcBitmap {
+        if code & UDC_FLAG != 0 {
+            self.udcs[(code & 0x0F) as usize]
+        } else {
+            crate::font::rom_bitmap(code)
+        }
+    }
+
+    pub fn brightness(&self) -> u8 {
+        control_to_brightness(self.control)
+    }
+
+    pub fn set_brightness(&mut self, level: u8) {
+        self.control = (self.control & !CW_BRIGHTNESS_MASK) | brightness_to_control(level);
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,160 @@
+//! `CMD_HELLO` request and the `CMD_INFO` answer describing the device, so
+//! host tools can refuse devices they do not support and adapt to the others.
+
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+
+/// Version of the protocol spoken by this library. Raised when a change
+/// breaks older hosts or devices, not when commands are added.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Maximum length of the firmware version string
+pub const MAX_VERSION_LEN: usize = 32;
+
+/// Frames may end with a CRC, see `packet::CMD_FLAG_CRC`
+pub const CAP_CRC: u32 = 1 << 0;
+/// Numbered packets are acknowledged, see `reliable`
+pub const CAP_ACK: u32 = 1 << 1;
+/// Messages may be split in fragments, see `fragment`
+pub const CAP_FRAGMENTS: u32 = 1 << 2;
+pub const CAP_MARKUP: u32 = 1 << 3;
+pub const CAP_TEMPLATES: u32 = 1 << 4;
+pub const CAP_WIDGETS: u32 = 1 << 5;
+pub const CAP_ANIMATIONS: u32 = 1 << 6;
+pub const CAP_TRANSITIONS: u32 = 1 << 7;
+pub const CAP_RSVP: u32 = 1 << 8;
+pub const CAP_DIAGNOSTICS: u32 = 1 << 9;
+pub const CAP_SCHEDULE: u32 = 1 << 10;
+/// The device has a real time clock set by `CMD_SET_TIME`
+pub const CAP_CLOCK: u32 = 1 << 11;
+/// The display state can be read back, see `query`
+pub const CAP_QUERY: u32 = 1 << 12;
+/// Fragmented messages may be compressed, see `compress`
+pub const CAP_COMPRESSION: u32 = 1 << 13;
+/// Commands may be authenticated, see `auth`
+pub const CAP_AUTH: u32 = 1 << 14;
+/// Events are sent to the hosts subscribing, see `event`
+pub const CAP_EVENTS: u32 = 1 << 15;
+/// Commands may be applied at once in batches, see `batch`
+pub const CAP_BATCH: u32 = 1 << 16;
+/// Commands may be applied at a given time of the device, see `sync`
+pub const CAP_SYNC: u32 = 1 << 17;
+
+/// Display modules the firmware can drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayModel {
+    Unknown = 0,
+    /// HP/Avago HDSP-2531 to HDSP-2534, 8 digits of 5x7 pixels
+    Hdsp253x = 1,
+}
+
+impl DisplayModel {
+    pub fn from_u8(model: u8) -> Self {
+        match model {
+            1 => DisplayModel::Hdsp253x,
+            _ => DisplayModel::Unknown,
+        }
+    }
+}
+
+/// Fixed size part of the `CMD_INFO` payload, before the version string
+const FIXED_SIZE: usize = 18;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub protocol_version: u8,
+    /// Version of the firmware crate, such as "0.1.0"
+    pub firmware_version: heapless::String<MAX_VERSION_LEN>,
+    /// Unique ID of the microcontroller
+    pub unique_id: [u8; 8],
+    pub display_model: DisplayModel,
+    /// Number of display modules chained on the board
+    pub modules: u8,
+    /// Number of digits over every module
+    pub digits: u8,
+    /// Number of user defined characters
+    pub udcs: u8,
+    /// `CAP_*` bits of the features the firmware supports
+    pub capabilities: u32,
+}
+
+impl DeviceInfo {
+    pub fn has(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    /// Encoded as `[protocol, model, modules, digits, udcs, capabilities_le,
+    /// unique_id, version_len, version...]`
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = [0; MAX_PACKET_PAYLOAD_SIZE];
+        payload[..5].copy_from_slice(&[
+            self.protocol_version,
+            self.display_model as u8,
+            self.modules,
+            self.digits,
+            self.udcs,
+        ]);
+        payload[5..9].copy_from_slice(&self.capabilities.to_le_bytes());
+        payload[9..17].copy_from_slice(&self.unique_id);
+
+        let version = self.firmware_version.as_bytes();
+        payload[17] = version.len() as u8;
+        payload[FIXED_SIZE..FIXED_SIZE + version.len()].copy_from_slice(version);
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_INFO.into());
+        packet.set_payload(&payload[..FIXED_SIZE + version.len()]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let (fixed, version) = payload.split_at_checked(FIXED_SIZE)?;
+        if version.len() != fixed[17] as usize {
+            return None;
+        }
+
+        Some(Self {
+            protocol_version: fixed[0],
+            display_model: DisplayModel::from_u8(fixed[1]),
+            modules: fixed[2],
+            digits: fixed[3],
+            udcs: fixed[4],
+            capabilities: u32::from_le_bytes(fixed[5..9].try_into().ok()?),
+            unique_id: fixed[9..17].try_into().ok()?,
+            firmware_version: heapless::String::try_from(core::str::from_utf8(version).ok()?).ok()?,
+        })
+    }
+}
+
+/// `CMD_HELLO` packet, answered with `CMD_INFO`
+pub fn hello_to_packet() -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_HELLO.into());
+    packet.set_payload(&[]);
+    packet
+}
+
+#[test]
+fn test_info() {
+    let info = DeviceInfo {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: heapless::String::try_from("0.1.0").unwrap(),
+        unique_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        display_model: DisplayModel::Hdsp253x,
+        modules: 1,
+        digits: 8,
+        udcs: 16,
+        capabilities: CAP_CRC | CAP_ACK | CAP_MARKUP,
+    };
+
+    let packet = info.to_packet();
+    assert_eq!(Command::try_from(packet.command()), Ok(Command::CMD_INFO));
+    let decoded = DeviceInfo::from_payload(packet.get_payload()).unwrap();
+    assert_eq!(decoded, info);
+    assert!(decoded.has(CAP_CRC | CAP_MARKUP));
+    assert!(!decoded.has(CAP_FRAGMENTS));
+
+    assert_eq!(DeviceInfo::from_payload(&packet.get_payload()[..20]), None);
+    assert!(hello_to_packet().get_payload().is_empty());
+}
@@ -5,6 +5,28 @@ pub mod cast;
 pub mod utils;
 pub mod circ_buff;
 pub mod random;
+pub mod hdsp;
+pub mod widget;
+pub mod anim;
+pub mod markup;
+pub mod time;
+pub mod template;
+pub mod font;
+pub mod transition;
+pub mod rsvp;
+pub mod diag;
+pub mod schedule;
+pub mod display;
+pub mod reliable;
+pub mod compress;
+pub mod fragment;
+pub mod auth;
+pub mod batch;
+pub mod sync;
+pub mod info;
+pub mod query;
+pub mod event;
+pub mod message;
 
 // pub fn add(left: u64, right: u64) -> u64 {
 //     left + right
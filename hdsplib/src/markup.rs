@@ -0,0 +1,270 @@
+//! Inline markup for rich messages.
+//!
+//! Plain ASCII is shown as is, tags between braces change how it is shown:
+//!
+//! - `{flash}` ... `{/flash}`: flash the enclosed characters
+//! - `{udc:N}`: show UDC slot `N` (0-15)
+//! - `{ch:N}`: show character `N` (0-127) of the ROM, for the symbols below 0x20
+//! - `{b:N}`: set the brightness to `N` (0 blank, 7 full)
+//! - `{p:N}`: pause scrolling for `N` ms
+//! - `{{`: a literal `{`
+//!
+//! Brightness changes and pauses are anchored to the glyph that follows them
+//! and fire once every glyph before them has been shown.
+
+use core::fmt;
+
+use crate::hdsp::{self, Glyph, MAX_BRIGHTNESS, NUM_UDC};
+
+/// Maximum number of glyphs in a message
+pub const MAX_MARKUP_GLYPHS: usize = 128;
+/// Maximum number of brightness changes and pauses in a message
+pub const MAX_MARKUP_EVENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Brightness(u8),
+    Pause(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnchoredEvent {
+    /// Index of the glyph following the event
+    pub anchor: usize,
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkupErrorKind {
+    /// A `{` without its `}`
+    UnterminatedTag,
+    UnknownTag,
+    /// A tag that needs an argument, like `{udc}`
+    MissingArgument,
+    /// An argument that is not a number or is out of range
+    InvalidArgument,
+    /// An argument given to a tag that takes none, like `{flash:1}`
+    UnexpectedArgument,
+    /// `{flash}` inside `{flash}`
+    NestedTag,
+    /// `{/flash}` without `{flash}`
+    UnbalancedClose,
+    /// `{flash}` without `{/flash}`
+    UnclosedTag,
+    /// A character the display cannot show
+    NonAscii,
+    TooManyGlyphs,
+    TooManyEvents,
+}
+
+/// Markup error at byte `pos` of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarkupError {
+    pub pos: usize,
+    pub kind: MarkupErrorKind,
+}
+
+impl fmt::Display for MarkupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self.kind {
+            MarkupErrorKind::UnterminatedTag => "unterminated tag",
+            MarkupErrorKind::UnknownTag => "unknown tag",
+            MarkupErrorKind::MissingArgument => "missing argument",
+            MarkupErrorKind::InvalidArgument => "invalid argument",
+            MarkupErrorKind::UnexpectedArgument => "unexpected argument",
+            MarkupErrorKind::NestedTag => "nested tag",
+            MarkupErrorKind::UnbalancedClose => "closing tag without opening tag",
+            MarkupErrorKind::UnclosedTag => "tag is never closed",
+            MarkupErrorKind::NonAscii => "character is not ASCII",
+            MarkupErrorKind::TooManyGlyphs => "message too long",
+            MarkupErrorKind::TooManyEvents => "too many brightness changes and pauses",
+        };
+        write!(f, "{} at position {}", msg, self.pos)
+    }
+}
+
+/// Glyphs and events of a parsed message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Markup {
+    pub glyphs: heapless::Vec<Glyph, MAX_MARKUP_GLYPHS>,
+    pub events: heapless::Vec<AnchoredEvent, MAX_MARKUP_EVENTS>,
+}
+
+impl Markup {
+    pub fn new() -> Self {
+        Self {
+            glyphs: heapless::Vec::new(),
+            events: heapless::Vec::new(),
+        }
+    }
+
+    /// Message of character RAM codes taken as they are, without tags. Codes
+    /// with `UDC_FLAG` set select a UDC.
+    pub fn plain(text: &[u8]) -> Result<Self, MarkupError> {
+        let mut markup = Markup::new();
+        for (pos, &code) in text.iter().enumerate() {
+            markup.glyphs.push(Glyph::new(code)).map_err(|_| MarkupError {
+                pos,
+                kind: MarkupErrorKind::TooManyGlyphs,
+            })?;
+        }
+        Ok(markup)
+    }
+}
+
+impl Default for Markup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn parse(input: &str) -> Result<Markup, MarkupError> {
+    let mut markup = Markup::new();
+    let bytes = input.as_bytes();
+
+    // Position of the open `{flash}` tag, if any
+    let mut flash: Option<usize> = None;
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let c = bytes[pos];
+
+        if c == b'{' && bytes.get(pos + 1) == Some(&b'{') {
+            push_glyph(&mut markup, pos, b'{', flash.is_some())?;
+            pos += 2;
+            continue;
+        }
+
+        if c != b'{' {
+            if !c.is_ascii() {
+                return Err(MarkupError {
+                    pos,
+                    kind: MarkupErrorKind::NonAscii,
+                });
+            }
+            push_glyph(&mut markup, pos, c, flash.is_some())?;
+            pos += 1;
+            continue;
+        }
+
+        let end = match bytes[pos..].iter().position(|&b| b == b'}') {
+            Some(len) => pos + len,
+            None => {
+                return Err(MarkupError {
+                    pos,
+                    kind: MarkupErrorKind::UnterminatedTag,
+                })
+            }
+        };
+
+        let tag = &input[pos + 1..end];
+        let (name, arg) = match tag.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (tag, None),
+        };
+
+        let error = |kind| MarkupError { pos, kind };
+        let number = |max: u32| -> Result<u32, MarkupError> {
+            let arg = arg.ok_or(error(MarkupErrorKind::MissingArgument))?;
+            match arg.parse::<u32>() {
+                Ok(n) if n <= max => Ok(n),
+                _ => Err(error(MarkupErrorKind::InvalidArgument)),
+            }
+        };
+        let no_argument = || match arg {
+            Some(_) => Err(error(MarkupErrorKind::UnexpectedArgument)),
+            None => Ok(()),
+        };
+
+        match name {
+            "flash" => {
+                no_argument()?;
+                if flash.is_some() {
+                    return Err(error(MarkupErrorKind::NestedTag));
+                }
+                flash = Some(pos);
+            }
+            "/flash" => {
+                no_argument()?;
+                if flash.is_none() {
+                    return Err(error(MarkupErrorKind::UnbalancedClose));
+                }
+                flash = None;
+            }
+            "udc" => {
+                let index = number(NUM_UDC as u32 - 1)?;
+                push_glyph(&mut markup, pos, hdsp::udc_char(index as u8), flash.is_some())?;
+            }
+            "ch" => {
+                let code = number(0x7F)?;
+                push_glyph(&mut markup, pos, code as u8, flash.is_some())?;
+            }
+            "b" => {
+                let level = number(MAX_BRIGHTNESS as u32)?;
+                push_event(&mut markup, pos, Event::Brightness(level as u8))?;
+            }
+            "p" => {
+                let ms = number(u16::MAX as u32)?;
+                push_event(&mut markup, pos, Event::Pause(ms as u16))?;
+            }
+            _ => return Err(error(MarkupErrorKind::UnknownTag)),
+        }
+
+        pos = end + 1;
+    }
+
+    if let Some(pos) = flash {
+        return Err(MarkupError {
+            pos,
+            kind: MarkupErrorKind::UnclosedTag,
+        });
+    }
+
+    Ok(markup)
+}
+
+fn push_glyph(markup: &mut Markup, pos: usize, code: u8, flash: bool) -> Result<(), MarkupError> {
+    markup.glyphs.push(Glyph { code, flash }).map_err(|_| MarkupError {
+        pos,
+        kind: MarkupErrorKind::TooManyGlyphs,
+    })
+}
+
+fn push_event(markup: &mut Markup, pos: usize, event: Event) -> Result<(), MarkupError> {
+    let anchor = markup.glyphs.len();
+    markup.events.push(AnchoredEvent { anchor, event }).map_err(|_| MarkupError {
+        pos,
+        kind: MarkupErrorKind::TooManyEvents,
+    })
+}
+
+#[test]
+fn test_markup() {
+    let markup = parse("Hola {flash}mundo{/flash} {udc:3} {b:2}{{").unwrap();
+
+    let codes: heapless::Vec<u8, 32> = markup.glyphs.iter().map(|g| g.code).collect();
+    assert_eq!(&codes[..], b"Hola mundo \x83 {");
+    assert!(!markup.glyphs[4].flash);
+    assert!(markup.glyphs[5].flash);
+    assert!(markup.glyphs[9].flash);
+    assert!(!markup.glyphs[10].flash);
+    assert_eq!(
+        &markup.events[..],
+        &[AnchoredEvent {
+            anchor: 13,
+            event: Event::Brightness(2)
+        }]
+    );
+
+    let error = |pos, kind| Err(MarkupError { pos, kind });
+    assert_eq!(parse("ab{flash"), error(2, MarkupErrorKind::UnterminatedTag));
+    assert_eq!(parse("{bold}"), error(0, MarkupErrorKind::UnknownTag));
+    assert_eq!(parse("x{udc}"), error(1, MarkupErrorKind::MissingArgument));
+    assert_eq!(parse("{udc:16}"), error(0, MarkupErrorKind::InvalidArgument));
+    assert_eq!(parse("{b:x}"), error(0, MarkupErrorKind::InvalidArgument));
+    assert_eq!(parse("{flash:1}"), error(0, MarkupErrorKind::UnexpectedArgument));
+    assert_eq!(parse("{flash}{flash}"), error(7, MarkupErrorKind::NestedTag));
+    assert_eq!(parse("a{/flash}"), error(1, MarkupErrorKind::UnbalancedClose));
+    assert_eq!(parse("a {flash}b"), error(2, MarkupErrorKind::UnclosedTag));
+    assert_eq!(parse("año"), error(1, MarkupErrorKind::NonAscii));
+}
@@ -0,0 +1,598 @@
+//! Every message of the protocol as one type, shared by the firmware and the
+//! hosts.
+//!
+//! Messages travel in `CMD_MESSAGE` packets, encoded with postcard, so adding
+//! a field to a message cannot put the two sides out of sync. The older
+//! commands with hand packed payloads are still understood, `from_command`
+//! decodes them into the same messages. `Message::Raw` sends a packet as is,
+//! for the legacy `CMD_SCREEN_BUFFER`, the `CMD_AUTH` wrapping of
+//! `auth::Signer` and anything else the schema does not cover.
+//!
+//! Postcard encodes the variants by position: new variants go at the end, and
+//! existing ones never change.
+
+use serde::{Deserialize, Serialize};
+
+use crate::anim::{AnimFrame, Animation, MAX_ANIM_FRAMES};
+use crate::auth::Key;
+use crate::batch::BatchOp;
+use crate::diag::{self, DiagReport};
+use crate::display::{self, ScrollConfig, UdcDefinition};
+use crate::event::{self, Event};
+use crate::hdsp::{MAX_BRIGHTNESS, NUM_UDC, UDC_ROW_MASK};
+use crate::info::DeviceInfo;
+use crate::packet::{Command, Packet, PacketView, UnknownCommand, MAX_PACKET_PAYLOAD_SIZE, VENDOR_COMMANDS};
+use crate::query::{Query, State};
+use crate::reliable::{NackCode, Response};
+use crate::rsvp::{self, DisplayMode, RsvpConfig};
+use crate::schedule::{self, Schedule};
+use crate::sync::{self, TimeReply};
+use crate::time::MAX_EPOCH_SECS;
+use crate::transition::TransitionSpec;
+use crate::widget::{self, WidgetConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageError {
+    /// Opcode of no known command, nor of a vendor one
+    UnknownCommand(u8),
+    /// Payload not in the format of its command, postcard for `CMD_MESSAGE`
+    Decode,
+    /// Encoded fine, but with values out of range
+    Invalid,
+    /// Larger than the buffer or the packet it goes in
+    TooLarge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message<'a> {
+    /// Character RAM codes, see `display::text_to_packet`
+    SetText(&'a [u8]),
+    SetMarkup(&'a str),
+    SetTemplate(&'a str),
+    /// 0 being blank and 7 full brightness
+    SetBrightness(u8),
+    SetControl(u8),
+    DefineUdc(UdcDefinition),
+    SetFlash(u8),
+    ScrollConfig(ScrollConfig),
+    Clear,
+    Reset,
+    /// Seconds since the Unix epoch
+    SetTime(u64),
+    Counter { index: u8, op: u8, value: i32 },
+    SetTransition { spec: TransitionSpec, once: bool },
+    SetMode { mode: DisplayMode, config: RsvpConfig },
+    DiagStart { step_ms: u16, loops: u8 },
+    DiagStop,
+    SetSchedule(Schedule),
+    ScheduleOverride { brightness: u8, minutes: u16 },
+    WidgetConfig(WidgetConfig),
+    WidgetValue { id: u8, value: u16 },
+    WidgetRemove(u8),
+    AnimUpload {
+        id: u8,
+        udc: u8,
+        frames: heapless::Vec<AnimFrame, MAX_ANIM_FRAMES>,
+    },
+    AnimStart(u8),
+    AnimStop(u8),
+    AnimBind { id: u8, digits: u8 },
+    Hello,
+    Query(Query),
+
+    // From the device
+    Response(Response),
+    Info(DeviceInfo),
+    State(State),
+    DiagReport(DiagReport),
+
+    /// Packet sent as is, outside of `CMD_MESSAGE`
+    Raw { command: u8, payload: &'a [u8] },
+    /// Command in `VENDOR_COMMANDS`, sent as is like `Raw`
+    Vendor { command: u8, payload: &'a [u8] },
+    /// Key of the authenticated mode, accepted once, see `auth`
+    SetKey(Key),
+    /// Mask of the events the host wants, see `event`
+    Subscribe(u16),
+    /// Sent by the device on its own
+    Event(Event),
+    Batch(BatchOp),
+    /// Time of the host, sent back with the time of the device, see `sync`
+    TimeRequest(u64),
+    TimeReply(TimeReply),
+    /// Command applied at a time of the device
+    At {
+        device_us: u64,
+        command: u8,
+        payload: &'a [u8],
+    },
+}
+
+impl From<UnknownCommand> for MessageError {
+    fn from(UnknownCommand(command): UnknownCommand) -> Self {
+        MessageError::UnknownCommand(command)
+    }
+}
+
+impl<'a> Message<'a> {
+    /// Whether every value is in range. Messages decoded from packets always
+    /// are.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Message::SetBrightness(level) => *level <= MAX_BRIGHTNESS,
+            Message::ScheduleOverride { brightness, .. } => *brightness <= MAX_BRIGHTNESS,
+            Message::DefineUdc(udc) => {
+                (udc.index as usize) < NUM_UDC && udc.bitmap.iter().all(|row| row & !UDC_ROW_MASK == 0)
+            }
+            Message::ScrollConfig(scroll) => scroll.step_ms > 0,
+            Message::SetTime(secs) => *secs <= MAX_EPOCH_SECS,
+            Message::SetSchedule(schedule) => schedule.is_valid(),
+            Message::WidgetConfig(config) => config.is_valid(),
+            Message::AnimUpload { udc, frames, .. } => !frames.is_empty() && (*udc as usize) < NUM_UDC,
+            Message::Vendor { command, .. } => VENDOR_COMMANDS.contains(command),
+            _ => true,
+        }
+    }
+
+    /// Whether the message only reads the device, and is applied without
+    /// authentication
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Message::Hello | Message::Query(_) | Message::TimeRequest(_))
+    }
+
+    /// Postcard encoding of the message, the payload of `CMD_MESSAGE`
+    pub fn to_bytes<'b>(&self, buf: &'b mut [u8]) -> Result<&'b mut [u8], MessageError> {
+        postcard::to_slice(self, buf).map_err(|_| MessageError::TooLarge)
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, MessageError> {
+        let message: Message = postcard::from_bytes(bytes).map_err(|_| MessageError::Decode)?;
+        match message.is_valid() {
+            true => Ok(message),
+            false => Err(MessageError::Invalid),
+        }
+    }
+
+    /// `CMD_MESSAGE` packet, or the packet itself for `Raw` and `Vendor`.
+    /// Messages too large for a packet are sent with `fragment::fragments`
+    /// over the output of `to_bytes`.
+    pub fn to_packet(&self) -> Result<Packet, MessageError> {
+        let mut packet = Packet::new();
+
+        if let Message::Raw { command, payload } | Message::Vendor { command, payload } = self {
+            if !self.is_valid() {
+                return Err(MessageError::UnknownCommand(*command));
+            }
+            if payload.len() > MAX_PACKET_PAYLOAD_SIZE {
+                return Err(MessageError::TooLarge);
+            }
+            packet.set_command(*command);
+            packet.set_payload(payload);
+            return Ok(packet);
+        }
+
+        let mut buf = [0; MAX_PACKET_PAYLOAD_SIZE];
+        let bytes = self.to_bytes(&mut buf)?;
+        packet.set_command(Command::CMD_MESSAGE.into());
+        packet.set_payload(bytes);
+        Ok(packet)
+    }
+
+    /// Decodes a command, a `CMD_MESSAGE` or one of the older hand packed
+    /// ones, refusing values out of range in either. `CMD_SCREEN_BUFFER`,
+    /// `CMD_FRAGMENT` and `CMD_AUTH` are left as `Raw`.
+    pub fn from_command(command: u8, payload: &'a [u8]) -> Result<Self, MessageError> {
+        if VENDOR_COMMANDS.contains(&command) {
+            return Ok(Message::Vendor { command, payload });
+        }
+
+        let message = match Command::try_from(command)? {
+            Command::CMD_MESSAGE => return Message::from_bytes(payload),
+            command => Message::from_legacy(command, payload).ok_or(MessageError::Decode)?,
+        };
+        match message.is_valid() {
+            true => Ok(message),
+            false => Err(MessageError::Invalid),
+        }
+    }
+
+    fn from_legacy(command: Command, payload: &'a [u8]) -> Option<Self> {
+        let message = match command {
+            // Decoded by from_command
+            Command::CMD_MESSAGE => return None,
+            Command::CMD_SCREEN_BUFFER | Command::CMD_FRAGMENT | Command::CMD_AUTH => Message::Raw {
+                command: command.into(),
+                payload,
+            },
+            Command::CMD_SET_TEXT => Message::SetText(payload),
+            Command::CMD_SET_MARKUP => Message::SetMarkup(core::str::from_utf8(payload).ok()?),
+            Command::CMD_SET_TEMPLATE => Message::SetTemplate(core::str::from_utf8(payload).ok()?),
+            Command::CMD_SET_BRIGHTNESS => Message::SetBrightness(display::brightness_from_payload(payload)?),
+            Command::CMD_SET_CONTROL => match payload {
+                [control] => Message::SetControl(*control),
+                _ => return None,
+            },
+            Command::CMD_DEFINE_UDC => Message::DefineUdc(UdcDefinition::from_payload(payload)?),
+            Command::CMD_SET_FLASH => match payload {
+                [digits] => Message::SetFlash(*digits),
+                _ => return None,
+            },
+            Command::CMD_SCROLL_CONFIG => Message::ScrollConfig(ScrollConfig::from_payload(payload)?),
+            Command::CMD_CLEAR => match payload {
+                [] => Message::Clear,
+                _ => return None,
+            },
+            Command::CMD_RESET => match payload {
+                [] => Message::Reset,
+                _ => return None,
+            },
+            Command::CMD_SET_TIME => Message::SetTime(u64::from_le_bytes(payload.try_into().ok()?)),
+            Command::CMD_COUNTER => match payload {
+                [index, op, value @ ..] => Message::Counter {
+                    index: *index,
+                    op: *op,
+                    value: i32::from_le_bytes((*value).try_into().ok()?),
+                },
+                _ => return None,
+            },
+            Command::CMD_SET_TRANSITION => {
+                let (spec, once) = TransitionSpec::from_payload(payload)?;
+                Message::SetTransition { spec, once }
+            }
+            Command::CMD_SET_MODE => {
+                let (mode, config) = rsvp::mode_from_payload(payload)?;
+                Message::SetMode { mode, config }
+            }
+            Command::CMD_DIAG => match payload {
+                [diag::DIAG_STOP] => Message::DiagStop,
+                [diag::DIAG_START, lo, hi, loops] => Message::DiagStart {
+                    step_ms: u16::from_le_bytes([*lo, *hi]),
+                    loops: *loops,
+                },
+                _ => return None,
+            },
+            Command::CMD_SET_SCHEDULE => Message::SetSchedule(Schedule::from_bytes(payload)?),
+            Command::CMD_SCHEDULE_OVERRIDE => {
+                let (brightness, minutes) = schedule::override_from_payload(payload)?;
+                Message::ScheduleOverride { brightness, minutes }
+            }
+            Command::CMD_WIDGET_CONFIG => Message::WidgetConfig(WidgetConfig::from_payload(payload)?),
+            Command::CMD_WIDGET_VALUE => {
+                let (id, value) = widget::value_from_payload(payload)?;
+                Message::WidgetValue { id, value }
+            }
+            Command::CMD_WIDGET_REMOVE => match payload {
+                [id] => Message::WidgetRemove(*id),
+                _ => return None,
+            },
+            Command::CMD_ANIM_UPLOAD => {
+                let animation = Animation::from_payload(payload)?;
+                Message::AnimUpload {
+                    id: animation.id,
+                    udc: animation.udc,
+                    frames: heapless::Vec::from_slice(animation.frames()).ok()?,
+                }
+            }
+            Command::CMD_ANIM_START => match payload {
+                [id] => Message::AnimStart(*id),
+                _ => return None,
+            },
+            Command::CMD_ANIM_STOP => match payload {
+                [id] => Message::AnimStop(*id),
+                _ => return None,
+            },
+            Command::CMD_ANIM_BIND => match payload {
+                [id, digits] => Message::AnimBind { id: *id, digits: *digits },
+                _ => return None,
+            },
+            Command::CMD_HELLO => match payload {
+                [] => Message::Hello,
+                _ => return None,
+            },
+            Command::CMD_QUERY => Message::Query(Query::from_payload(payload)?),
+            Command::CMD_ACK => match payload {
+                [seq] => Message::Response(Response { seq: *seq, result: Ok(()) }),
+                _ => return None,
+            },
+            Command::CMD_NACK => match payload {
+                [seq, code] => Message::Response(Response {
+                    seq: *seq,
+                    result: Err(NackCode::from_u8(*code)?),
+                }),
+                _ => return None,
+            },
+            Command::CMD_INFO => Message::Info(DeviceInfo::from_payload(payload)?),
+            Command::CMD_STATE => Message::State(State::from_payload(payload)?),
+            Command::CMD_DIAG_REPORT => Message::DiagReport(DiagReport::from_payload(payload)?),
+            Command::CMD_SET_KEY => Message::SetKey(payload.try_into().ok()?),
+            Command::CMD_SUBSCRIBE => Message::Subscribe(event::subscribe_from_payload(payload)?),
+            Command::CMD_EVENT => Message::Event(Event::from_payload(payload)?),
+            Command::CMD_BATCH => Message::Batch(BatchOp::from_payload(payload)?),
+            Command::CMD_TIME_REQUEST => Message::TimeRequest(sync::time_request_from_payload(payload)?),
+            Command::CMD_TIME_REPLY => Message::TimeReply(TimeReply::from_payload(payload)?),
+            Command::CMD_AT => {
+                let (device_us, command, payload) = sync::at_from_payload(payload)?;
+                Message::At {
+                    device_us,
+                    command,
+                    payload,
+                }
+            }
+        };
+        Some(message)
+    }
+}
+
+impl<'a> TryFrom<&'a Packet> for Message<'a> {
+    type Error = MessageError;
+
+    fn try_from(packet: &'a Packet) -> Result<Self, Self::Error> {
+        Message::from_command(packet.command(), packet.get_payload())
+    }
+}
+
+impl<'a> TryFrom<PacketView<'a>> for Message<'a> {
+    type Error = MessageError;
+
+    fn try_from(packet: PacketView<'a>) -> Result<Self, Self::Error> {
+        Message::from_command(packet.command(), packet.payload())
+    }
+}
+
+#[test]
+fn test_messages() {
+    use crate::anim;
+    use crate::auth;
+    use crate::diag::{DiagResult, Pattern};
+    use crate::info::{self, DisplayModel};
+    use crate::schedule::{Window, WEEKDAYS};
+    use crate::transition::{Easing, TransitionKind};
+
+    let mut schedule = Schedule::new();
+    schedule.windows.push(Window::new(WEEKDAYS, 8 * 60, 19 * 60, 7)).unwrap();
+    let mut frames = heapless::Vec::new();
+    frames.push(AnimFrame { bitmap: [0x1F; 7], duration_ms: 100 }).unwrap();
+    frames.push(AnimFrame { bitmap: [0x04; 7], duration_ms: 250 }).unwrap();
+    let udc = UdcDefinition {
+        index: 2,
+        bitmap: [0x1F, 0, 0, 0, 0, 0, 0x1F],
+    };
+    let spec = TransitionSpec {
+        kind: TransitionKind::Roll,
+        easing: Easing::EaseOut,
+        duration_ms: 300,
+    };
+    let scroll = ScrollConfig {
+        step_ms: 120,
+        end_pause_ms: 900,
+    };
+    let widget = WidgetConfig::new(1, widget::WidgetKind::Bar, 0, 8, 0, 100).with_label(b"CPU");
+    let response = Response {
+        seq: 3,
+        result: Err(NackCode::Failed),
+    };
+    let info = DeviceInfo {
+        protocol_version: info::PROTOCOL_VERSION,
+        firmware_version: heapless::String::try_from("0.1.0").unwrap(),
+        unique_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        display_model: DisplayModel::Hdsp253x,
+        modules: 1,
+        digits: 8,
+        udcs: 16,
+        capabilities: info::CAP_CRC | info::CAP_QUERY,
+    };
+    let report = DiagReport {
+        pattern: Pattern::Checkerboard,
+        result: DiagResult::NotChecked,
+    };
+    let time_reply = TimeReply {
+        host_us: 1_000_000,
+        device_us: 5_000_400,
+    };
+
+    let messages = [
+        Message::SetText(b"HELLO"),
+        Message::SetMarkup("{b3}Hi"),
+        Message::SetTemplate("{time}"),
+        Message::SetBrightness(5),
+        Message::SetControl(0x13),
+        Message::DefineUdc(udc),
+        Message::SetFlash(0x81),
+        Message::ScrollConfig(scroll),
+        Message::Clear,
+        Message::Reset,
+        Message::SetTime(1_700_000_000),
+        Message::Counter { index: 1, op: 2, value: -5 },
+        Message::SetTransition { spec, once: true },
+        Message::SetMode {
+            mode: DisplayMode::Rsvp,
+            config: RsvpConfig::DEFAULT,
+        },
+        Message::DiagStart { step_ms: 300, loops: 2 },
+        Message::DiagStop,
+        Message::SetSchedule(schedule.clone()),
+        Message::ScheduleOverride { brightness: 2, minutes: 30 },
+        Message::WidgetConfig(widget),
+        Message::WidgetValue { id: 2, value: 40 },
+        Message::WidgetRemove(2),
+        Message::AnimUpload {
+            id: 1,
+            udc: 4,
+            frames: frames.clone(),
+        },
+        Message::AnimStart(1),
+        Message::AnimStop(1),
+        Message::AnimBind { id: 1, digits: 0x0F },
+        Message::Hello,
+        Message::Query(Query::Flash),
+        Message::Response(response),
+        Message::Info(info.clone()),
+        Message::State(State::Flash(0x81)),
+        Message::DiagReport(report),
+        Message::Raw {
+            command: Command::CMD_SCREEN_BUFFER.into(),
+            payload: &[1, 2, 3],
+        },
+        Message::Vendor {
+            command: 0x31,
+            payload: &[0x00, 0xAA],
+        },
+        Message::SetKey([0x5A; auth::KEY_SIZE]),
+        Message::Subscribe(event::ALL_EVENTS),
+        Message::Event(Event::Button { pressed: true }),
+        Message::Batch(BatchOp::Commit),
+        Message::TimeRequest(1_000_000),
+        Message::TimeReply(time_reply),
+        Message::At {
+            device_us: 5_000_000,
+            command: Command::CMD_SET_TEXT.into(),
+            payload: b"HELLO",
+        },
+    ];
+
+    // Index of each variant, this stops building when one is added until it
+    // is added to the messages above
+    let variant = |message: &Message| match message {
+        Message::SetText(_) => 0,
+        Message::SetMarkup(_) => 1,
+        Message::SetTemplate(_) => 2,
+        Message::SetBrightness(_) => 3,
+        Message::SetControl(_) => 4,
+        Message::DefineUdc(_) => 5,
+        Message::SetFlash(_) => 6,
+        Message::ScrollConfig(_) => 7,
+        Message::Clear => 8,
+        Message::Reset => 9,
+        Message::SetTime(_) => 10,
+        Message::Counter { .. } => 11,
+        Message::SetTransition { .. } => 12,
+        Message::SetMode { .. } => 13,
+        Message::DiagStart { .. } => 14,
+        Message::DiagStop => 15,
+        Message::SetSchedule(_) => 16,
+        Message::ScheduleOverride { .. } => 17,
+        Message::WidgetConfig(_) => 18,
+        Message::WidgetValue { .. } => 19,
+        Message::WidgetRemove(_) => 20,
+        Message::AnimUpload { .. } => 21,
+        Message::AnimStart(_) => 22,
+        Message::AnimStop(_) => 23,
+        Message::AnimBind { .. } => 24,
+        Message::Hello => 25,
+        Message::Query(_) => 26,
+        Message::Response(_) => 27,
+        Message::Info(_) => 28,
+        Message::State(_) => 29,
+        Message::DiagReport(_) => 30,
+        Message::Raw { .. } => 31,
+        Message::Vendor { .. } => 32,
+        Message::SetKey(_) => 33,
+        Message::Subscribe(_) => 34,
+        Message::Event(_) => 35,
+        Message::Batch(_) => 36,
+        Message::TimeRequest(_) => 37,
+        Message::TimeReply(_) => 38,
+        Message::At { .. } => 39,
+    };
+    for (i, message) in messages.iter().enumerate() {
+        assert_eq!(variant(message), i);
+    }
+
+    for message in &messages {
+        assert!(message.is_valid());
+        let packet = message.to_packet().unwrap();
+        match message {
+            Message::Raw { command, .. } | Message::Vendor { command, .. } => assert_eq!(packet.command(), *command),
+            _ => assert_eq!(Command::try_from(packet.command()), Ok(Command::CMD_MESSAGE)),
+        }
+        assert_eq!(Message::try_from(&packet).as_ref(), Ok(message));
+        assert_eq!(Message::try_from(packet.view()).as_ref(), Ok(message));
+    }
+
+    // Older packets decode into the same messages
+    let animation = Animation::from_frames(1, 4, &frames).unwrap();
+    let legacy = [
+        (display::text_to_packet(b"HELLO"), Message::SetText(b"HELLO")),
+        (display::brightness_to_packet(5), Message::SetBrightness(5)),
+        (display::control_to_packet(0x13), Message::SetControl(0x13)),
+        (udc.to_packet(), Message::DefineUdc(udc)),
+        (display::flash_to_packet(0x81), Message::SetFlash(0x81)),
+        (scroll.to_packet(), Message::ScrollConfig(scroll)),
+        (display::clear_to_packet(), Message::Clear),
+        (display::reset_to_packet(), Message::Reset),
+        (spec.to_packet(false), Message::SetTransition { spec, once: false }),
+        (
+            rsvp::mode_to_packet(DisplayMode::Scroll, &RsvpConfig::DEFAULT),
+            Message::SetMode {
+                mode: DisplayMode::Scroll,
+                config: RsvpConfig::DEFAULT,
+            },
+        ),
+        (diag::start_to_packet(300, 2), Message::DiagStart { step_ms: 300, loops: 2 }),
+        (diag::stop_to_packet(), Message::DiagStop),
+        (schedule.to_packet(), Message::SetSchedule(schedule.clone())),
+        (
+            schedule::override_to_packet(2, 30),
+            Message::ScheduleOverride { brightness: 2, minutes: 30 },
+        ),
+        (widget.to_packet(), Message::WidgetConfig(widget)),
+        (widget::value_to_packet(2, 40), Message::WidgetValue { id: 2, value: 40 }),
+        (widget::remove_to_packet(2), Message::WidgetRemove(2)),
+        (
+            animation.to_packet(),
+            Message::AnimUpload { id: 1, udc: 4, frames },
+        ),
+        (anim::start_to_packet(1), Message::AnimStart(1)),
+        (anim::stop_to_packet(1), Message::AnimStop(1)),
+        (anim::bind_to_packet(1, 0x0F), Message::AnimBind { id: 1, digits: 0x0F }),
+        (info::hello_to_packet(), Message::Hello),
+        (Query::Flash.to_packet(), Message::Query(Query::Flash)),
+        (response.to_packet(), Message::Response(response)),
+        (info.to_packet(), Message::Info(info)),
+        (State::Flash(0x81).to_packet(), Message::State(State::Flash(0x81))),
+        (report.to_packet(), Message::DiagReport(report)),
+        (auth::key_to_packet(&[0x5A; auth::KEY_SIZE]), Message::SetKey([0x5A; auth::KEY_SIZE])),
+        (event::subscribe_to_packet(event::ALL_EVENTS), Message::Subscribe(event::ALL_EVENTS)),
+        (Event::RxOverflow(2).to_packet(), Message::Event(Event::RxOverflow(2))),
+        (BatchOp::Begin.to_packet(), Message::Batch(BatchOp::Begin)),
+        (sync::time_request_to_packet(1_000_000), Message::TimeRequest(1_000_000)),
+        (time_reply.to_packet(), Message::TimeReply(time_reply)),
+        (
+            sync::at_to_packet(5_000_000, Command::CMD_CLEAR.into(), &[]).unwrap(),
+            Message::At {
+                device_us: 5_000_000,
+                command: Command::CMD_CLEAR.into(),
+                payload: &[],
+            },
+        ),
+    ];
+    for (packet, message) in &legacy {
+        assert_eq!(Message::try_from(packet).as_ref(), Ok(message));
+    }
+
+    // Unknown opcodes are named, vendor ones are kept for the application
+    assert_eq!(Message::from_command(0x2F, &[]), Err(MessageError::UnknownCommand(0x2F)));
+    assert_eq!(Message::from_command(0x00, &[]), Err(MessageError::UnknownCommand(0x00)));
+    assert_eq!(Message::from_command(0x3F, &[1]), Ok(Message::Vendor { command: 0x3F, payload: &[1] }));
+    let not_vendor = Message::Vendor {
+        command: Command::CMD_SET_TEXT.into(),
+        payload: &[],
+    };
+    assert_eq!(not_vendor.to_packet().unwrap_err(), MessageError::UnknownCommand(0x14));
+    assert_eq!(Message::from_command(Command::CMD_SET_BRIGHTNESS.into(), &[1, 2]), Err(MessageError::Decode));
+    assert_eq!(Message::from_command(Command::CMD_SET_KEY.into(), &[1; 16]), Err(MessageError::Decode));
+    // Only what changes nothing is applied unauthenticated
+    assert!(Message::Query(Query::Auth).is_read_only());
+    assert!(!Message::SetKey([0x5A; auth::KEY_SIZE]).is_read_only());
+
+    // Out of range values are refused even when well encoded
+    let mut buf = [0; 16];
+    let bytes = Message::SetBrightness(9).to_bytes(&mut buf).unwrap();
+    assert_eq!(Message::from_bytes(bytes), Err(MessageError::Invalid));
+    let bytes = Message::SetTime(u64::MAX).to_bytes(&mut buf).unwrap();
+    assert_eq!(Message::from_bytes(bytes), Err(MessageError::Invalid));
+    // Older packets as well
+    let time = u64::MAX.to_le_bytes();
+    assert_eq!(Message::from_command(Command::CMD_SET_TIME.into(), &time), Err(MessageError::Invalid));
+    assert_eq!(Message::from_bytes(&[0xFF, 0xFF]), Err(MessageError::Decode));
+}
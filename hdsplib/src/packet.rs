@@ -1,14 +1,124 @@
 #![allow(dead_code)]
 
+use core::ops::RangeInclusive;
+
 /// Maximum size of a packet
 pub const MAX_PACKET_SIZE: usize = 256;
 /// Maximum size of the payload inside a packet
 pub const MAX_PACKET_PAYLOAD_SIZE: usize = MAX_PACKET_SIZE - 1;
+/// Size of the CRC trailer
+pub const CRC_SIZE: usize = 2;
+/// Maximum size of a decoded frame, a packet followed by its CRC
+pub const MAX_FRAME_SIZE: usize = MAX_PACKET_SIZE + CRC_SIZE;
+/// Maximum size of a COBS encoded frame, delimiter included
+pub const MAX_ENCODED_SIZE: usize = MAX_FRAME_SIZE + 4;
+
+/// Protocol version bit, set in the command byte of the frames ending with a
+/// CRC. Frames without it are the original format and are still accepted, so
+/// hosts which do not know about the CRC keep working, until a frame with a
+/// CRC comes, see `FrameDecoder`.
+pub const CMD_FLAG_CRC: u8 = 0x80;
+/// Set in the command byte of the packets whose payload starts with a
+/// sequence number. The device answers them with `CMD_ACK` or `CMD_NACK`.
+pub const CMD_FLAG_SEQ: u8 = 0x40;
+/// Bits of the command byte giving the command itself
+pub const CMD_MASK: u8 = !(CMD_FLAG_CRC | CMD_FLAG_SEQ);
+
+/// Why a COBS frame could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CobsError {
+    /// No data before the delimiter, or no delimiter at all
+    Empty,
+    /// A code byte is zero
+    ZeroCode,
+    /// The frame ends before the block announced by a code byte
+    Truncated,
+    /// Decodes to more than a frame can hold
+    Overflow,
+    /// The last byte is not the 0x00 delimiter
+    MissingDelimiter,
+}
+
+/// Why a received frame was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Not valid COBS, or too short or too long to be a packet
+    Framing,
+    /// Well formed, but the CRC does not match the content, or is missing
+    /// from a host which sent one before
+    Crc,
+    /// Longer than any frame, dropped up to its delimiter
+    Oversize,
+}
+
+/// Decoded packet and whether its frame had a CRC
+pub type FrameResult = Result<(Packet, bool), FrameError>;
 
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xFFFF, data)
+}
+
+/// Continues a CRC-16 over more data, for frames whose parts are not
+/// contiguous
+fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
-    CMD_INVALID = 0x00,
     CMD_SCREEN_BUFFER = 0x01,
     CMD_ACK = 0x02,
+    CMD_WIDGET_CONFIG = 0x03,
+    CMD_WIDGET_VALUE = 0x04,
+    CMD_WIDGET_REMOVE = 0x05,
+    CMD_ANIM_UPLOAD = 0x06,
+    CMD_ANIM_START = 0x07,
+    CMD_ANIM_STOP = 0x08,
+    CMD_ANIM_BIND = 0x09,
+    CMD_SET_MARKUP = 0x0A,
+    CMD_SET_TEMPLATE = 0x0B,
+    CMD_SET_TIME = 0x0C,
+    CMD_COUNTER = 0x0D,
+    CMD_SET_TRANSITION = 0x0E,
+    CMD_SET_MODE = 0x0F,
+    CMD_DIAG = 0x10,
+    CMD_DIAG_REPORT = 0x11,
+    CMD_SET_SCHEDULE = 0x12,
+    CMD_SCHEDULE_OVERRIDE = 0x13,
+    CMD_SET_TEXT = 0x14,
+    CMD_SET_BRIGHTNESS = 0x15,
+    CMD_SET_CONTROL = 0x16,
+    CMD_DEFINE_UDC = 0x17,
+    CMD_SET_FLASH = 0x18,
+    CMD_SCROLL_CONFIG = 0x19,
+    CMD_CLEAR = 0x1A,
+    CMD_RESET = 0x1B,
+    CMD_NACK = 0x1C,
+    CMD_FRAGMENT = 0x1D,
+    CMD_HELLO = 0x1E,
+    CMD_INFO = 0x1F,
+    CMD_QUERY = 0x20,
+    CMD_STATE = 0x21,
+    CMD_MESSAGE = 0x22,
+    CMD_AUTH = 0x23,
+    CMD_SET_KEY = 0x24,
+    CMD_EVENT = 0x25,
+    CMD_SUBSCRIBE = 0x26,
+    CMD_BATCH = 0x27,
+    CMD_TIME_REQUEST = 0x28,
+    CMD_TIME_REPLY = 0x29,
+    CMD_AT = 0x2A,
 }
 
 impl From<Command> for u8 {
@@ -17,97 +127,175 @@ impl From<Command> for u8 {
     }
 }
 
-impl From<u8> for Command {
-    fn from(command: u8) -> Self {
-        match command {
+/// Opcodes kept for vendor and experimental commands, no command of the
+/// protocol will ever use them
+pub const VENDOR_COMMANDS: RangeInclusive<u8> = 0x30..=0x3F;
+
+/// Opcode of no known command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownCommand(pub u8);
+
+impl TryFrom<u8> for Command {
+    type Error = UnknownCommand;
+
+    fn try_from(command: u8) -> Result<Self, Self::Error> {
+        Ok(match command {
             0x01 => Command::CMD_SCREEN_BUFFER,
             0x02 => Command::CMD_ACK,
-            _ => Command::CMD_INVALID,
-        }
+            0x03 => Command::CMD_WIDGET_CONFIG,
+            0x04 => Command::CMD_WIDGET_VALUE,
+            0x05 => Command::CMD_WIDGET_REMOVE,
+            0x06 => Command::CMD_ANIM_UPLOAD,
+            0x07 => Command::CMD_ANIM_START,
+            0x08 => Command::CMD_ANIM_STOP,
+            0x09 => Command::CMD_ANIM_BIND,
+            0x0A => Command::CMD_SET_MARKUP,
+            0x0B => Command::CMD_SET_TEMPLATE,
+            0x0C => Command::CMD_SET_TIME,
+            0x0D => Command::CMD_COUNTER,
+            0x0E => Command::CMD_SET_TRANSITION,
+            0x0F => Command::CMD_SET_MODE,
+            0x10 => Command::CMD_DIAG,
+            0x11 => Command::CMD_DIAG_REPORT,
+            0x12 => Command::CMD_SET_SCHEDULE,
+            0x13 => Command::CMD_SCHEDULE_OVERRIDE,
+            0x14 => Command::CMD_SET_TEXT,
+            0x15 => Command::CMD_SET_BRIGHTNESS,
+            0x16 => Command::CMD_SET_CONTROL,
+            0x17 => Command::CMD_DEFINE_UDC,
+            0x18 => Command::CMD_SET_FLASH,
+            0x19 => Command::CMD_SCROLL_CONFIG,
+            0x1A => Command::CMD_CLEAR,
+            0x1B => Command::CMD_RESET,
+            0x1C => Command::CMD_NACK,
+            0x1D => Command::CMD_FRAGMENT,
+            0x1E => Command::CMD_HELLO,
+            0x1F => Command::CMD_INFO,
+            0x20 => Command::CMD_QUERY,
+            0x21 => Command::CMD_STATE,
+            0x22 => Command::CMD_MESSAGE,
+            0x23 => Command::CMD_AUTH,
+            0x24 => Command::CMD_SET_KEY,
+            0x25 => Command::CMD_EVENT,
+            0x26 => Command::CMD_SUBSCRIBE,
+            0x27 => Command::CMD_BATCH,
+            0x28 => Command::CMD_TIME_REQUEST,
+            0x29 => Command::CMD_TIME_REPLY,
+            0x2A => Command::CMD_AT,
+            _ => return Err(UnknownCommand(command)),
+        })
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Packet {
-    pub data: [u8; MAX_PACKET_SIZE],
+    /// Room is left for the CRC of a frame
+    pub data: [u8; MAX_FRAME_SIZE],
     pub size: usize,
 }
 
 impl Packet  {
     pub fn new() -> Self {
         Self {
-            data: [0; MAX_PACKET_SIZE],
+            data: [0; MAX_FRAME_SIZE],
             size: 0,
         }
     }
 
+    /// Command, without the flags
     pub fn command(&self) -> u8 {
-        self.data[0]
+        self.data[0] & CMD_MASK
     }
     
+    /// Sets the command, keeping the sequence number if there is one
     pub fn set_command(&mut self, command: u8) {
-        self.data[0] = command;
+        self.data[0] = (self.data[0] & CMD_FLAG_SEQ) | (command & CMD_MASK);
+    }
+
+    /// Sequence number of the packet, if the host wants it acknowledged
+    pub fn seq(&self) -> Option<u8> {
+        match self.data[0] & CMD_FLAG_SEQ {
+            0 => None,
+            _ => Some(self.data[1]),
+        }
+    }
+
+    /// Numbers the packet, moving the payload to make room for the sequence
+    /// number if it had none. A full payload leaves no room for it.
+    pub fn set_seq(&mut self, seq: u8) {
+        if self.seq().is_none() {
+            let size = self.size.max(1);
+            if size >= MAX_PACKET_SIZE {
+                panic!("Payload size exceeds maximum packet size");
+            }
+            self.data.copy_within(1..size, 2);
+            self.size = size + 1;
+            self.data[0] |= CMD_FLAG_SEQ;
+        }
+        self.data[1] = seq;
+    }
+
+    fn payload_start(&self) -> usize {
+        match self.seq() {
+            Some(_) => 2,
+            None => 1,
+        }
     }
 
     pub fn get_payload(&self) -> &[u8] {
-        &self.data[1..self.size]
+        &self.data[self.payload_start()..self.size]
     }
     
+    /// Sets the payload, after the sequence number if there is one
     pub fn set_payload(&mut self, payload: &[u8]) {
+        let start = self.payload_start();
         let size = payload.len();
-        if size > MAX_PACKET_SIZE - 1 {
+        if size > MAX_PACKET_SIZE - start {
             panic!("Payload size exceeds maximum packet size");
         }
-        self.size = size + 1;
-        self.data[1..self.size].copy_from_slice(payload);
+        self.size = size + start;
+        self.data[start..self.size].copy_from_slice(payload);
     }
 
-    // Decode COBS (http://www.stuartcheshire.org/papers/cobsforton.pdf)
-    pub fn from_cobs(encoded_data: &[u8]) -> Result<Self, ()> {
-        // let mut packet = Packet::new(data.len());
-        let mut packet = Packet::new();
-        
-        let decoded_data = packet.data_mut();
-        let mut encoded_ptr = 1;
-        let mut next_idx = encoded_data[0] as usize;
-        let mut block_size = encoded_data[0] as usize - 1;
-        let mut decoded_ptr = 0;
-
-
-        while encoded_ptr < encoded_data.len() - 1 {
-            let mut value = encoded_data[encoded_ptr];
-
-            if encoded_ptr == next_idx {
-                let offset = value as usize;
-                next_idx += offset as usize;
-                
+    /// Decodes a COBS frame (http://www.stuartcheshire.org/papers/cobsforton.pdf),
+    /// delimiter included. Any input gives a packet or an error, never a panic.
+    pub fn from_cobs(encoded_data: &[u8]) -> Result<Self, CobsError> {
+        let (&delimiter, body) = encoded_data.split_last().ok_or(CobsError::Empty)?;
+        if delimiter != 0x00 {
+            return Err(CobsError::MissingDelimiter);
+        }
+        if body.is_empty() {
+            return Err(CobsError::Empty);
+        }
 
-                if block_size < 0xFE {
-                    value = 0x00;
-                    block_size = offset - 1;
-                } else { // It is another pointer
-                    encoded_ptr += 1;
-                    block_size = offset - 1;
-                    continue;
-                }
-
-                if next_idx >= encoded_data.len() {
-                    return Err(());
-                }
+        let mut packet = Packet::new();
+        let mut pos = 0;
+        while pos < body.len() {
+            let code = body[pos];
+            if code == 0x00 {
+                return Err(CobsError::ZeroCode);
             }
 
-            if decoded_ptr >= decoded_data.len() {
-                return Err(());
+            let end = pos + code as usize;
+            let block = body.get(pos + 1..end).ok_or(CobsError::Truncated)?;
+            // A delimiter in the middle of a block cut it short
+            if block.contains(&0x00) {
+                return Err(CobsError::Truncated);
             }
-            decoded_data[decoded_ptr] = value;
-            
-            decoded_ptr += 1;
-            encoded_ptr += 1;
 
+            let zero = code < 0xFF && end < body.len();
+            let size = packet.size + block.len() + zero as usize;
+            if size > packet.data.len() {
+                return Err(CobsError::Overflow);
+            }
+            packet.data[packet.size..packet.size + block.len()].copy_from_slice(block);
+            packet.size = size;
+            if zero {
+                packet.data[size - 1] = 0x00;
+            }
+            pos = end;
         }
 
-        packet.size = decoded_ptr;
-
         Ok(packet)
     }
     
@@ -115,8 +303,8 @@ impl Packet  {
         Encode COBS (http://www.stuartcheshire.org/papers/cobsforton.pdf)
         Returns a tuple of the encoded data and the size of the encoded data
      */
-    pub fn to_cobs_slice(&self) -> ([u8; MAX_PACKET_SIZE+4], usize) {
-        let mut encoded_data = [0; MAX_PACKET_SIZE+4];
+    pub fn to_cobs_slice(&self) -> ([u8; MAX_ENCODED_SIZE], usize) {
+        let mut encoded_data = [0; MAX_ENCODED_SIZE];
         let mut encoded_ptr = 1usize;
         
         let mut decoded_ptr = 0usize;
@@ -171,6 +359,56 @@ impl Packet  {
         (encoded_data, encoded_ptr)
     }
 
+    /// Decodes a COBS frame, checking and removing the CRC when the frame has
+    /// one. The flag tells whether it did, replies should use the same format.
+    /// A frame on its own is accepted without a CRC, `FrameDecoder` refuses
+    /// it once the host sent one.
+    pub fn from_frame(encoded_data: &[u8]) -> FrameResult {
+        let mut packet = Packet::from_cobs(encoded_data).map_err(|e| match e {
+            CobsError::Overflow => FrameError::Oversize,
+            _ => FrameError::Framing,
+        })?;
+        let crc = packet.check_frame(false)?;
+        Ok((packet, crc))
+    }
+
+    /// Checks and removes the CRC of a decoded frame, if it has one, see
+    /// `check_crc`. Returns whether it had one.
+    fn check_frame(&mut self, crc_required: bool) -> Result<bool, FrameError> {
+        let (size, crc) = check_crc(&mut self.data[..self.size], crc_required)?;
+        self.size = size;
+
+        // Room for the sequence number, and no more than a packet
+        if !(self.payload_start()..=MAX_PACKET_SIZE).contains(&self.size) {
+            return Err(FrameError::Framing);
+        }
+        Ok(crc)
+    }
+
+    /// Encodes the packet as a COBS frame, with a CRC unless `crc` is false
+    /// for hosts speaking the original format
+    pub fn to_frame(&self, crc: bool) -> ([u8; MAX_ENCODED_SIZE], usize) {
+        if !crc {
+            return self.to_cobs_slice();
+        }
+
+        let mut frame = *self;
+        frame.data[0] |= CMD_FLAG_CRC;
+        let crc = crc16(&frame.data[..frame.size]).to_le_bytes();
+        frame.data[frame.size..frame.size + CRC_SIZE].copy_from_slice(&crc);
+        frame.size += CRC_SIZE;
+        frame.to_cobs_slice()
+    }
+
+    /// The packet borrowed as a `PacketView`
+    pub fn view(&self) -> PacketView<'_> {
+        PacketView {
+            command: self.command(),
+            seq: self.seq(),
+            payload: self.get_payload(),
+        }
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.data
     }
@@ -180,6 +418,381 @@ impl Packet  {
     }
 }
 
+/// Decodes COBS frames as their bytes come in, without keeping the encoded
+/// frame around.
+///
+/// Garbage, bad frames and frames too long for a packet are reported once
+/// their delimiter comes, decoding starts over right after it.
+///
+/// Once a frame came with a CRC, the frames without one are refused with
+/// `FrameError::Crc`: the host sends them all with a CRC, and a corruption
+/// clearing `CMD_FLAG_CRC` would otherwise pass for the original format.
+pub struct FrameDecoder {
+    packet: Packet,
+    /// Bytes of the current block still to come, the next byte is a code
+    /// byte when 0
+    remaining: u8,
+    /// Whether the current block ends with a zero, which is only written if
+    /// another block follows
+    zero_pending: bool,
+    /// Whether any byte of the current frame came
+    started: bool,
+    overflow: bool,
+    crc_required: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            packet: Packet::new(),
+            remaining: 0,
+            zero_pending: false,
+            started: false,
+            overflow: false,
+            crc_required: false,
+        }
+    }
+
+    fn put(&mut self, byte: u8) {
+        if self.packet.size == self.packet.data.len() {
+            self.overflow = true;
+            return;
+        }
+        self.packet.data[self.packet.size] = byte;
+        self.packet.size += 1;
+    }
+
+    /// Feeds one byte. Returns the packet, and whether it had a CRC, when the
+    /// byte ends a frame.
+    pub fn push(&mut self, byte: u8) -> Option<FrameResult> {
+        let result = self.push_in_place(byte)?;
+        Some(result.map(|crc| (self.packet, crc)))
+    }
+
+    /// Feeds one byte like `push`, without copying the packet out: `packet`
+    /// borrows it until the next byte. Returns whether it had a CRC when the
+    /// byte ends a frame.
+    pub fn push_in_place(&mut self, byte: u8) -> Option<Result<bool, FrameError>> {
+        if byte == 0x00 {
+            // Back to back delimiters carry no packet
+            if !self.started {
+                return None;
+            }
+
+            let result = match (self.overflow, self.remaining) {
+                (true, _) => Err(FrameError::Oversize),
+                (false, 0) => self.packet.check_frame(self.crc_required),
+                (false, _) => Err(FrameError::Framing),
+            };
+            if result == Ok(true) {
+                self.crc_required = true;
+            }
+            self.reset();
+            return Some(result);
+        }
+
+        // The last packet is kept until the next frame starts
+        if !self.started {
+            self.started = true;
+            self.packet.size = 0;
+        }
+        if self.overflow {
+            return None;
+        }
+
+        if self.remaining == 0 {
+            if self.zero_pending {
+                self.put(0x00);
+            }
+            self.remaining = byte - 1;
+            self.zero_pending = byte < 0xFF;
+        } else {
+            self.put(byte);
+            self.remaining -= 1;
+        }
+        None
+    }
+
+    /// Feeds bytes until one ends a frame. Returns the number of bytes used,
+    /// and the packet if a frame ended.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> (usize, Option<FrameResult>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if let Some(result) = self.push(byte) {
+                return (i + 1, Some(result));
+            }
+        }
+        (bytes.len(), None)
+    }
+
+    /// Packet of the last frame `push_in_place` decoded
+    pub fn packet(&self) -> PacketView<'_> {
+        self.packet.view()
+    }
+
+    /// Drops the frame being decoded
+    pub fn reset(&mut self) {
+        self.remaining = 0;
+        self.zero_pending = false;
+        self.started = false;
+        self.overflow = false;
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks and removes the CRC at the end of a decoded frame, if it has one,
+/// it must with `crc_required`. Returns the size left and whether there was
+/// one.
+fn check_crc(frame: &mut [u8], crc_required: bool) -> Result<(usize, bool), FrameError> {
+    let Some(&command) = frame.first() else {
+        return Err(FrameError::Framing);
+    };
+    match (command & CMD_FLAG_CRC != 0, crc_required) {
+        (true, _) => {}
+        (false, true) => return Err(FrameError::Crc),
+        (false, false) => return Ok((frame.len(), false)),
+    }
+
+    let size = match frame.len().checked_sub(CRC_SIZE) {
+        Some(size) if size > 0 => size,
+        _ => return Err(FrameError::Framing),
+    };
+    let received = u16::from_le_bytes([frame[size], frame[size + 1]]);
+    if crc16(&frame[..size]) != received {
+        return Err(FrameError::Crc);
+    }
+    frame[0] &= !CMD_FLAG_CRC;
+    Ok((size, true))
+}
+
+/// COBS encoding of `parts` one after the other, delimiter included, handed
+/// to `write` in pieces. Nothing is buffered, a block is looked through for
+/// its length before it is written.
+fn cobs_encode<E>(parts: &[&[u8]], mut write: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let byte_at = |mut i: usize| {
+        for part in parts {
+            if i < part.len() {
+                return part[i];
+            }
+            i -= part.len();
+        }
+        0x00
+    };
+
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        while end < len && end - start < 0xFE && byte_at(end) != 0x00 {
+            end += 1;
+        }
+
+        write(&[(end - start + 1) as u8])?;
+        let mut offset = 0;
+        for part in parts {
+            let from = start.saturating_sub(offset).min(part.len());
+            let to = end.saturating_sub(offset).min(part.len());
+            if from < to {
+                write(&part[from..to])?;
+            }
+            offset += part.len();
+        }
+
+        if end == len {
+            break;
+        }
+        // The zero ending a block is given by its code, a full block has none
+        start = match end - start {
+            0xFE => end,
+            _ => end + 1,
+        };
+    }
+    write(&[0x00])
+}
+
+/// Decodes a COBS frame where it is, delimiter included. Returns the size of
+/// the decoded data, at the start of `buf`.
+fn cobs_decode_in_place(buf: &mut [u8]) -> Result<usize, CobsError> {
+    let len = buf.len().checked_sub(1).ok_or(CobsError::Empty)?;
+    if buf[len] != 0x00 {
+        return Err(CobsError::MissingDelimiter);
+    }
+    if len == 0 {
+        return Err(CobsError::Empty);
+    }
+
+    // The decoded data never gets ahead of the encoded data still to read
+    let mut pos = 0;
+    let mut size = 0;
+    while pos < len {
+        let code = buf[pos];
+        if code == 0x00 {
+            return Err(CobsError::ZeroCode);
+        }
+
+        let end = pos + code as usize;
+        if end > len || buf[pos + 1..end].contains(&0x00) {
+            return Err(CobsError::Truncated);
+        }
+        buf.copy_within(pos + 1..end, size);
+        size += end - pos - 1;
+        if code < 0xFF && end < len {
+            buf[size] = 0x00;
+            size += 1;
+        }
+        pos = end;
+    }
+
+    Ok(size)
+}
+
+/// Packet borrowing its payload, from a receive buffer or from the caller.
+/// It is encoded and decoded without copying the payload into a `Packet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketView<'a> {
+    command: u8,
+    seq: Option<u8>,
+    payload: &'a [u8],
+}
+
+impl<'a> PacketView<'a> {
+    pub fn new(command: u8, payload: &'a [u8]) -> Self {
+        if payload.len() > MAX_PACKET_PAYLOAD_SIZE {
+            panic!("Payload size exceeds maximum packet size");
+        }
+
+        Self {
+            command: command & CMD_MASK,
+            seq: None,
+            payload,
+        }
+    }
+
+    /// Numbers the packet, see `Packet::set_seq`
+    pub fn with_seq(self, seq: u8) -> Self {
+        if self.payload.len() > MAX_PACKET_PAYLOAD_SIZE - 1 {
+            panic!("Payload size exceeds maximum packet size");
+        }
+
+        Self { seq: Some(seq), ..self }
+    }
+
+    /// Command, without the flags
+    pub fn command(&self) -> u8 {
+        self.command
+    }
+
+    pub fn seq(&self) -> Option<u8> {
+        self.seq
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Copy of the packet, to keep it past the buffer it borrows
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_command(self.command);
+        if let Some(seq) = self.seq {
+            packet.set_seq(seq);
+        }
+        packet.set_payload(self.payload);
+        packet
+    }
+
+    /// Command byte and sequence number, as they start the frame
+    fn header(&self, crc: bool) -> ([u8; 2], usize) {
+        let flags = if crc { CMD_FLAG_CRC } else { 0 };
+        match self.seq {
+            Some(seq) => ([self.command | flags | CMD_FLAG_SEQ, seq], 2),
+            None => ([self.command | flags, 0], 1),
+        }
+    }
+
+    /// Encodes the frame piece by piece, see `Packet::to_frame`
+    fn encode_with<E>(&self, crc: bool, write: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        let (header, header_len) = self.header(crc);
+        let header = &header[..header_len];
+
+        let trailer = crc16_update(crc16(header), self.payload).to_le_bytes();
+        let trailer = if crc { &trailer[..] } else { &[] };
+        cobs_encode(&[header, self.payload, trailer], write)
+    }
+
+    /// Largest size the encoded frame can have, delimiter included. Found
+    /// from the sizes alone, where `encoded_len` encodes the frame.
+    pub fn max_encoded_len(&self, crc: bool) -> usize {
+        let (_, header_len) = self.header(crc);
+        let trailer_len = if crc { CRC_SIZE } else { 0 };
+        let len = header_len + self.payload.len() + trailer_len;
+        // A code byte per block of up to 254 bytes, and the delimiter
+        len + len / 254 + 2
+    }
+
+    /// Size of the encoded frame, delimiter included
+    pub fn encoded_len(&self, crc: bool) -> usize {
+        let mut len = 0;
+        let _ = self.encode_with(crc, |bytes| -> Result<(), ()> {
+            len += bytes.len();
+            Ok(())
+        });
+        len
+    }
+
+    /// Writes the frame to `writer`, with a CRC unless `crc` is false for
+    /// hosts speaking the original format. Returns the number of bytes
+    /// written.
+    pub fn write_frame<W: embedded_io::Write>(&self, crc: bool, writer: &mut W) -> Result<usize, W::Error> {
+        let mut len = 0;
+        self.encode_with(crc, |bytes| {
+            len += bytes.len();
+            writer.write_all(bytes)
+        })?;
+        Ok(len)
+    }
+
+    /// Encodes the frame at the start of `buf`. Returns its size, or an
+    /// error if `buf` is too small.
+    pub fn encode(&self, crc: bool, mut buf: &mut [u8]) -> Result<usize, embedded_io::SliceWriteError> {
+        self.write_frame(crc, &mut buf)
+    }
+
+    /// Decodes a COBS frame, delimiter included, where it is in `frame`. The
+    /// view borrows its payload from there. The flag tells whether the frame
+    /// had a CRC, see `Packet::from_frame`.
+    pub fn decode(frame: &'a mut [u8]) -> Result<(Self, bool), FrameError> {
+        let size = cobs_decode_in_place(frame).map_err(|_| FrameError::Framing)?;
+        if size > MAX_FRAME_SIZE {
+            return Err(FrameError::Oversize);
+        }
+        let (size, crc) = check_crc(&mut frame[..size], false)?;
+        if size > MAX_PACKET_SIZE {
+            return Err(FrameError::Framing);
+        }
+
+        let frame = &frame[..size];
+        let view = match (frame[0] & CMD_FLAG_SEQ, frame) {
+            (0, [command, payload @ ..]) => Self {
+                command: command & CMD_MASK,
+                seq: None,
+                payload,
+            },
+            (_, [command, seq, payload @ ..]) => Self {
+                command: command & CMD_MASK,
+                seq: Some(*seq),
+                payload,
+            },
+            _ => return Err(FrameError::Framing),
+        };
+        Ok((view, crc))
+    }
+}
+
 // impl From<&[u8]> for Packet {
 //     fn from(data: &[u8]) -> Self {
 //         let mut packet = Packet::new(data.len());
@@ -188,6 +801,20 @@ impl Packet  {
 //     }
 // }
 
+#[test]
+fn test_command() {
+    for byte in 0..=0xFF {
+        match Command::try_from(byte) {
+            Ok(command) => assert_eq!(u8::from(command), byte),
+            Err(e) => assert_eq!(e, UnknownCommand(byte)),
+        }
+    }
+    assert_eq!(Command::try_from(0x00), Err(UnknownCommand(0x00)));
+    assert!(VENDOR_COMMANDS.clone().all(|byte| Command::try_from(byte).is_err()));
+    // Vendor opcodes fit in the command byte along with the flags
+    assert_eq!(VENDOR_COMMANDS.end() & !CMD_MASK, 0);
+}
+
 #[test]
 fn test_packet() {
     let encoded_data_good = [0x02, 0x23, 0x03, 0xD4, 0x81, 0x02, 0xFA, 0x00];
@@ -259,4 +886,254 @@ fn test_packet() {
 
     let (encoded_data, encoded_size) = packet.to_cobs_slice();
     assert_eq!(encoded_data[0..encoded_size], encoded_data_good);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_frame_crc() {
+    assert_eq!(crc16(b"123456789"), 0x29B1);
+
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SET_TEXT.into());
+    packet.set_payload(&[0x00; MAX_PACKET_PAYLOAD_SIZE]);
+
+    let (encoded, size) = packet.to_frame(true);
+    let (decoded, crc) = Packet::from_frame(&encoded[..size]).unwrap();
+    assert!(crc);
+    assert_eq!(decoded.command(), Command::CMD_SET_TEXT as u8);
+    assert_eq!(decoded.get_payload(), packet.get_payload());
+
+    // Original format, as sent by older hosts
+    let (encoded, size) = packet.to_frame(false);
+    let (decoded, crc) = Packet::from_frame(&encoded[..size]).unwrap();
+    assert!(!crc);
+    assert_eq!(decoded.get_payload(), packet.get_payload());
+
+    packet.set_payload(b"HELLO");
+    let (mut encoded, size) = packet.to_frame(true);
+    encoded[3] ^= 0x04;
+    assert_eq!(Packet::from_frame(&encoded[..size]).unwrap_err(), FrameError::Crc);
+
+    assert_eq!(Packet::from_frame(&[]).unwrap_err(), FrameError::Framing);
+    assert_eq!(Packet::from_frame(&[0x00]).unwrap_err(), FrameError::Framing);
+    // Too short to hold a CRC
+    assert_eq!(Packet::from_frame(&[0x03, 0x94, 0x01, 0x00]).unwrap_err(), FrameError::Framing);
+    // Too short to hold a sequence number
+    assert_eq!(Packet::from_frame(&[0x02, 0x54, 0x00]).unwrap_err(), FrameError::Framing);
+}
+
+#[test]
+fn test_packet_seq() {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SET_TEXT.into());
+    packet.set_payload(b"HELLO");
+    assert_eq!(packet.seq(), None);
+
+    packet.set_seq(42);
+    assert_eq!(packet.seq(), Some(42));
+    assert_eq!(packet.command(), Command::CMD_SET_TEXT as u8);
+    assert_eq!(packet.get_payload(), b"HELLO");
+
+    let (encoded, size) = packet.to_frame(true);
+    let (decoded, _) = Packet::from_frame(&encoded[..size]).unwrap();
+    assert_eq!(decoded.seq(), Some(42));
+    assert_eq!(decoded.get_payload(), b"HELLO");
+
+    packet.set_payload(&[0x01; MAX_PACKET_PAYLOAD_SIZE - 1]);
+    assert_eq!(packet.size, MAX_PACKET_SIZE);
+}
+
+#[test]
+fn test_packet_view() {
+    let payload = [0x00, 0x12, 0x00, 0x00, 0x34];
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SET_TEXT.into());
+    packet.set_payload(&payload);
+
+    for (view, crc) in [
+        (PacketView::new(Command::CMD_SET_TEXT.into(), &payload), true),
+        (PacketView::new(Command::CMD_SET_TEXT.into(), &payload), false),
+        (PacketView::new(Command::CMD_SET_TEXT.into(), &payload).with_seq(7), true),
+    ] {
+        if let Some(seq) = view.seq() {
+            packet.set_seq(seq);
+        }
+        assert_eq!(view.to_packet().view(), view);
+        assert_eq!(packet.view(), view);
+
+        // Same frame as the packet it stands for
+        let (expected, size) = packet.to_frame(crc);
+        let mut buf = [0x00; MAX_ENCODED_SIZE];
+        assert_eq!(view.encode(crc, &mut buf), Ok(size));
+        assert_eq!(buf[..size], expected[..size]);
+        assert_eq!(view.encoded_len(crc), size);
+        assert!(view.max_encoded_len(crc) >= size);
+
+        let (decoded, decoded_crc) = PacketView::decode(&mut buf[..size]).unwrap();
+        assert_eq!((decoded, decoded_crc), (view, crc));
+    }
+
+    // The bound holds with and without zeros, whatever the size
+    for fill in [0x00, 0x01] {
+        let payload = [fill; MAX_PACKET_PAYLOAD_SIZE];
+        for len in 0..MAX_PACKET_PAYLOAD_SIZE {
+            let view = PacketView::new(Command::CMD_SET_TEXT.into(), &payload[..len]).with_seq(1);
+            assert!(view.max_encoded_len(true) >= view.encoded_len(true));
+            assert!(view.max_encoded_len(true) <= MAX_ENCODED_SIZE);
+        }
+    }
+
+    let view = PacketView::new(Command::CMD_SET_TEXT.into(), &[0x01; MAX_PACKET_PAYLOAD_SIZE]);
+    assert!(view.max_encoded_len(true) >= view.encoded_len(true));
+    let mut buf = [0x00; MAX_ENCODED_SIZE];
+    let size = view.encode(false, &mut buf).unwrap();
+    assert_eq!(PacketView::decode(&mut buf[..size]).unwrap().0, view);
+    assert_eq!(view.encode(false, &mut buf[..size - 1]), Err(embedded_io::SliceWriteError::Full));
+
+    let size = PacketView::new(Command::CMD_SET_TEXT.into(), b"HELLO").encode(true, &mut buf).unwrap();
+    buf[3] ^= 0x04;
+    assert_eq!(PacketView::decode(&mut buf[..size]).unwrap_err(), FrameError::Crc);
+    assert_eq!(PacketView::decode(&mut [0x00]).unwrap_err(), FrameError::Framing);
+    assert_eq!(PacketView::decode(&mut [0x02, 0x54, 0x00]).unwrap_err(), FrameError::Framing);
+    let mut long = [0x01; 300];
+    long[299] = 0x00;
+    assert_eq!(PacketView::decode(&mut long).unwrap_err(), FrameError::Oversize);
+}
+
+#[test]
+fn test_frame_decoder() {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SET_TEXT.into());
+    packet.set_payload(&[0x00, 0x12, 0x00, 0x00, 0x34]);
+    let (small, small_size) = packet.to_frame(true);
+    packet.set_payload(&[0x01; MAX_PACKET_PAYLOAD_SIZE]);
+    let (large, large_size) = packet.to_frame(false);
+
+    let mut stream = [0x00; 1024];
+    let mut size = 0;
+    let mut append = |bytes: &[u8]| {
+        stream[size..size + bytes.len()].copy_from_slice(bytes);
+        size += bytes.len();
+    };
+    // Garbage before the first frame, then back to back delimiters
+    append(&[0x05, 0x01]);
+    append(&[0x00, 0x00]);
+    append(&large[..large_size]);
+    append(&small[..small_size]);
+    // Too long for any packet
+    append(&[0xFF; 600]);
+    append(&[0x00]);
+    append(&small[..small_size]);
+
+    let mut decoder = FrameDecoder::new();
+    let mut results = heapless::Vec::<_, 8>::new();
+    let mut pos = 0;
+    while pos < size {
+        let (used, result) = decoder.push_slice(&stream[pos..size]);
+        pos += used;
+        results.extend(result);
+    }
+
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0].unwrap_err(), FrameError::Framing);
+    let (decoded, crc) = results[1].unwrap();
+    assert!(!crc);
+    assert_eq!(decoded.get_payload(), &[0x01; MAX_PACKET_PAYLOAD_SIZE]);
+    let (decoded, crc) = results[2].unwrap();
+    assert!(crc);
+    assert_eq!(decoded.get_payload(), &[0x00, 0x12, 0x00, 0x00, 0x34]);
+    assert_eq!(results[3].unwrap_err(), FrameError::Oversize);
+    assert_eq!(results[4].unwrap().0.get_payload(), &[0x00, 0x12, 0x00, 0x00, 0x34]);
+
+    // In place, the packet is borrowed from the decoder
+    let mut crc = None;
+    for &byte in &small[..small_size] {
+        crc = crc.or(decoder.push_in_place(byte));
+    }
+    assert_eq!(crc, Some(Ok(true)));
+    let view = decoder.packet();
+    assert_eq!((view.command(), view.payload()), (Command::CMD_SET_TEXT as u8, &[0x00, 0x12, 0x00, 0x00, 0x34][..]));
+
+    // Once a frame had a CRC, the original format is refused, corrupted
+    // command bytes included
+    assert_eq!(decoder.push_slice(&large[..large_size]).1.unwrap().unwrap_err(), FrameError::Crc);
+    let mut corrupted = small;
+    corrupted[1] &= !CMD_FLAG_CRC;
+    assert_eq!(decoder.push_slice(&corrupted[..small_size]).1.unwrap().unwrap_err(), FrameError::Crc);
+    assert!(decoder.push_slice(&small[..small_size]).1.unwrap().is_ok());
+}
+
+#[test]
+fn test_cobs_errors() {
+    assert_eq!(Packet::from_cobs(&[]).unwrap_err(), CobsError::Empty);
+    assert_eq!(Packet::from_cobs(&[0x00]).unwrap_err(), CobsError::Empty);
+    assert_eq!(Packet::from_cobs(&[0x00, 0x00]).unwrap_err(), CobsError::ZeroCode);
+    assert_eq!(Packet::from_cobs(&[0x02, 0x11, 0x00, 0x00]).unwrap_err(), CobsError::ZeroCode);
+    assert_eq!(Packet::from_cobs(&[0x05, 0x11, 0x00]).unwrap_err(), CobsError::Truncated);
+    assert_eq!(Packet::from_cobs(&[0x03, 0x11, 0x00, 0x12, 0x00]).unwrap_err(), CobsError::Truncated);
+    assert_eq!(Packet::from_cobs(&[0x02, 0x11]).unwrap_err(), CobsError::MissingDelimiter);
+    assert_eq!(Packet::from_cobs(&[0x01; 300]).unwrap_err(), CobsError::MissingDelimiter);
+
+    let mut long = [0x01; 300];
+    long[299] = 0x00;
+    assert_eq!(Packet::from_cobs(&long).unwrap_err(), CobsError::Overflow);
+}
+
+#[test]
+fn test_cobs_random() {
+    use crate::random::LCG;
+
+    let mut lcg = LCG::new(0x1234_5678);
+
+    // Round trip of random packets, zeros being frequent
+    for _ in 0..2000 {
+        let mut packet = Packet::new();
+        packet.size = 1 + lcg.next() as usize % MAX_PACKET_SIZE;
+        for byte in &mut packet.data[..packet.size] {
+            let r = lcg.next();
+            *byte = match r % 4 {
+                0 => 0x00,
+                _ => (r >> 8) as u8,
+            };
+        }
+
+        let (encoded, size) = packet.to_cobs_slice();
+        assert!(!encoded[..size - 1].contains(&0x00));
+        let decoded = Packet::from_cobs(&encoded[..size]).unwrap();
+        assert_eq!(decoded.data[..decoded.size], packet.data[..packet.size]);
+
+        packet.data[0] &= CMD_MASK;
+        let (encoded, size) = packet.to_frame(true);
+        let (decoded, crc) = Packet::from_frame(&encoded[..size]).unwrap();
+        assert!(crc);
+        assert_eq!(decoded.data[..decoded.size], packet.data[..packet.size]);
+
+        let mut buf = [0x00; MAX_ENCODED_SIZE];
+        assert_eq!(packet.view().encode(true, &mut buf), Ok(size));
+        assert_eq!(buf[..size], encoded[..size]);
+    }
+
+    // Arbitrary bytes, damaged frames included, never panic
+    let mut decoder = FrameDecoder::new();
+    for _ in 0..5000 {
+        let mut input = [0x00; 300];
+        let size = lcg.next() as usize % input.len();
+        for byte in &mut input[..size] {
+            let r = lcg.next();
+            *byte = match r % 8 {
+                0 => 0x00,
+                1 => 0xFF,
+                _ => (r >> 8) as u8,
+            };
+        }
+
+        let _ = Packet::from_cobs(&input[..size]);
+        let _ = Packet::from_frame(&input[..size]);
+        let mut copy = input;
+        let _ = PacketView::decode(&mut copy[..size]);
+        let mut pos = 0;
+        while pos < size {
+            let (used, _) = decoder.push_slice(&input[pos..size]);
+            pos += used;
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! `CMD_QUERY` requests reading back the state of the display, answered with
+//! `CMD_STATE`. The answer is what the device was told, the display module
+//! itself cannot be read back.
+
+use serde::{Deserialize, Serialize};
+
+use crate::display::ScrollConfig;
+use crate::hdsp::{Glyph, UdcBitmap, NUM_UDC, UDC_ROWS};
+use crate::markup::MAX_MARKUP_GLYPHS;
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+use crate::rsvp::{self, DisplayMode, RsvpConfig};
+use crate::schedule::{Schedule, MAX_SCHEDULE_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Query {
+    Text = 0,
+    Scroll = 1,
+    Control = 2,
+    Udcs = 3,
+    Flash = 4,
+    Mode = 5,
+    Schedule = 6,
+    Auth = 7,
+    Rewrites = 8,
+}
+
+impl TryFrom<u8> for Query {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Query::Text),
+            1 => Ok(Query::Scroll),
+            2 => Ok(Query::Control),
+            3 => Ok(Query::Udcs),
+            4 => Ok(Query::Flash),
+            5 => Ok(Query::Mode),
+            6 => Ok(Query::Schedule),
+            7 => Ok(Query::Auth),
+            8 => Ok(Query::Rewrites),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Query {
+    pub fn to_packet(self) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_QUERY.into());
+        packet.set_payload(&[self as u8]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [query] => Query::try_from(*query).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Answer to a query
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum State {
+    Text(heapless::Vec<Glyph, MAX_MARKUP_GLYPHS>),
+    Scroll {
+        config: ScrollConfig,
+        /// Position of the leftmost digit in the text
+        pos: u16,
+    },
+    Control {
+        /// Control word set by the host and the text
+        control: u8,
+        /// Brightness shown, after the schedule and the effects
+        brightness: u8,
+    },
+    Udcs([UdcBitmap; NUM_UDC]),
+    /// Digits flashing whatever the text shows
+    Flash(u8),
+    Mode(DisplayMode, RsvpConfig),
+    Schedule(Schedule),
+    Auth {
+        /// A key is set, see `auth`
+        locked: bool,
+        /// Session of this boot of the device
+        session: u32,
+        /// Last counter accepted in the session
+        counter: u32,
+    },
+    /// Times the whole display was written again since boot, in case it
+    /// lost its contents
+    Rewrites(u32),
+}
+
+impl State {
+    pub fn query(&self) -> Query {
+        match self {
+            State::Text(_) => Query::Text,
+            State::Scroll { .. } => Query::Scroll,
+            State::Control { .. } => Query::Control,
+            State::Udcs(_) => Query::Udcs,
+            State::Flash(_) => Query::Flash,
+            State::Mode(..) => Query::Mode,
+            State::Schedule(_) => Query::Schedule,
+            State::Auth { .. } => Query::Auth,
+            State::Rewrites(_) => Query::Rewrites,
+        }
+    }
+
+    /// Encoded as `[query, state...]`. The text is its length, the codes and
+    /// then the flash attributes with one bit per glyph.
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = [0; MAX_PACKET_PAYLOAD_SIZE];
+        payload[0] = self.query() as u8;
+
+        let size = 1 + match self {
+            State::Text(glyphs) => {
+                let len = glyphs.len();
+                payload[1] = len as u8;
+                for (i, glyph) in glyphs.iter().enumerate() {
+                    payload[2 + i] = glyph.code;
+                    payload[2 + len + i / 8] |= (glyph.flash as u8) << (i % 8);
+                }
+                1 + len + len.div_ceil(8)
+            }
+            State::Scroll { config, pos } => {
+                let config = config.to_packet();
+                let config = config.get_payload();
+                payload[1..1 + config.len()].copy_from_slice(config);
+                payload[1 + config.len()..3 + config.len()].copy_from_slice(&pos.to_le_bytes());
+                config.len() + 2
+            }
+            State::Control { control, brightness } => {
+                payload[1..3].copy_from_slice(&[*control, *brightness]);
+                2
+            }
+            State::Udcs(udcs) => {
+                for (chunk, bitmap) in payload[1..].chunks_exact_mut(UDC_ROWS).zip(udcs) {
+                    chunk.copy_from_slice(bitmap);
+                }
+                NUM_UDC * UDC_ROWS
+            }
+            State::Flash(digits) => {
+                payload[1] = *digits;
+                1
+            }
+            State::Mode(mode, config) => {
+                let mode = rsvp::mode_to_packet(*mode, config);
+                let mode = mode.get_payload();
+                payload[1..1 + mode.len()].copy_from_slice(mode);
+                mode.len()
+            }
+            State::Schedule(schedule) => {
+                let mut buf = [0; MAX_SCHEDULE_SIZE];
+                let size = schedule.to_bytes(&mut buf);
+                payload[1..1 + size].copy_from_slice(&buf[..size]);
+                size
+            }
+            State::Auth { locked, session, counter } => {
+                payload[1] = *locked as u8;
+                payload[2..6].copy_from_slice(&session.to_le_bytes());
+                payload[6..10].copy_from_slice(&counter.to_le_bytes());
+                9
+            }
+            State::Rewrites(rewrites) => {
+                payload[1..5].copy_from_slice(&rewrites.to_le_bytes());
+                4
+            }
+        };
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_STATE.into());
+        packet.set_payload(&payload[..size]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let (&query, data) = payload.split_first()?;
+
+        match Query::try_from(query).ok()? {
+            Query::Text => {
+                let (&len, data) = data.split_first()?;
+                let len = len as usize;
+                if data.len() != len + len.div_ceil(8) {
+                    return None;
+                }
+
+                let (codes, flash) = data.split_at(len);
+                let mut glyphs = heapless::Vec::new();
+                for (i, &code) in codes.iter().enumerate() {
+                    let glyph = Glyph {
+                        code,
+                        flash: flash[i / 8] & (1 << (i % 8)) != 0,
+                    };
+                    glyphs.push(glyph).ok()?;
+                }
+                Some(State::Text(glyphs))
+            }
+            Query::Scroll => {
+                let (config, pos) = data.split_at_checked(data.len().checked_sub(2)?)?;
+                Some(State::Scroll {
+                    config: ScrollConfig::from_payload(config)?,
+                    pos: u16::from_le_bytes([pos[0], pos[1]]),
+                })
+            }
+            Query::Control => match data {
+                [control, brightness] => Some(State::Control {
+                    control: *control,
+                    brightness: *brightness,
+                }),
+                _ => None,
+            },
+            Query::Udcs => {
+                if data.len() != NUM_UDC * UDC_ROWS {
+                    return None;
+                }
+                let mut udcs = [[0; UDC_ROWS]; NUM_UDC];
+                for (bitmap, chunk) in udcs.iter_mut().zip(data.chunks_exact(UDC_ROWS)) {
+                    bitmap.copy_from_slice(chunk);
+                }
+                Some(State::Udcs(udcs))
+            }
+            Query::Flash => match data {
+                [digits] => Some(State::Flash(*digits)),
+                _ => None,
+            },
+            Query::Mode => {
+                let (mode, config) = rsvp::mode_from_payload(data)?;
+                Some(State::Mode(mode, config))
+            }
+            Query::Schedule => Some(State::Schedule(Schedule::from_bytes(data)?)),
+            Query::Auth => match data {
+                [locked @ (0 | 1), s0, s1, s2, s3, c0, c1, c2, c3] => Some(State::Auth {
+                    locked: *locked == 1,
+                    session: u32::from_le_bytes([*s0, *s1, *s2, *s3]),
+                    counter: u32::from_le_bytes([*c0, *c1, *c2, *c3]),
+                }),
+                _ => None,
+            },
+            Query::Rewrites => match data {
+                [r0, r1, r2, r3] => Some(State::Rewrites(u32::from_le_bytes([*r0, *r1, *r2, *r3]))),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[test]
+fn test_query() {
+    use crate::schedule::{Window, WEEKDAYS};
+
+    assert_eq!(Query::from_payload(Query::Udcs.to_packet().get_payload()), Some(Query::Udcs));
+    assert_eq!(Query::from_payload(&[9]), None);
+
+    let mut glyphs = heapless::Vec::new();
+    for (i, &code) in b"HELLO WORLD".iter().enumerate() {
+        glyphs.push(Glyph { code, flash: i % 3 == 0 }).unwrap();
+    }
+    let mut udcs = [[0; UDC_ROWS]; NUM_UDC];
+    udcs[15] = [0x1F, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1F];
+    let mut schedule = Schedule::new();
+    schedule.windows.push(Window::new(WEEKDAYS, 8 * 60, 19 * 60, 7)).unwrap();
+
+    let states = [
+        State::Text(glyphs),
+        State::Text(heapless::Vec::new()),
+        State::Scroll {
+            config: ScrollConfig::DEFAULT,
+            pos: 300,
+        },
+        State::Control {
+            control: 0x13,
+            brightness: 4,
+        },
+        State::Udcs(udcs),
+        State::Flash(0x81),
+        State::Mode(DisplayMode::Rsvp, RsvpConfig::DEFAULT),
+        State::Schedule(schedule),
+        State::Auth {
+            locked: true,
+            session: 12,
+            counter: 0x0102_0304,
+        },
+        State::Rewrites(70_000),
+    ];
+    for state in states {
+        let packet = state.to_packet();
+        assert_eq!(Command::try_from(packet.command()), Ok(Command::CMD_STATE));
+        assert_eq!(State::from_payload(packet.get_payload()), Some(state));
+    }
+
+    assert_eq!(State::from_payload(&[Query::Text as u8, 3, b'A', b'B']), None);
+    assert_eq!(State::from_payload(&[Query::Scroll as u8, 1]), None);
+    assert_eq!(State::from_payload(&[Query::Auth as u8, 2, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+}
@@ -0,0 +1,286 @@
+//! Acknowledged delivery of packets.
+//!
+//! A packet numbered with `Packet::set_seq` is answered by the device with
+//! `CMD_ACK [seq]` once applied, or `CMD_NACK [seq, code]` when it was
+//! refused. The host keeps a bounded window of packets waiting for their
+//! answer and sends them again when it does not come in time.
+//!
+//! The device remembers the answers to the last packets it received. A packet
+//! sent again because its answer was lost gets the same answer, and is not
+//! applied a second time. A host starts its session with `CMD_HELLO`, which
+//! makes the device forget the answers given to the previous one. Once the
+//! device has a key, only a `CMD_HELLO` wrapped in `CMD_AUTH` does, see
+//! `auth`.
+
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+
+/// Number of answers the device remembers, the host window must not be larger
+pub const HISTORY_SIZE: usize = 16;
+/// Default time before a packet is sent again
+pub const DEFAULT_TIMEOUT_MS: u64 = 200;
+/// Default number of times a packet is sent again before giving up
+pub const DEFAULT_RETRIES: u8 = 3;
+
+/// Why the device refused a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NackCode {
+    UnknownCommand = 1,
+    InvalidPayload = 2,
+    /// Valid, but could not be applied, for instance a flash write failing
+    Failed = 3,
+    /// Not authenticated while the device has a key, see `auth`
+    Unauthorized = 4,
+}
+
+impl NackCode {
+    pub fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(NackCode::UnknownCommand),
+            2 => Some(NackCode::InvalidPayload),
+            3 => Some(NackCode::Failed),
+            4 => Some(NackCode::Unauthorized),
+            _ => None,
+        }
+    }
+}
+
+/// Answer of the device to a numbered packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+    pub seq: u8,
+    pub result: Result<(), NackCode>,
+}
+
+impl Response {
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        match self.result {
+            Ok(()) => {
+                packet.set_command(Command::CMD_ACK.into());
+                packet.set_payload(&[self.seq]);
+            }
+            Err(code) => {
+                packet.set_command(Command::CMD_NACK.into());
+                packet.set_payload(&[self.seq, code as u8]);
+            }
+        }
+        packet
+    }
+
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        match (Command::try_from(packet.command()), packet.get_payload()) {
+            (Ok(Command::CMD_ACK), [seq]) => Some(Self { seq: *seq, result: Ok(()) }),
+            (Ok(Command::CMD_NACK), [seq, code]) => Some(Self {
+                seq: *seq,
+                result: Err(NackCode::from_u8(*code)?),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Answers of the device to the last numbered packets
+pub struct Receiver {
+    history: Deque<Response, HISTORY_SIZE>,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self { history: Deque::new() }
+    }
+
+    /// Answer already given to `seq`, if the packet is a duplicate
+    pub fn duplicate(&self, seq: u8) -> Option<Response> {
+        self.history.iter().find(|r| r.seq == seq).copied()
+    }
+
+    /// Remembers the answer given to a packet
+    pub fn record(&mut self, response: Response) {
+        if self.history.is_full() {
+            self.history.pop_front();
+        }
+        let _ = self.history.push_back(response);
+    }
+
+    /// Forgets every answer, for a host starting a new session
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the host has to do after `Sender::poll`
+#[derive(Debug, Clone, Copy)]
+pub enum SenderEvent<'a> {
+    /// The packet got no answer in time and has to be sent again
+    Resend(&'a Packet),
+    /// The packet got no answer after every retry, it was dropped
+    TimedOut(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    packet: Packet,
+    deadline_ms: u64,
+    retries: u8,
+}
+
+/// Host side of the acknowledged delivery, with up to `N` packets waiting for
+/// their answer. `N` must not be larger than `HISTORY_SIZE`.
+pub struct Sender<const N: usize> {
+    in_flight: heapless::Vec<InFlight, N>,
+    next_seq: u8,
+    pub timeout_ms: u64,
+    pub retries: u8,
+}
+
+impl<const N: usize> Sender<N> {
+    /// Sender numbering packets from `first_seq`. A host starting a new
+    /// session sends `CMD_HELLO` first, or the device could take its first
+    /// packets for duplicates of the previous session.
+    pub fn new(first_seq: u8) -> Self {
+        Self {
+            in_flight: heapless::Vec::new(),
+            next_seq: first_seq,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Whether another packet can be sent before some are answered
+    pub fn is_full(&self) -> bool {
+        self.in_flight.is_full()
+    }
+
+    /// Numbers `packet`, which is then ready to transmit, and keeps it until
+    /// it is answered. Returns false and leaves it as it was when the window
+    /// is full, or when its payload is full and leaves no room for the
+    /// sequence number.
+    pub fn send(&mut self, packet: &mut Packet, now_ms: u64) -> bool {
+        let no_room = packet.seq().is_none() && packet.get_payload().len() >= MAX_PACKET_PAYLOAD_SIZE;
+        if self.is_full() || no_room {
+            return false;
+        }
+
+        packet.set_seq(self.next_seq);
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let _ = self.in_flight.push(InFlight {
+            packet: *packet,
+            deadline_ms: now_ms + self.timeout_ms,
+            retries: 0,
+        });
+        true
+    }
+
+    /// Handles a packet from the device. Returns the answer if it was one to
+    /// a packet waiting for it.
+    pub fn receive(&mut self, packet: &Packet) -> Option<Response> {
+        let response = Response::from_packet(packet)?;
+        let index = self.in_flight.iter().position(|f| f.packet.seq() == Some(response.seq))?;
+        self.in_flight.swap_remove(index);
+        Some(response)
+    }
+
+    /// Packet to send again or given up on at `now_ms`, to be called until it
+    /// returns `None`
+    pub fn poll(&mut self, now_ms: u64) -> Option<SenderEvent<'_>> {
+        let index = self.in_flight.iter().position(|f| now_ms >= f.deadline_ms)?;
+
+        if self.in_flight[index].retries >= self.retries {
+            let seq = self.in_flight.swap_remove(index).packet.seq().unwrap_or_default();
+            return Some(SenderEvent::TimedOut(seq));
+        }
+
+        let timeout_ms = self.timeout_ms;
+        let flight = &mut self.in_flight[index];
+        flight.retries += 1;
+        flight.deadline_ms = now_ms + timeout_ms;
+        Some(SenderEvent::Resend(&flight.packet))
+    }
+
+    /// Number of packets waiting for their answer
+    pub fn pending(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[test]
+fn test_reliable() {
+    use crate::display;
+
+    let mut sender: Sender<2> = Sender::new(254);
+    let mut first = display::brightness_to_packet(5);
+    assert!(sender.send(&mut first, 0));
+    assert_eq!(first.seq(), Some(254));
+    assert_eq!(first.command(), Command::CMD_SET_BRIGHTNESS as u8);
+    assert_eq!(first.get_payload(), &[5]);
+    let mut second = display::clear_to_packet();
+    assert!(sender.send(&mut second, 0));
+    assert_eq!(second.seq(), Some(255));
+    let mut third = display::reset_to_packet();
+    assert!(!sender.send(&mut third, 0));
+    assert_eq!(third.seq(), None);
+
+    // A full payload is not cut to make room for the sequence number
+    let mut full = Packet::new();
+    full.set_command(Command::CMD_SET_TEXT.into());
+    full.set_payload(&[0x41; MAX_PACKET_PAYLOAD_SIZE]);
+    let mut roomy: Sender<2> = Sender::new(0);
+    assert!(!roomy.send(&mut full, 0));
+    assert_eq!(full.seq(), None);
+    assert_eq!(full.get_payload(), &[0x41; MAX_PACKET_PAYLOAD_SIZE]);
+    full.set_payload(&[0x41; MAX_PACKET_PAYLOAD_SIZE - 1]);
+    assert!(roomy.send(&mut full, 0));
+    assert_eq!(full.get_payload(), &[0x41; MAX_PACKET_PAYLOAD_SIZE - 1]);
+
+    // The device applies the first packet once, even when it comes again
+    let mut receiver = Receiver::new();
+    assert_eq!(receiver.duplicate(254), None);
+    let ack = Response { seq: 254, result: Ok(()) };
+    receiver.record(ack);
+    assert_eq!(receiver.duplicate(254), Some(ack));
+
+    assert_eq!(sender.receive(&ack.to_packet()), Some(ack));
+    assert_eq!(sender.receive(&ack.to_packet()), None);
+    assert_eq!(sender.pending(), 1);
+    assert!(sender.send(&mut third, 0));
+    assert_eq!(third.seq(), Some(0));
+
+    let nack = Response {
+        seq: 0,
+        result: Err(NackCode::InvalidPayload),
+    };
+    assert_eq!(Response::from_packet(&nack.to_packet()), Some(nack));
+    assert_eq!(sender.receive(&nack.to_packet()), Some(nack));
+
+    // The second packet is never answered
+    assert!(sender.poll(DEFAULT_TIMEOUT_MS - 1).is_none());
+    for retry in 1..=DEFAULT_RETRIES as u64 {
+        match sender.poll(retry * DEFAULT_TIMEOUT_MS) {
+            Some(SenderEvent::Resend(packet)) => assert_eq!(packet.seq(), Some(255)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert!(matches!(sender.poll(10 * DEFAULT_TIMEOUT_MS), Some(SenderEvent::TimedOut(255))));
+    assert_eq!(sender.pending(), 0);
+
+    for seq in 0..=HISTORY_SIZE as u8 {
+        receiver.record(Response { seq, result: Ok(()) });
+    }
+    assert_eq!(receiver.duplicate(0), None);
+    assert!(receiver.duplicate(HISTORY_SIZE as u8).is_some());
+
+    // A new host numbering from the same seqs is not answered from the
+    // history of the previous one once it said hello
+    receiver.clear();
+    assert!((0..=255).all(|seq| receiver.duplicate(seq).is_none()));
+}
@@ -0,0 +1,293 @@
+//! Word by word reading mode, also known as RSVP (rapid serial visual
+//! presentation).
+//!
+//! Instead of scrolling, the message is shown one word at a time, centred on
+//! the display. Each word stays for a base time plus a time per character, and
+//! punctuation at the end of a word adds a pause. Words longer than the display
+//! are either hyphenated or scrolled.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{Frame, Glyph, NUM_DIGITS, UDC_FLAG};
+use crate::packet::{Command, Packet};
+
+/// Time each window of a scrolled long word is shown, except the first and last
+pub const LONG_WORD_SCROLL_MS: u32 = 150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayMode {
+    /// The message scrolls from right to left
+    Scroll = 0,
+    /// The message is shown word by word
+    Rsvp = 1,
+}
+
+impl TryFrom<u8> for DisplayMode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DisplayMode::Scroll),
+            1 => Ok(DisplayMode::Rsvp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How words longer than the display are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LongWords {
+    /// Split in parts ending with a hyphen
+    Hyphenate = 0,
+    /// Scrolled one character at a time
+    Scroll = 1,
+}
+
+impl TryFrom<u8> for LongWords {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(LongWords::Hyphenate),
+            1 => Ok(LongWords::Scroll),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RsvpConfig {
+    pub long_words: LongWords,
+    /// Time every word is shown, also the pause after a comma
+    pub word_ms: u16,
+    /// Time added per character of the word
+    pub char_ms: u16,
+}
+
+impl RsvpConfig {
+    pub const DEFAULT: RsvpConfig = RsvpConfig {
+        long_words: LongWords::Hyphenate,
+        word_ms: 250,
+        char_ms: 40,
+    };
+
+    /// Extra time after a word ending with `code`
+    pub fn punctuation_ms(&self, code: u8) -> u32 {
+        match code {
+            b',' | b';' | b':' | b'-' => self.word_ms as u32,
+            b'.' | b'!' | b'?' => 2 * self.word_ms as u32,
+            _ => 0,
+        }
+    }
+}
+
+impl Default for RsvpConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// `CMD_SET_MODE` packet. The mode applies to the current message and the
+/// following ones.
+pub fn mode_to_packet(mode: DisplayMode, config: &RsvpConfig) -> Packet {
+    let word_ms = config.word_ms.to_le_bytes();
+    let char_ms = config.char_ms.to_le_bytes();
+
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SET_MODE.into());
+    packet.set_payload(&[
+        mode as u8,
+        config.long_words as u8,
+        word_ms[0],
+        word_ms[1],
+        char_ms[0],
+        char_ms[1],
+    ]);
+    packet
+}
+
+/// Decodes a `CMD_SET_MODE` payload. The payload can be only the mode, the
+/// default settings are then used.
+pub fn mode_from_payload(payload: &[u8]) -> Option<(DisplayMode, RsvpConfig)> {
+    match payload {
+        [mode] => Some((DisplayMode::try_from(*mode).ok()?, RsvpConfig::DEFAULT)),
+        [mode, long_words, w0, w1, c0, c1] => {
+            let config = RsvpConfig {
+                long_words: LongWords::try_from(*long_words).ok()?,
+                word_ms: u16::from_le_bytes([*w0, *w1]),
+                char_ms: u16::from_le_bytes([*c0, *c1]),
+            };
+            Some((DisplayMode::try_from(*mode).ok()?, config))
+        }
+        _ => None,
+    }
+}
+
+/// Part of the message shown at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// Index of the first glyph shown
+    pub start: usize,
+    /// Number of glyphs shown
+    pub len: usize,
+    /// The glyphs are followed by a hyphen, the word continues in the next step
+    pub hyphen: bool,
+    pub dwell_ms: u32,
+}
+
+impl Step {
+    /// Index after the last glyph shown
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// Draws the step centred into `frame`, clearing the other digits
+    pub fn render(&self, glyphs: &[Glyph], frame: &mut Frame) {
+        let width = self.len + self.hyphen as usize;
+        let pad = NUM_DIGITS.saturating_sub(width) / 2;
+
+        frame.chars = [b' '; NUM_DIGITS];
+        frame.flash = 0x00;
+
+        let shown = glyphs[self.start..self.end()].iter().copied();
+        let hyphen = self.hyphen.then_some(Glyph::new(b'-'));
+        for (i, glyph) in shown.chain(hyphen).enumerate().take(NUM_DIGITS - pad) {
+            frame.chars[pad + i] = glyph.code;
+            if glyph.flash {
+                frame.flash |= 1 << (pad + i);
+            }
+        }
+    }
+}
+
+fn is_space(glyph: &Glyph) -> bool {
+    glyph.code == b' '
+}
+
+/// Splits a message into steps, starting over at the end
+pub struct Rsvp {
+    pub config: RsvpConfig,
+    /// Next glyph to show
+    pos: usize,
+}
+
+impl Rsvp {
+    pub fn new(config: RsvpConfig) -> Self {
+        Self { config, pos: 0 }
+    }
+
+    pub fn restart(&mut self) {
+        self.pos = 0;
+    }
+
+    /// Next part of `glyphs` to show, `None` if there are no words at all
+    pub fn next(&mut self, glyphs: &[Glyph]) -> Option<Step> {
+        let skip_spaces = |pos: usize| pos + glyphs.iter().skip(pos).take_while(|g| is_space(g)).count();
+
+        let mut start = skip_spaces(self.pos);
+        if start >= glyphs.len() {
+            start = skip_spaces(0);
+            if start >= glyphs.len() {
+                return None;
+            }
+        }
+
+        let word_end = start + glyphs[start..].iter().take_while(|g| !is_space(g)).count();
+        // Part of the word left, a long word is shown in several steps
+        let rest = word_end - start;
+        let word_ms = self.config.word_ms as u32;
+        let char_ms = self.config.char_ms as u32;
+
+        let last = glyphs[word_end - 1].code;
+        let punctuation_ms = match last & UDC_FLAG {
+            0 => self.config.punctuation_ms(last),
+            _ => 0,
+        };
+
+        // A scrolled long word has already been read when its end is reached
+        let is_word_start = start == 0 || is_space(&glyphs[start - 1]);
+
+        let step = if rest <= NUM_DIGITS {
+            let is_last_window = self.config.long_words == LongWords::Scroll && !is_word_start;
+            let dwell_ms = match is_last_window {
+                true => word_ms + punctuation_ms,
+                false => word_ms + char_ms * rest as u32 + punctuation_ms,
+            };
+            Step {
+                start,
+                len: rest,
+                hyphen: false,
+                dwell_ms,
+            }
+        } else {
+            match self.config.long_words {
+                LongWords::Hyphenate => Step {
+                    start,
+                    len: NUM_DIGITS - 1,
+                    hyphen: true,
+                    dwell_ms: word_ms + char_ms * (NUM_DIGITS as u32 - 1),
+                },
+                LongWords::Scroll => {
+                    let dwell_ms = match is_word_start {
+                        true => word_ms + char_ms * NUM_DIGITS as u32,
+                        false => LONG_WORD_SCROLL_MS,
+                    };
+                    Step {
+                        start,
+                        len: NUM_DIGITS,
+                        hyphen: false,
+                        dwell_ms,
+                    }
+                }
+            }
+        };
+
+        self.pos = match self.config.long_words {
+            LongWords::Scroll if rest > NUM_DIGITS => start + 1,
+            _ => step.end(),
+        };
+        Some(step)
+    }
+}
+
+#[test]
+fn test_rsvp() {
+    let glyphs: heapless::Vec<Glyph, 64> = b"Hi, internationalization works."
+        .iter()
+        .map(|&c| Glyph::new(c))
+        .collect();
+
+    let mut rsvp = Rsvp::new(RsvpConfig::DEFAULT);
+    let steps: heapless::Vec<Step, 8> = (0..6).map(|_| rsvp.next(&glyphs).unwrap()).collect();
+    let (start_len, hyphens): (heapless::Vec<(usize, usize), 8>, heapless::Vec<bool, 8>) =
+        steps.iter().map(|s| ((s.start, s.len), s.hyphen)).unzip();
+
+    assert_eq!(&start_len[..], &[(0, 3), (4, 7), (11, 7), (18, 6), (25, 6), (0, 3)]);
+    assert_eq!(&hyphens[..], &[false, true, true, false, false, false]);
+    // Base time, 3 characters and a comma
+    assert_eq!(steps[0].dwell_ms, 250 + 3 * 40 + 250);
+    assert_eq!(steps[4].dwell_ms, 250 + 6 * 40 + 500);
+
+    let mut frame = Frame::new();
+    steps[0].render(&glyphs, &mut frame);
+    assert_eq!(&frame.chars, b"  Hi,   ");
+    steps[1].render(&glyphs, &mut frame);
+    assert_eq!(&frame.chars, b"interna-");
+
+    let mut rsvp = Rsvp::new(RsvpConfig {
+        long_words: LongWords::Scroll,
+        ..RsvpConfig::DEFAULT
+    });
+    rsvp.next(&glyphs);
+    let windows: heapless::Vec<usize, 16> = (0..14).map(|_| rsvp.next(&glyphs).unwrap().start).collect();
+    assert_eq!(&windows[..], &[4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 25]);
+
+    let blank: heapless::Vec<Glyph, 4> = b"   ".iter().map(|&c| Glyph::new(c)).collect();
+    assert_eq!(rsvp.next(&blank), None);
+
+    let packet = mode_to_packet(DisplayMode::Rsvp, &RsvpConfig::DEFAULT);
+    assert_eq!(
+        mode_from_payload(packet.get_payload()),
+        Some((DisplayMode::Rsvp, RsvpConfig::DEFAULT))
+    );
+}
@@ -0,0 +1,255 @@
+//! Brightness schedule evaluated against the device clock.
+//!
+//! A schedule is a list of windows, each one giving a brightness level for
+//! some days of the week between two times of day. The first window matching
+//! the current time wins, and the default level applies outside every window.
+//! A level of 0 blanks the display.
+//!
+//! The level is a ceiling, brightness changes of the message still apply below
+//! it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{Frame, MAX_BRIGHTNESS};
+use crate::packet::{Command, Packet};
+use crate::time::{DateTime, WallClock, SECS_PER_MINUTE};
+
+/// Maximum number of windows in a schedule
+pub const MAX_WINDOWS: usize = 8;
+/// Size of an encoded window
+const WINDOW_SIZE: usize = 6;
+/// Maximum size of an encoded schedule
+pub const MAX_SCHEDULE_SIZE: usize = 1 + MAX_WINDOWS * WINDOW_SIZE;
+
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Monday to Friday
+pub const WEEKDAYS: u8 = 0x1F;
+/// Saturday and Sunday
+pub const WEEKEND: u8 = 0x60;
+pub const EVERY_DAY: u8 = WEEKDAYS | WEEKEND;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    /// One bit per day, bit 0 being Monday
+    pub days: u8,
+    /// Minutes since midnight
+    pub start: u16,
+    /// Minutes since midnight, the window goes past midnight when it is not
+    /// after `start`
+    pub end: u16,
+    pub brightness: u8,
+}
+
+impl Window {
+    pub fn new(days: u8, start: u16, end: u16, brightness: u8) -> Self {
+        Self {
+            days,
+            start,
+            end,
+            brightness,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.start < MINUTES_PER_DAY && self.end <= MINUTES_PER_DAY && self.brightness <= MAX_BRIGHTNESS
+    }
+
+    /// Whether `minute` of day `weekday` (0 is Monday) falls in the window
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let on_day = |day: u8| self.days & (1 << (day % 7)) != 0;
+
+        if self.start < self.end {
+            on_day(weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            // Past midnight the window belongs to the day before
+            (on_day(weekday) && minute >= self.start) || (on_day(weekday + 6) && minute < self.end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub windows: heapless::Vec<Window, MAX_WINDOWS>,
+    /// Brightness outside every window
+    pub default_brightness: u8,
+}
+
+impl Schedule {
+    /// Schedule keeping the display at full brightness
+    pub fn new() -> Self {
+        Self {
+            windows: heapless::Vec::new(),
+            default_brightness: MAX_BRIGHTNESS,
+        }
+    }
+
+    /// Brightness ceiling at time `now`
+    pub fn brightness_at(&self, now: &DateTime) -> u8 {
+        let minute = (now.secs_of_day() / SECS_PER_MINUTE as u32) as u16;
+
+        self.windows
+            .iter()
+            .find(|w| w.contains(now.weekday, minute))
+            .map_or(self.default_brightness, |w| w.brightness)
+    }
+
+    /// Encoded as `[default, (days, start_le, end_le, brightness)...]`, the
+    /// same in `CMD_SET_SCHEDULE` and in flash
+    pub fn to_bytes(&self, buf: &mut [u8; MAX_SCHEDULE_SIZE]) -> usize {
+        buf[0] = self.default_brightness;
+
+        for (i, window) in self.windows.iter().enumerate() {
+            let start = window.start.to_le_bytes();
+            let end = window.end.to_le_bytes();
+            buf[1 + i * WINDOW_SIZE..1 + (i + 1) * WINDOW_SIZE]
+                .copy_from_slice(&[window.days, start[0], start[1], end[0], end[1], window.brightness]);
+        }
+        1 + self.windows.len() * WINDOW_SIZE
+    }
+
+    /// Whether every level and time is in range, `from_bytes` only gives
+    /// valid schedules
+    pub fn is_valid(&self) -> bool {
+        self.default_brightness <= MAX_BRIGHTNESS && self.windows.iter().all(Window::is_valid)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&default_brightness, windows) = bytes.split_first()?;
+        if default_brightness > MAX_BRIGHTNESS || windows.len() % WINDOW_SIZE != 0 {
+            return None;
+        }
+
+        let mut schedule = Schedule {
+            windows: heapless::Vec::new(),
+            default_brightness,
+        };
+        for w in windows.chunks_exact(WINDOW_SIZE) {
+            let window = Window {
+                days: w[0],
+                start: u16::from_le_bytes([w[1], w[2]]),
+                end: u16::from_le_bytes([w[3], w[4]]),
+                brightness: w[5],
+            };
+            if !window.is_valid() {
+                return None;
+            }
+            schedule.windows.push(window).ok()?;
+        }
+        Some(schedule)
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut buf = [0; MAX_SCHEDULE_SIZE];
+        let size = self.to_bytes(&mut buf);
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_SET_SCHEDULE.into());
+        packet.set_payload(&buf[..size]);
+        packet
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Schedule along with a temporary override from the host
+pub struct Dimmer {
+    pub schedule: Schedule,
+    /// Brightness held until the given time in ms
+    held: Option<(u8, u64)>,
+}
+
+impl Dimmer {
+    pub fn new(schedule: Schedule) -> Self {
+        Self { schedule, held: None }
+    }
+
+    /// Holds `brightness` for `minutes`, 0 minutes going back to the schedule
+    pub fn hold(&mut self, brightness: u8, minutes: u16, now_ms: u64) {
+        self.held = match minutes {
+            0 => None,
+            _ => Some((brightness, now_ms + minutes as u64 * SECS_PER_MINUTE * 1000)),
+        };
+    }
+
+    /// Brightness ceiling at `now_ms`. The schedule is ignored until the clock
+    /// is set.
+    pub fn brightness(&mut self, clock: &WallClock, now_ms: u64) -> u8 {
+        match self.held {
+            Some((brightness, until_ms)) if now_ms < until_ms => return brightness,
+            Some(_) => self.held = None,
+            None => {}
+        }
+
+        clock
+            .now(now_ms)
+            .map_or(MAX_BRIGHTNESS, |now| self.schedule.brightness_at(&now))
+    }
+}
+
+/// `CMD_SCHEDULE_OVERRIDE` packet holding `brightness` for `minutes`, 0
+/// minutes going back to the schedule
+pub fn override_to_packet(brightness: u8, minutes: u16) -> Packet {
+    let minutes = minutes.to_le_bytes();
+
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_SCHEDULE_OVERRIDE.into());
+    packet.set_payload(&[brightness, minutes[0], minutes[1]]);
+    packet
+}
+
+/// Decodes a `CMD_SCHEDULE_OVERRIDE` payload into `(brightness, minutes)`
+pub fn override_from_payload(payload: &[u8]) -> Option<(u8, u16)> {
+    match payload {
+        [brightness, lo, hi] if *brightness <= MAX_BRIGHTNESS => Some((*brightness, u16::from_le_bytes([*lo, *hi]))),
+        _ => None,
+    }
+}
+
+/// Lowers the brightness of `frame` to `ceiling` if it is brighter
+pub fn apply(ceiling: u8, frame: &mut Frame) {
+    if frame.brightness() > ceiling {
+        frame.set_brightness(ceiling);
+    }
+}
+
+#[test]
+fn test_schedule() {
+    let mut schedule = Schedule::new();
+    schedule.default_brightness = 1;
+    // Weekdays 08:00 to 19:00 at full brightness, blank on weekend nights
+    schedule.windows.push(Window::new(WEEKDAYS, 8 * 60, 19 * 60, 7)).unwrap();
+    schedule.windows.push(Window::new(WEEKEND, 22 * 60, 7 * 60, 0)).unwrap();
+
+    // 2024-03-01 was a Friday
+    let friday = 1_709_251_200;
+    let at = |secs: u64| schedule.brightness_at(&DateTime::from_epoch_secs(secs));
+    assert_eq!(at(friday + 8 * 3600), 7);
+    assert_eq!(at(friday + 19 * 3600), 1);
+    // Saturday 23:00 and Sunday 03:00
+    assert_eq!(at(friday + 47 * 3600), 0);
+    assert_eq!(at(friday + 51 * 3600), 0);
+    // Saturday 03:00, the window started on Friday which is not in it
+    assert_eq!(at(friday + 27 * 3600), 1);
+
+    let decoded = Schedule::from_bytes(schedule.to_packet().get_payload()).unwrap();
+    assert_eq!(decoded, schedule);
+    assert_eq!(Schedule::from_bytes(&[8]), None);
+
+    let mut frame = Frame::new();
+    apply(3, &mut frame);
+    assert_eq!(frame.brightness(), 3);
+
+    let mut clock = WallClock::new();
+    let mut dimmer = Dimmer::new(schedule);
+    assert_eq!(dimmer.brightness(&clock, 0), MAX_BRIGHTNESS);
+    clock.set(friday + 20 * 3600, 0);
+    assert_eq!(dimmer.brightness(&clock, 0), 1);
+    dimmer.hold(5, 1, 1000);
+    assert_eq!(dimmer.brightness(&clock, 60_999), 5);
+    assert_eq!(dimmer.brightness(&clock, 61_000), 1);
+}
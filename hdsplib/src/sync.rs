@@ -0,0 +1,345 @@
+//! Commands applied at a given time of the device, so that several devices
+//! driven together change at once.
+//!
+//! Each device counts time in microseconds since it booted. The host maps
+//! its own clock to it with `CMD_TIME_REQUEST [host time]`, which the device
+//! answers right away with `CMD_TIME_REPLY [host time, device time]`. The
+//! device time falls within the round trip, `ClockSync` keeps the exchanges
+//! with the shortest ones, whose estimate is the closest. The clocks drift
+//! apart slowly, the host syncs again from time to time.
+//!
+//! `CMD_AT [device time, command, payload...]` queues a command until the
+//! device time comes, applying it right away if it has passed. Wrapping
+//! `CMD_BATCH [COMMIT]`, it queues the commands of the batch open, see
+//! `batch`. Commands due at the same time are applied before the same frame.
+//! The commands of a batch are handed out together, to be applied whole or
+//! dropped. `CMD_AT` itself is never staged in a batch.
+
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+
+use crate::batch::Staged;
+use crate::packet::{Command, Packet, MAX_PACKET_PAYLOAD_SIZE};
+
+/// Exchanges `ClockSync` keeps, the older ones are dropped
+pub const SYNC_SAMPLES: usize = 8;
+/// Device time before the command of a `CMD_AT` payload
+const AT_HEADER_SIZE: usize = 8 + 1;
+/// Time, flags, command and little endian length before each queued payload
+const RECORD_HEADER_SIZE: usize = 8 + 1 + 1 + 2;
+/// Set on the first record of each command or batch queued
+const RECORD_FIRST: u8 = 0x01;
+
+/// `CMD_TIME_REQUEST` packet, `host_us` being sent back as is
+pub fn time_request_to_packet(host_us: u64) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_TIME_REQUEST.into());
+    packet.set_payload(&host_us.to_le_bytes());
+    packet
+}
+
+pub fn time_request_from_payload(payload: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(payload.try_into().ok()?))
+}
+
+/// Answer of the device to `CMD_TIME_REQUEST`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeReply {
+    /// Time of the host sent with the request
+    pub host_us: u64,
+    /// Time of the device when it answered
+    pub device_us: u64,
+}
+
+impl TimeReply {
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = [0; 16];
+        payload[..8].copy_from_slice(&self.host_us.to_le_bytes());
+        payload[8..].copy_from_slice(&self.device_us.to_le_bytes());
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_TIME_REPLY.into());
+        packet.set_payload(&payload);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() != 16 {
+            return None;
+        }
+        Some(Self {
+            host_us: u64::from_le_bytes(payload[..8].try_into().ok()?),
+            device_us: u64::from_le_bytes(payload[8..].try_into().ok()?),
+        })
+    }
+}
+
+/// `CMD_AT` packet applying `command` at `device_us`, `None` if the payload
+/// does not fit
+pub fn at_to_packet(device_us: u64, command: u8, payload: &[u8]) -> Option<Packet> {
+    let mut buf = [0; MAX_PACKET_PAYLOAD_SIZE];
+    let size = AT_HEADER_SIZE + payload.len();
+    buf.get_mut(AT_HEADER_SIZE..size)?.copy_from_slice(payload);
+    buf[..8].copy_from_slice(&device_us.to_le_bytes());
+    buf[8] = command;
+
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_AT.into());
+    packet.set_payload(&buf[..size]);
+    Some(packet)
+}
+
+/// Decodes a `CMD_AT` payload into the device time, the command and its
+/// payload
+pub fn at_from_payload(payload: &[u8]) -> Option<(u64, u8, &[u8])> {
+    let (header, rest) = payload.split_at_checked(AT_HEADER_SIZE)?;
+    Some((u64::from_le_bytes(header[..8].try_into().ok()?), header[8], rest))
+}
+
+/// One request answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSample {
+    pub round_trip_us: u64,
+    /// Device time minus host time
+    pub offset_us: i64,
+}
+
+/// Estimates the offset from the host clock to the clock of a device
+pub struct ClockSync {
+    samples: Deque<TimeSample, SYNC_SAMPLES>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self { samples: Deque::new() }
+    }
+
+    /// Adds the answer to a request, received at `received_us` of the host.
+    /// The device time is taken in the middle of the round trip.
+    pub fn add(&mut self, reply: &TimeReply, received_us: u64) -> Option<TimeSample> {
+        let round_trip_us = received_us.checked_sub(reply.host_us)?;
+        let middle_us = reply.host_us + round_trip_us / 2;
+        let sample = TimeSample {
+            round_trip_us,
+            offset_us: reply.device_us.wrapping_sub(middle_us) as i64,
+        };
+
+        if self.samples.is_full() {
+            self.samples.pop_front();
+        }
+        let _ = self.samples.push_back(sample);
+        Some(sample)
+    }
+
+    /// The sample with the shortest round trip, its error is at most half of
+    /// it
+    pub fn best(&self) -> Option<TimeSample> {
+        self.samples.iter().min_by_key(|sample| sample.round_trip_us).copied()
+    }
+
+    /// Device time at `host_us`
+    pub fn to_device(&self, host_us: u64) -> Option<u64> {
+        Some(host_us.wrapping_add_signed(self.best()?.offset_us))
+    }
+
+    /// Host time at `device_us`
+    pub fn to_host(&self, device_us: u64) -> Option<u64> {
+        Some(device_us.wrapping_add_signed(self.best()?.offset_us.wrapping_neg()))
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Commands waiting for their time on the device, `N` bytes of them, kept in
+/// time order
+pub struct Timeline<const N: usize> {
+    bytes: heapless::Vec<u8, N>,
+}
+
+impl<const N: usize> Timeline<N> {
+    pub fn new() -> Self {
+        Self { bytes: heapless::Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+
+    /// Iterates over the `(offset, device time, flags, command, payload)`
+    /// records
+    fn records(&self) -> impl Iterator<Item = (usize, u64, u8, u8, &[u8])> {
+        let mut pos = 0;
+        core::iter::from_fn(move || {
+            let header = self.bytes.get(pos..pos + RECORD_HEADER_SIZE)?;
+            let device_us = u64::from_le_bytes(header[..8].try_into().ok()?);
+            let len = u16::from_le_bytes([header[10], header[11]]) as usize;
+            let payload = self.bytes.get(pos + RECORD_HEADER_SIZE..pos + RECORD_HEADER_SIZE + len)?;
+            let record = (pos, device_us, header[8], header[9], payload);
+            pos += RECORD_HEADER_SIZE + len;
+            Some(record)
+        })
+    }
+
+    /// Time the next command is due
+    pub fn next_due(&self) -> Option<u64> {
+        self.records().next().map(|(_, device_us, _, _, _)| device_us)
+    }
+
+    /// Queues a command for `device_us`, after the ones due at the same time.
+    /// Returns false if it does not fit.
+    pub fn push(&mut self, device_us: u64, command: u8, payload: &[u8]) -> bool {
+        self.insert(device_us, RECORD_FIRST, command, payload)
+    }
+
+    fn insert(&mut self, device_us: u64, flags: u8, command: u8, payload: &[u8]) -> bool {
+        let size = RECORD_HEADER_SIZE + payload.len();
+        if payload.len() > u16::MAX as usize || self.bytes.len() + size > N {
+            return false;
+        }
+
+        let pos = self
+            .records()
+            .find(|(_, due_us, _, _, _)| *due_us > device_us)
+            .map_or(self.bytes.len(), |(pos, _, _, _, _)| pos);
+        let len = (payload.len() as u16).to_le_bytes();
+
+        // Room is made at the end, and moved in place
+        let end = self.bytes.len() + size;
+        if self.bytes.resize(end, 0).is_err() {
+            return false;
+        }
+        self.bytes[pos..].rotate_right(size);
+        let record = &mut self.bytes[pos..pos + size];
+        record[..8].copy_from_slice(&device_us.to_le_bytes());
+        record[8..RECORD_HEADER_SIZE].copy_from_slice(&[flags, command, len[0], len[1]]);
+        record[RECORD_HEADER_SIZE..].copy_from_slice(payload);
+        true
+    }
+
+    /// Queues the commands of a batch for `device_us`, all of them or none.
+    /// Returns false if they do not fit.
+    pub fn push_staged<const M: usize>(&mut self, device_us: u64, staged: &Staged<M>) -> bool {
+        let size: usize = staged.iter().map(|(_, payload)| RECORD_HEADER_SIZE + payload.len()).sum();
+        if self.bytes.len() + size > N {
+            return false;
+        }
+        // Records queued at the same time stay next to each other
+        let mut flags = RECORD_FIRST;
+        staged.iter().all(|(command, payload)| {
+            let inserted = self.insert(device_us, flags, command, payload);
+            flags = 0;
+            inserted
+        })
+    }
+
+    /// Takes out the next command due at `now_us`, or all the commands of the
+    /// next batch due. To be called until it returns `None`.
+    pub fn take_due(&mut self, now_us: u64) -> Option<Staged<N>> {
+        let mut due = Staged::new();
+        let mut end = 0;
+        for (pos, device_us, flags, command, payload) in self.records() {
+            if device_us > now_us || (end > 0 && flags & RECORD_FIRST != 0) {
+                break;
+            }
+            // Smaller than the records, always fits
+            due.push(command, payload);
+            end = pos + RECORD_HEADER_SIZE + payload.len();
+        }
+
+        if end == 0 {
+            return None;
+        }
+        let len = self.bytes.len();
+        self.bytes.copy_within(end.., 0);
+        self.bytes.truncate(len - end);
+        Some(due)
+    }
+}
+
+impl<const N: usize> Default for Timeline<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_sync() {
+    assert_eq!(time_request_from_payload(time_request_to_packet(1234).get_payload()), Some(1234));
+    let reply = TimeReply {
+        host_us: 1_000_000,
+        device_us: 5_000_400,
+    };
+    assert_eq!(TimeReply::from_payload(reply.to_packet().get_payload()), Some(reply));
+
+    let packet = at_to_packet(7_000_000, Command::CMD_SET_TEXT.into(), b"HELLO").unwrap();
+    assert_eq!(
+        at_from_payload(packet.get_payload()),
+        Some((7_000_000, Command::CMD_SET_TEXT.into(), &b"HELLO"[..]))
+    );
+    assert!(at_to_packet(0, 0x14, &[0x41; MAX_PACKET_PAYLOAD_SIZE]).is_none());
+    assert_eq!(at_from_payload(&[1, 2, 3]), None);
+
+    // The device is 4 s ahead, the round trips are uneven and the shortest
+    // one wins
+    let mut sync = ClockSync::new();
+    assert_eq!(sync.to_device(0), None);
+    sync.add(&TimeReply { host_us: 1_000_000, device_us: 5_000_900 }, 1_003_000);
+    sync.add(&TimeReply { host_us: 2_000_000, device_us: 6_000_300 }, 2_000_600);
+    sync.add(&TimeReply { host_us: 3_000_000, device_us: 7_002_000 }, 3_002_500);
+    assert_eq!(sync.best(), Some(TimeSample { round_trip_us: 600, offset_us: 4_000_000 }));
+    assert_eq!(sync.to_device(10_000_000), Some(14_000_000));
+    assert_eq!(sync.to_host(14_000_000), Some(10_000_000));
+    assert_eq!(sync.add(&reply, 999_999), None);
+    for i in 0..SYNC_SAMPLES as u64 {
+        sync.add(&TimeReply { host_us: i, device_us: i + 10_000 }, i + 1000);
+    }
+    assert_eq!(sync.best().map(|s| s.round_trip_us), Some(1000));
+
+    let mut timeline = Timeline::<64>::new();
+    assert!(timeline.push(300, 0x14, b"C"));
+    assert!(timeline.push(100, 0x14, b"A"));
+    assert!(timeline.push(300, 0x1A, &[]));
+    assert!(timeline.push(200, 0x14, b"B"));
+    assert_eq!(timeline.next_due(), Some(100));
+    assert!(timeline.take_due(99).is_none());
+
+    let commands = |due: Option<Staged<64>>| -> heapless::Vec<(u8, heapless::Vec<u8, 4>), 4> {
+        due.unwrap().iter().map(|(command, payload)| (command, heapless::Vec::from_slice(payload).unwrap())).collect()
+    };
+    assert_eq!(commands(timeline.take_due(200)), [(0x14, heapless::Vec::from_slice(b"A").unwrap())]);
+    assert_eq!(commands(timeline.take_due(200)), [(0x14, heapless::Vec::from_slice(b"B").unwrap())]);
+    assert!(timeline.take_due(200).is_none());
+    // Same time, in the order they came
+    assert_eq!(commands(timeline.take_due(1000)), [(0x14, heapless::Vec::from_slice(b"C").unwrap())]);
+    assert_eq!(commands(timeline.take_due(1000)), [(0x1A, heapless::Vec::new())]);
+    assert!(timeline.is_empty());
+
+    // A batch is queued whole or not at all, and handed out whole
+    let mut staged = Staged::<64>::new();
+    staged.push(0x14, &[0x41; 20]);
+    staged.push(0x18, &[0x81]);
+    assert!(timeline.push_staged(500, &staged));
+    assert!(!timeline.push_staged(600, &staged));
+    assert_eq!(timeline.take_due(600).map(|due| due.iter().count()), Some(2));
+
+    let mut staged = Staged::<64>::new();
+    staged.push(0x1A, &[]);
+    staged.push(0x18, &[0x81]);
+    assert!(timeline.push(700, 0x14, b"D"));
+    assert!(timeline.push_staged(700, &staged));
+    assert!(timeline.push(700, 0x14, b"E"));
+    assert_eq!(timeline.take_due(700).map(|due| due.iter().count()), Some(1));
+    let due = timeline.take_due(700).unwrap();
+    let commands: heapless::Vec<(u8, &[u8]), 4> = due.iter().collect();
+    assert_eq!(&commands[..], &[(0x1A, &[][..]), (0x18, &[0x81][..])]);
+    assert_eq!(timeline.take_due(700).map(|due| due.iter().count()), Some(1));
+    assert!(timeline.is_empty());
+}
@@ -0,0 +1,291 @@
+//! Text templates with variables evaluated on the device.
+//!
+//! A template is a markup message where placeholders between braces are
+//! replaced by their current value before the markup is parsed:
+//!
+//! - `{hh}`, `{mm}`, `{ss}`: time of day
+//! - `{dd}`, `{mo}`, `{yy}`, `{yyyy}`, `{wd}`: date and weekday name
+//! - `{temp}`: internal temperature in degrees Celsius
+//! - `{c0}` to `{c7}`: counters set and incremented by the host
+//! - `{up}`, `{upm}`, `{uph}`: uptime in seconds, minutes and hours
+//!
+//! A placeholder can be followed by a width, `{up:6}` pads with spaces and
+//! `{up:06}` with zeros. Any other tag is left for the markup parser.
+
+use core::fmt::Write;
+
+use crate::markup::{self, Markup, MarkupError, MarkupErrorKind};
+use crate::time::{WallClock, SECS_PER_HOUR, SECS_PER_MINUTE};
+
+/// Maximum length of a template and of its evaluated text
+pub const MAX_TEMPLATE_LEN: usize = 128;
+/// Number of host counters
+pub const NUM_COUNTERS: usize = 8;
+/// Period at which templates are evaluated again
+pub const TEMPLATE_TICK_MS: u64 = 250;
+
+/// `CMD_COUNTER` operation setting the counter
+pub const COUNTER_SET: u8 = 0;
+/// `CMD_COUNTER` operation adding to the counter
+pub const COUNTER_ADD: u8 = 1;
+
+type Text = heapless::String<MAX_TEMPLATE_LEN>;
+
+/// Values of the template variables at evaluation time
+pub struct Vars<'a> {
+    pub clock: &'a WallClock,
+    pub counters: &'a [i32; NUM_COUNTERS],
+    pub temp_c: i32,
+    pub now_ms: u64,
+}
+
+#[derive(Clone)]
+pub struct TemplateEngine {
+    template: Option<Text>,
+    /// Last evaluated text, to only update the display on changes
+    rendered: Text,
+    next_tick_ms: u64,
+    pub clock: WallClock,
+    pub counters: [i32; NUM_COUNTERS],
+}
+
+impl TemplateEngine {
+    pub fn new() -> Self {
+        Self {
+            template: None,
+            rendered: Text::new(),
+            next_tick_ms: 0,
+            clock: WallClock::new(),
+            counters: [0; NUM_COUNTERS],
+        }
+    }
+
+    /// Replaces the template and returns its first evaluation. The template
+    /// is rejected if it does not evaluate to valid markup, the error position
+    /// is then relative to the evaluated text.
+    pub fn set(&mut self, template: &str, temp_c: i32, now_ms: u64) -> Result<Markup, MarkupError> {
+        let too_long = MarkupError {
+            pos: MAX_TEMPLATE_LEN,
+            kind: MarkupErrorKind::TooManyGlyphs,
+        };
+        let source = Text::try_from(template).map_err(|_| too_long)?;
+
+        let rendered = self.render(&source, temp_c, now_ms)?;
+        let markup = markup::parse(&rendered)?;
+
+        self.template = Some(source);
+        self.rendered = rendered;
+        self.next_tick_ms = now_ms + TEMPLATE_TICK_MS;
+        Ok(markup)
+    }
+
+    /// Stops evaluating the template, when the host sends a plain text
+    pub fn clear(&mut self) {
+        self.template = None;
+    }
+
+    pub fn update_counter(&mut self, index: usize, op: u8, value: i32) -> bool {
+        let Some(counter) = self.counters.get_mut(index) else {
+            return false;
+        };
+
+        match op {
+            COUNTER_SET => *counter = value,
+            COUNTER_ADD => *counter = counter.wrapping_add(value),
+            _ => return false,
+        }
+
+        // Show the new value right away
+        self.next_tick_ms = 0;
+        true
+    }
+
+    /// Evaluates the template again if it is due. Returns the new markup if
+    /// the text changed.
+    pub fn tick(&mut self, temp_c: i32, now_ms: u64) -> Option<Markup> {
+        if now_ms < self.next_tick_ms {
+            return None;
+        }
+        self.next_tick_ms = now_ms + TEMPLATE_TICK_MS;
+
+        let source = self.template.clone()?;
+        let rendered = self.render(&source, temp_c, now_ms).ok()?;
+        if rendered == self.rendered {
+            return None;
+        }
+
+        let markup = markup::parse(&rendered).ok()?;
+        self.rendered = rendered;
+        Some(markup)
+    }
+
+    fn render(&self, source: &str, temp_c: i32, now_ms: u64) -> Result<Text, MarkupError> {
+        let vars = Vars {
+            clock: &self.clock,
+            counters: &self.counters,
+            temp_c,
+            now_ms,
+        };
+        render(source, &vars)
+    }
+}
+
+/// Replaces the placeholders of `source` with the values of `vars`
+pub fn render(source: &str, vars: &Vars) -> Result<Text, MarkupError> {
+    let mut out = Text::new();
+    let mut rest = source;
+
+    while let Some(start) = rest.find('{') {
+        let pos = source.len() - rest.len() + start;
+        let overflow = MarkupError {
+            pos,
+            kind: MarkupErrorKind::TooManyGlyphs,
+        };
+
+        out.push_str(&rest[..start]).map_err(|_| overflow)?;
+        rest = &rest[start..];
+
+        // Escaped braces are left for the markup parser
+        if rest.starts_with("{{") {
+            out.push_str("{{").map_err(|_| overflow)?;
+            rest = &rest[2..];
+            continue;
+        }
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let tag = &rest[1..end];
+        let (name, spec) = tag.split_once(':').unwrap_or((tag, ""));
+
+        match write_var(&mut out, name, spec, vars) {
+            Some(result) => result.map_err(|_| overflow)?,
+            // Not a variable, it is markup
+            None => out.push_str(&rest[..=end]).map_err(|_| overflow)?,
+        }
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest).map_err(|_| MarkupError {
+        pos: source.len() - rest.len(),
+        kind: MarkupErrorKind::TooManyGlyphs,
+    })?;
+    Ok(out)
+}
+
+/// Writes variable `name` formatted with `spec`. Returns `None` if `name`
+/// is not a variable.
+fn write_var(out: &mut Text, name: &str, spec: &str, vars: &Vars) -> Option<core::fmt::Result> {
+    let now = vars.clock.now(vars.now_ms);
+    let uptime_s = vars.now_ms / 1000;
+
+    // Calendar fields are two digits wide and zero padded unless told otherwise
+    let (value, default_width): (Option<i64>, usize) = match name {
+        "hh" => (now.map(|t| t.hour as i64), 2),
+        "mm" => (now.map(|t| t.minute as i64), 2),
+        "ss" => (now.map(|t| t.second as i64), 2),
+        "dd" => (now.map(|t| t.day as i64), 2),
+        "mo" => (now.map(|t| t.month as i64), 2),
+        "yy" => (now.map(|t| t.year as i64 % 100), 2),
+        "yyyy" => (now.map(|t| t.year as i64), 4),
+        "wd" => {
+            let name = now.map(|t| t.weekday_name()).unwrap_or("---");
+            return Some(out.write_str(name));
+        }
+        "temp" => (Some(vars.temp_c as i64), 0),
+        "up" => (Some(uptime_s as i64), 0),
+        "upm" => (Some((uptime_s / SECS_PER_MINUTE) as i64), 0),
+        "uph" => (Some((uptime_s / SECS_PER_HOUR) as i64), 0),
+        _ => {
+            let index = name.strip_prefix('c')?.parse::<usize>().ok()?;
+            (Some(*vars.counters.get(index)? as i64), 0)
+        }
+    };
+
+    let (zero_pad, width) = match spec {
+        "" => (default_width > 0, default_width),
+        _ => (spec.starts_with('0'), spec.parse::<usize>().ok()?),
+    };
+
+    let result = match value {
+        Some(value) if zero_pad => write!(out, "{:0width$}", value, width = width),
+        Some(value) => write!(out, "{:width$}", value, width = width),
+        // The clock was never set
+        None => (0..width.max(1)).try_for_each(|_| out.write_char('-')),
+    };
+    Some(result)
+}
+
+impl Default for TemplateEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_template() {
+    let mut clock = WallClock::new();
+    let mut counters = [0; NUM_COUNTERS];
+    counters[2] = -7;
+    let vars = Vars {
+        clock: &clock,
+        counters: &counters,
+        temp_c: 23,
+        now_ms: 3_725_000,
+    };
+
+    // Clock not set yet, its fields show as dashes
+    assert_eq!(render("{hh}:{mm} {wd}", &vars).unwrap(), "--:-- ---");
+    assert_eq!(render("{temp}C {c2} {up:6}|{upm:03} {uph}", &vars).unwrap(), "23C -7   3725|062 1");
+    // Markup tags and escaped braces are kept for the parser
+    assert_eq!(render("{flash}{c9}{/flash} {{x}", &vars).unwrap(), "{flash}{c9}{/flash} {{x}");
+    assert_eq!(render("{up:x}", &vars).unwrap(), "{up:x}");
+
+    // 2024-02-29 23:59:58
+    clock.set(1_709_251_198, 0);
+    let vars = Vars {
+        clock: &clock,
+        counters: &counters,
+        temp_c: 23,
+        now_ms: 1000,
+    };
+    assert_eq!(render("{dd}/{mo}/{yy} {hh}:{mm}:{ss} {wd}", &vars).unwrap(), "29/02/24 23:59:59 Thu");
+    assert_eq!(render("{yyyy}", &vars).unwrap(), "2024");
+
+    let long = [b'a'; MAX_TEMPLATE_LEN];
+    let long = core::str::from_utf8(&long).unwrap();
+    assert_eq!(render(long, &vars).unwrap().len(), MAX_TEMPLATE_LEN);
+    // The value of a placeholder not fitting fails at the placeholder
+    let mut text = heapless::String::<{ MAX_TEMPLATE_LEN + 8 }>::new();
+    text.push_str(&long[4..]).unwrap();
+    text.push_str("{temp}").unwrap();
+    let error = render(&text, &Vars { temp_c: 10_000, ..vars }).unwrap_err();
+    assert_eq!((error.pos, error.kind), (MAX_TEMPLATE_LEN - 4, MarkupErrorKind::TooManyGlyphs));
+}
+
+#[test]
+fn test_template_engine() {
+    let mut engine = TemplateEngine::new();
+    assert!(engine.tick(20, 0).is_none());
+    assert!(engine.set("{bold}", 20, 0).is_err());
+
+    let markup = engine.set("{temp}C", 20, 0).unwrap();
+    assert_eq!(markup.glyphs.len(), 3);
+    // Only evaluated again once due, and only returned when it changed
+    assert!(engine.tick(21, TEMPLATE_TICK_MS - 1).is_none());
+    assert!(engine.tick(20, TEMPLATE_TICK_MS).is_none());
+    assert!(engine.tick(21, 2 * TEMPLATE_TICK_MS).is_some());
+
+    engine.set("{c1}", 20, 1000).unwrap();
+    assert!(engine.update_counter(1, COUNTER_ADD, 5));
+    assert!(engine.update_counter(1, COUNTER_ADD, 5));
+    assert_eq!(engine.counters[1], 10);
+    assert!(engine.tick(20, 1001).is_some());
+    assert!(engine.update_counter(1, COUNTER_SET, -1));
+    assert_eq!(engine.counters[1], -1);
+    assert!(!engine.update_counter(NUM_COUNTERS, COUNTER_SET, 0));
+    assert!(!engine.update_counter(0, 2, 0));
+
+    engine.clear();
+    assert!(engine.tick(20, 5000).is_none());
+}
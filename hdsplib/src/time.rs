@@ -0,0 +1,122 @@
+//! Calendar time for the device clock.
+//!
+//! The device has no battery backed clock. The host sets it with the number
+//! of seconds since 1970-01-01 00:00:00 in the local time zone, and the device
+//! keeps it running from its timer.
+
+pub const SECS_PER_MINUTE: u64 = 60;
+pub const SECS_PER_HOUR: u64 = 60 * SECS_PER_MINUTE;
+pub const SECS_PER_DAY: u64 = 24 * SECS_PER_HOUR;
+/// 9999-12-31 23:59:59, the last time the clock can be set to
+pub const MAX_EPOCH_SECS: u64 = 253_402_300_799;
+
+/// Three letter weekday names, Monday first
+pub const WEEKDAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 is Monday, 6 is Sunday
+    pub weekday: u8,
+}
+
+impl DateTime {
+    pub fn from_epoch_secs(secs: u64) -> Self {
+        let days = secs / SECS_PER_DAY;
+        let secs_of_day = secs % SECS_PER_DAY;
+
+        // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / SECS_PER_HOUR) as u8,
+            minute: (secs_of_day % SECS_PER_HOUR / SECS_PER_MINUTE) as u8,
+            second: (secs_of_day % SECS_PER_MINUTE) as u8,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 3) % 7) as u8,
+        }
+    }
+
+    /// Seconds since midnight
+    pub fn secs_of_day(&self) -> u32 {
+        self.hour as u32 * SECS_PER_HOUR as u32
+            + self.minute as u32 * SECS_PER_MINUTE as u32
+            + self.second as u32
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        WEEKDAY_NAMES[self.weekday as usize]
+    }
+}
+
+/// Wall clock running from a monotonic millisecond counter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock {
+    /// Epoch time in ms at counter 0, `None` until the clock is set
+    offset_ms: Option<u64>,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self { offset_ms: None }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.offset_ms.is_some()
+    }
+
+    /// Sets the clock, times past `MAX_EPOCH_SECS` being taken as it
+    pub fn set(&mut self, epoch_secs: u64, now_ms: u64) {
+        self.offset_ms = Some((epoch_secs.min(MAX_EPOCH_SECS) * 1000).saturating_sub(now_ms));
+    }
+
+    pub fn epoch_secs(&self, now_ms: u64) -> Option<u64> {
+        self.offset_ms.map(|offset| offset.saturating_add(now_ms) / 1000)
+    }
+
+    pub fn now(&self, now_ms: u64) -> Option<DateTime> {
+        self.epoch_secs(now_ms).map(DateTime::from_epoch_secs)
+    }
+}
+
+#[test]
+fn test_date_time() {
+    let t = DateTime::from_epoch_secs(0);
+    assert_eq!((t.year, t.month, t.day, t.weekday_name()), (1970, 1, 1, "Thu"));
+
+    // 2024-02-29 23:59:58, a leap day
+    let t = DateTime::from_epoch_secs(1_709_251_198);
+    assert_eq!((t.year, t.month, t.day), (2024, 2, 29));
+    assert_eq!((t.hour, t.minute, t.second), (23, 59, 58));
+    assert_eq!(t.weekday_name(), "Thu");
+
+    let mut clock = WallClock::new();
+    assert!(clock.now(5000).is_none());
+    clock.set(1_709_251_198, 5000);
+    let t = clock.now(7500).unwrap();
+    assert_eq!((t.year, t.month, t.day, t.hour, t.second), (2024, 3, 1, 0, 0));
+    assert_eq!(t.weekday_name(), "Fri");
+
+    // Times from the host cannot overflow the clock
+    clock.set(u64::MAX, 0);
+    assert_eq!(clock.now(0).unwrap().year, 9999);
+    assert_eq!(clock.epoch_secs(u64::MAX), Some(u64::MAX / 1000));
+}
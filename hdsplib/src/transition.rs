@@ -0,0 +1,450 @@
+//! Transition effects between two messages.
+//!
+//! A transition mixes a snapshot of the old frame into the new one while it
+//! runs. Effects that need pixels from both messages in the same digit draw
+//! that digit through a UDC slot the new frame does not show, borrowed for
+//! the duration of the transition, so the UDCs of widgets, animations and the
+//! message are left alone.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{self, Frame, UdcBitmap, NUM_DIGITS, NUM_UDC, UDC_COLUMNS, UDC_FLAG, UDC_ROWS};
+use crate::packet::{Command, Packet};
+use crate::random::LCG;
+
+/// Progress of a transition from 0 to `PROGRESS_MAX`
+pub const PROGRESS_MAX: u32 = 1000;
+
+/// Delay between the start of the roll of two consecutive digits
+const ROLL_STAGGER: u32 = PROGRESS_MAX / 16;
+/// Duration of the roll of a single digit
+const ROLL_DIGIT: u32 = PROGRESS_MAX - ROLL_STAGGER * (NUM_DIGITS as u32 - 1);
+
+const PIXELS_PER_DIGIT: usize = UDC_ROWS * UDC_COLUMNS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransitionKind {
+    /// The new message replaces the old one at once
+    None = 0,
+    /// The new message is revealed from the left
+    WipeLeft = 1,
+    /// The new message is revealed from the right
+    WipeRight = 2,
+    /// Every digit rolls down like a slot machine reel
+    Roll = 3,
+    /// Pixels switch to the new message in random order
+    Dissolve = 4,
+    /// The old message fades out and the new one fades in
+    Fade = 5,
+}
+
+impl TryFrom<u8> for TransitionKind {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TransitionKind::None),
+            1 => Ok(TransitionKind::WipeLeft),
+            2 => Ok(TransitionKind::WipeRight),
+            3 => Ok(TransitionKind::Roll),
+            4 => Ok(TransitionKind::Dissolve),
+            5 => Ok(TransitionKind::Fade),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Easing {
+    Linear = 0,
+    /// Starts slow
+    EaseIn = 1,
+    /// Ends slow
+    EaseOut = 2,
+    /// Starts and ends slow
+    EaseInOut = 3,
+}
+
+impl TryFrom<u8> for Easing {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Easing::Linear),
+            1 => Ok(Easing::EaseIn),
+            2 => Ok(Easing::EaseOut),
+            3 => Ok(Easing::EaseInOut),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Easing {
+    /// Eases a linear progress `t`, both from 0 to `PROGRESS_MAX`
+    pub fn apply(self, t: u32) -> u32 {
+        let t = t.min(PROGRESS_MAX);
+        let m = PROGRESS_MAX;
+
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t / m,
+            Easing::EaseOut => m - (m - t) * (m - t) / m,
+            Easing::EaseInOut if t < m / 2 => 2 * t * t / m,
+            Easing::EaseInOut => m - 2 * (m - t) * (m - t) / m,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransitionSpec {
+    pub kind: TransitionKind,
+    pub easing: Easing,
+    pub duration_ms: u16,
+}
+
+impl TransitionSpec {
+    pub const NONE: TransitionSpec = TransitionSpec {
+        kind: TransitionKind::None,
+        easing: Easing::Linear,
+        duration_ms: 0,
+    };
+
+    /// `CMD_SET_TRANSITION` packet. With `once` the transition is only used
+    /// for the next message, otherwise it is used for every message.
+    pub fn to_packet(&self, once: bool) -> Packet {
+        let duration = self.duration_ms.to_le_bytes();
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_SET_TRANSITION.into());
+        packet.set_payload(&[self.kind as u8, self.easing as u8, duration[0], duration[1], once as u8]);
+        packet
+    }
+
+    /// Decodes a `CMD_SET_TRANSITION` payload into `(spec, once)`
+    pub fn from_payload(payload: &[u8]) -> Option<(Self, bool)> {
+        match payload {
+            [kind, easing, lo, hi, once] => {
+                let spec = TransitionSpec {
+                    kind: TransitionKind::try_from(*kind).ok()?,
+                    easing: Easing::try_from(*easing).ok()?,
+                    duration_ms: u16::from_le_bytes([*lo, *hi]),
+                };
+                Some((spec, *once != 0))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A running transition
+pub struct Transition {
+    spec: TransitionSpec,
+    old: Frame,
+    start_ms: u64,
+    /// Progress at which each pixel switches to the new message, for `Dissolve`
+    thresholds: [u8; NUM_DIGITS * PIXELS_PER_DIGIT],
+}
+
+impl Transition {
+    pub fn new(spec: TransitionSpec, old: Frame, now_ms: u64, lcg: &mut LCG) -> Self {
+        let mut thresholds = [0; NUM_DIGITS * PIXELS_PER_DIGIT];
+        if spec.kind == TransitionKind::Dissolve {
+            for threshold in thresholds.iter_mut() {
+                *threshold = (lcg.next() >> 24) as u8;
+            }
+        }
+
+        Self {
+            spec,
+            old,
+            start_ms: now_ms,
+            thresholds,
+        }
+    }
+
+    /// Eased progress at `now_ms`
+    pub fn progress(&self, now_ms: u64) -> u32 {
+        let elapsed = now_ms.saturating_sub(self.start_ms);
+        if elapsed >= self.spec.duration_ms as u64 {
+            return PROGRESS_MAX;
+        }
+
+        let t = elapsed as u32 * PROGRESS_MAX / self.spec.duration_ms as u32;
+        self.spec.easing.apply(t)
+    }
+
+    pub fn is_done(&self, now_ms: u64) -> bool {
+        self.progress(now_ms) >= PROGRESS_MAX
+    }
+
+    /// Mixes the old frame into `frame`, which holds the new message
+    pub fn apply(&self, frame: &mut Frame, now_ms: u64) {
+        let p = self.progress(now_ms);
+        if p >= PROGRESS_MAX {
+            return;
+        }
+
+        let new = *frame;
+        let old = &self.old;
+        let mut free = FreeUdcs::new(&new);
+
+        match self.spec.kind {
+            TransitionKind::None => {}
+            TransitionKind::WipeLeft | TransitionKind::WipeRight => {
+                let revealed = ((p * NUM_DIGITS as u32 + PROGRESS_MAX / 2) / PROGRESS_MAX) as usize;
+
+                for i in 0..NUM_DIGITS {
+                    let shows_new = match self.spec.kind {
+                        TransitionKind::WipeLeft => i < revealed,
+                        _ => i >= NUM_DIGITS - revealed,
+                    };
+                    if shows_new {
+                        continue;
+                    }
+
+                    // The slot of an old UDC may hold another bitmap now, draw it borrowing one
+                    let code = old.chars[i];
+                    if code & UDC_FLAG == 0 {
+                        frame.chars[i] = code;
+                    } else {
+                        show_bitmap(frame, i, old.bitmap(code), &mut free);
+                    }
+                    set_flash(frame, i, old.flash & (1 << i) != 0);
+                }
+            }
+            TransitionKind::Roll => {
+                for i in 0..NUM_DIGITS {
+                    let start = ROLL_STAGGER * i as u32;
+                    let local = (p.saturating_sub(start) * PROGRESS_MAX / ROLL_DIGIT).min(PROGRESS_MAX);
+                    // The new character comes from the top with a blank row in between
+                    let shift = (local * (UDC_ROWS as u32 + 1) / PROGRESS_MAX) as usize;
+
+                    let old_bitmap = old.bitmap(old.chars[i]);
+                    let new_bitmap = new.bitmap(new.chars[i]);
+                    let mut bitmap = hdsp::UDC_BLANK;
+                    for (row, data) in bitmap.iter_mut().enumerate() {
+                        // Rows of the reel: new character, blank row, old character
+                        let reel_row = UDC_ROWS + 1 - shift + row;
+                        *data = match reel_row {
+                            r if r < UDC_ROWS => new_bitmap[r],
+                            r if r == UDC_ROWS => 0x00,
+                            r => old_bitmap[r - UDC_ROWS - 1],
+                        };
+                    }
+
+                    show_bitmap(frame, i, bitmap, &mut free);
+                    set_flash(frame, i, false);
+                }
+            }
+            TransitionKind::Dissolve => {
+                let switched = p * 256 / PROGRESS_MAX;
+
+                for i in 0..NUM_DIGITS {
+                    let old_bitmap = old.bitmap(old.chars[i]);
+                    let new_bitmap = new.bitmap(new.chars[i]);
+                    let thresholds = &self.thresholds[i * PIXELS_PER_DIGIT..(i + 1) * PIXELS_PER_DIGIT];
+
+                    let mut bitmap = hdsp::UDC_BLANK;
+                    for (row, data) in bitmap.iter_mut().enumerate() {
+                        for column in 0..UDC_COLUMNS {
+                            let mask = hdsp::udc_column_mask(column);
+                            let source = if (thresholds[row * UDC_COLUMNS + column] as u32) < switched {
+                                new_bitmap[row]
+                            } else {
+                                old_bitmap[row]
+                            };
+                            *data |= source & mask;
+                        }
+                    }
+
+                    show_bitmap(frame, i, bitmap, &mut free);
+                    set_flash(frame, i, false);
+                }
+            }
+            TransitionKind::Fade => {
+                // Fade out the old message in the first half, fade in the new one in the second
+                let half = PROGRESS_MAX / 2;
+                if p < half {
+                    let level = old.brightness() as u32 * (half - p) / half;
+                    *frame = *old;
+                    frame.set_brightness(level as u8);
+                } else {
+                    let level = new.brightness() as u32 * (p - half) / half;
+                    frame.set_brightness(level as u8);
+                }
+            }
+        }
+    }
+}
+
+// A frame shows at most one slot per digit, the others are enough for every digit
+const _: () = assert!(NUM_UDC >= 2 * NUM_DIGITS);
+
+/// UDC slots no digit of a frame shows, highest first
+struct FreeUdcs(u16);
+
+impl FreeUdcs {
+    fn new(frame: &Frame) -> Self {
+        let mut free = u16::MAX;
+        for code in frame.chars.iter().filter(|code| *code & UDC_FLAG != 0) {
+            free &= !(1 << (code & 0x0F));
+        }
+        Self(free)
+    }
+
+    fn take(&mut self) -> usize {
+        let udc = NUM_UDC - 1 - self.0.leading_zeros() as usize;
+        self.0 &= !(1 << udc);
+        udc
+    }
+}
+
+/// Shows `bitmap` on digit `pos` through a borrowed UDC slot
+fn show_bitmap(frame: &mut Frame, pos: usize, bitmap: UdcBitmap, free: &mut FreeUdcs) {
+    let udc = free.take();
+    frame.udcs[udc] = bitmap;
+    frame.chars[pos] = hdsp::udc_char(udc as u8);
+}
+
+fn set_flash(frame: &mut Frame, pos: usize, flash: bool) {
+    if flash {
+        frame.flash |= 1 << pos;
+    } else {
+        frame.flash &= !(1 << pos);
+    }
+}
+
+/// Transition settings and the running transition, if any
+pub struct Transitions {
+    default: TransitionSpec,
+    /// Transition for the next message only
+    next: Option<TransitionSpec>,
+    active: Option<Transition>,
+    lcg: LCG,
+}
+
+impl Transitions {
+    pub fn new() -> Self {
+        Self {
+            default: TransitionSpec::NONE,
+            next: None,
+            active: None,
+            lcg: LCG::new(0x7A3C_91E5),
+        }
+    }
+
+    pub fn configure(&mut self, spec: TransitionSpec, once: bool) {
+        if once {
+            self.next = Some(spec);
+        } else {
+            self.default = spec;
+        }
+    }
+
+    /// Starts the transition from `old`, the frame shown before the new message
+    pub fn start(&mut self, old: Frame, now_ms: u64) {
+        let spec = self.next.take().unwrap_or(self.default);
+
+        self.active = match spec.kind {
+            TransitionKind::None => None,
+            _ if spec.duration_ms == 0 => None,
+            _ => Some(Transition::new(spec, old, now_ms, &mut self.lcg)),
+        };
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Mixes the running transition into `frame`, ending it when done
+    pub fn apply(&mut self, frame: &mut Frame, now_ms: u64) {
+        if let Some(transition) = &self.active {
+            if transition.is_done(now_ms) {
+                self.active = None;
+            } else {
+                transition.apply(frame, now_ms);
+            }
+        }
+    }
+}
+
+impl Default for Transitions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_transitions() {
+    let spec = TransitionSpec {
+        kind: TransitionKind::Roll,
+        easing: Easing::EaseInOut,
+        duration_ms: 800,
+    };
+    assert_eq!(TransitionSpec::from_payload(spec.to_packet(true).get_payload()), Some((spec, true)));
+
+    assert_eq!(Easing::EaseInOut.apply(0), 0);
+    assert_eq!(Easing::EaseInOut.apply(500), 500);
+    assert_eq!(Easing::EaseIn.apply(PROGRESS_MAX), PROGRESS_MAX);
+
+    let mut old = Frame::new();
+    old.put_str(0, b"AAAAAAAA");
+    let mut new = Frame::new();
+    new.put_str(0, b"BBBBBBBB");
+
+    let mut transitions = Transitions::new();
+    transitions.configure(
+        TransitionSpec {
+            kind: TransitionKind::WipeLeft,
+            easing: Easing::Linear,
+            duration_ms: 800,
+        },
+        true,
+    );
+    transitions.start(old, 1000);
+
+    let mut frame = new;
+    transitions.apply(&mut frame, 1300);
+    assert_eq!(&frame.chars, b"BBBAAAAA");
+
+    // Rolling starts with the old character on every digit
+    let mut lcg = LCG::new(1);
+    let roll = Transition::new(spec, old, 0, &mut lcg);
+    let mut frame = new;
+    roll.apply(&mut frame, 0);
+    assert_eq!(frame.chars[0], hdsp::udc_char(15));
+    assert_eq!(frame.chars[7], hdsp::udc_char(8));
+    assert_eq!(frame.udcs[8], crate::font::rom_bitmap(b'A'));
+
+    // UDCs the new frame shows are never borrowed
+    let mut shown = new;
+    for (i, code) in shown.chars.iter_mut().enumerate() {
+        *code = hdsp::udc_char(2 * i as u8 + 1);
+        shown.udcs[2 * i + 1] = [0x1F; UDC_ROWS];
+    }
+    let mut frame = shown;
+    roll.apply(&mut frame, 0);
+    assert_eq!(frame.chars, [14, 12, 10, 8, 6, 4, 2, 0].map(hdsp::udc_char));
+    assert!((0..NUM_DIGITS).all(|i| frame.udcs[2 * i + 1] == [0x1F; UDC_ROWS]));
+
+    // Wiping draws the old UDCs through borrowed slots, the new ones as they are
+    let mut wiped = old;
+    wiped.chars[0] = hdsp::udc_char(1);
+    wiped.udcs[1] = crate::font::rom_bitmap(b'Z');
+    let wipe = Transition::new(TransitionSpec { kind: TransitionKind::WipeRight, easing: Easing::Linear, ..spec }, wiped, 0, &mut lcg);
+    let mut frame = shown;
+    wipe.apply(&mut frame, 700);
+    assert_eq!(frame.chars[0], hdsp::udc_char(14));
+    assert_eq!(frame.chars[1..], shown.chars[1..]);
+    assert_eq!(frame.udcs[14], crate::font::rom_bitmap(b'Z'));
+    assert_eq!(frame.udcs[1], [0x1F; UDC_ROWS]);
+
+    let mut frame = new;
+    transitions.apply(&mut frame, 1800);
+    assert_eq!(frame, new);
+    assert!(!transitions.is_active());
+
+    // The "once" transition is gone, the default one is none
+    transitions.start(old, 2000);
+    assert!(!transitions.is_active());
+}
@@ -0,0 +1,395 @@
+//! Bar graph, gauge and sparkline widgets drawn with UDCs.
+//!
+//! A widget owns a range of digits of the display, optionally starting with an
+//! ASCII label, and a range of UDC slots starting at `udc_base`. The host sends
+//! the configuration once and then only the value with `CMD_WIDGET_VALUE`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::hdsp::{self, Frame, UdcBitmap, NUM_DIGITS, NUM_UDC, UDC_COLUMNS, UDC_ROWS};
+use crate::packet::{Command, Packet};
+
+/// Number of widgets that can be shown at the same time
+pub const MAX_WIDGETS: usize = 4;
+/// Maximum length of a widget label
+pub const MAX_LABEL_LEN: usize = NUM_DIGITS - 1;
+/// Samples kept by a sparkline, one per pixel column of the display
+pub const SPARKLINE_MAX_SAMPLES: usize = NUM_DIGITS * UDC_COLUMNS;
+
+/// Size of the fixed part of the `CMD_WIDGET_CONFIG` payload, the label follows
+const CONFIG_HEADER_SIZE: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WidgetKind {
+    /// Horizontal bar with one step per pixel column
+    Bar = 0,
+    /// One segment per digit
+    Gauge = 1,
+    /// History of the last values, one per pixel column
+    Sparkline = 2,
+}
+
+impl TryFrom<u8> for WidgetKind {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(WidgetKind::Bar),
+            1 => Ok(WidgetKind::Gauge),
+            2 => Ok(WidgetKind::Sparkline),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WidgetConfig {
+    pub id: u8,
+    pub kind: WidgetKind,
+    /// First digit used by the widget
+    pub pos: u8,
+    /// Number of digits used by the widget, label included
+    pub width: u8,
+    /// First UDC slot used by the widget
+    pub udc_base: u8,
+    /// Value drawn as a full bar
+    pub max: u16,
+    label: [u8; MAX_LABEL_LEN],
+    label_len: u8,
+}
+
+impl WidgetConfig {
+    pub fn new(id: u8, kind: WidgetKind, pos: u8, width: u8, udc_base: u8, max: u16) -> Self {
+        Self {
+            id,
+            kind,
+            pos,
+            width,
+            udc_base,
+            max,
+            label: [0x00; MAX_LABEL_LEN],
+            label_len: 0,
+        }
+    }
+
+    /// Sets the label, truncating it to `MAX_LABEL_LEN`
+    pub fn with_label(mut self, label: &[u8]) -> Self {
+        let len = label.len().min(MAX_LABEL_LEN);
+        self.label[..len].copy_from_slice(&label[..len]);
+        self.label_len = len as u8;
+        self
+    }
+
+    pub fn label(&self) -> &[u8] {
+        &self.label[..self.label_len as usize]
+    }
+
+    /// Number of digits left for the graphic part of the widget
+    pub fn cells(&self) -> usize {
+        (self.width as usize).saturating_sub(self.label_len as usize)
+    }
+
+    /// Number of UDC slots needed to draw the widget
+    pub fn udcs_needed(&self) -> usize {
+        match self.kind {
+            WidgetKind::Bar => 3,
+            WidgetKind::Gauge => 2,
+            WidgetKind::Sparkline => self.cells(),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.label_len as usize <= MAX_LABEL_LEN
+            && self.max > 0
+            && self.cells() > 0
+            && self.pos as usize + self.width as usize <= NUM_DIGITS
+            && self.udc_base as usize + self.udcs_needed() <= NUM_UDC
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut payload = [0x00; CONFIG_HEADER_SIZE + MAX_LABEL_LEN];
+        payload[0] = self.id;
+        payload[1] = self.kind as u8;
+        payload[2] = self.pos;
+        payload[3] = self.width;
+        payload[4] = self.udc_base;
+        payload[5..7].copy_from_slice(&self.max.to_le_bytes());
+        payload[CONFIG_HEADER_SIZE..CONFIG_HEADER_SIZE + self.label_len as usize]
+            .copy_from_slice(self.label());
+
+        let mut packet = Packet::new();
+        packet.set_command(Command::CMD_WIDGET_CONFIG.into());
+        packet.set_payload(&payload[..CONFIG_HEADER_SIZE + self.label_len as usize]);
+        packet
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() < CONFIG_HEADER_SIZE {
+            return None;
+        }
+
+        let kind = WidgetKind::try_from(payload[1]).ok()?;
+        let max = u16::from_le_bytes([payload[5], payload[6]]);
+        let config = WidgetConfig::new(payload[0], kind, payload[2], payload[3], payload[4], max)
+            .with_label(&payload[CONFIG_HEADER_SIZE..]);
+
+        Some(config)
+    }
+}
+
+/// `CMD_WIDGET_VALUE` packet updating the value of widget `id`
+pub fn value_to_packet(id: u8, value: u16) -> Packet {
+    let value = value.to_le_bytes();
+
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_WIDGET_VALUE.into());
+    packet.set_payload(&[id, value[0], value[1]]);
+    packet
+}
+
+/// Decodes a `CMD_WIDGET_VALUE` payload into `(id, value)`
+pub fn value_from_payload(payload: &[u8]) -> Option<(u8, u16)> {
+    match payload {
+        [id, lo, hi] => Some((*id, u16::from_le_bytes([*lo, *hi]))),
+        _ => None,
+    }
+}
+
+/// `CMD_WIDGET_REMOVE` packet removing widget `id`
+pub fn remove_to_packet(id: u8) -> Packet {
+    let mut packet = Packet::new();
+    packet.set_command(Command::CMD_WIDGET_REMOVE.into());
+    packet.set_payload(&[id]);
+    packet
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Widget {
+    config: WidgetConfig,
+    value: u16,
+    /// Sparkline history as column heights, oldest first
+    samples: [u8; SPARKLINE_MAX_SAMPLES],
+}
+
+impl Widget {
+    pub fn new(config: WidgetConfig) -> Self {
+        Self {
+            config,
+            value: 0,
+            samples: [0; SPARKLINE_MAX_SAMPLES],
+        }
+    }
+
+    pub fn config(&self) -> &WidgetConfig {
+        &self.config
+    }
+
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub fn set_value(&mut self, value: u16) {
+        self.value = value.min(self.config.max);
+
+        if self.config.kind == WidgetKind::Sparkline {
+            self.samples.copy_within(1.., 0);
+            self.samples[SPARKLINE_MAX_SAMPLES - 1] = self.scale(UDC_ROWS - 1) as u8 + 1;
+        }
+    }
+
+    /// Scales the value to `0..=steps`, rounding to the nearest step
+    fn scale(&self, steps: usize) -> usize {
+        let max = self.config.max as usize;
+        (self.value as usize * steps + max / 2) / max
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        let pos = self.config.pos as usize;
+        let label = self.config.label();
+        frame.put_str(pos, label);
+
+        let first_cell = pos + label.len();
+        let cells = self.config.cells();
+        let base = self.config.udc_base as usize;
+
+        match self.config.kind {
+            WidgetKind::Bar => {
+                let filled = self.scale(cells * UDC_COLUMNS);
+
+                frame.udcs[base] = bar_glyph(UDC_COLUMNS);
+                frame.udcs[base + 1] = bar_glyph(0);
+                frame.udcs[base + 2] = bar_glyph(filled % UDC_COLUMNS);
+
+                for i in 0..cells {
+                    let columns = filled.saturating_sub(i * UDC_COLUMNS).min(UDC_COLUMNS);
+                    let udc = match columns {
+                        UDC_COLUMNS => base,
+                        0 => base + 1,
+                        _ => base + 2,
+                    };
+                    frame.chars[first_cell + i] = hdsp::udc_char(udc as u8);
+                }
+            }
+            WidgetKind::Gauge => {
+                let lit = self.scale(cells);
+
+                frame.udcs[base] = segment_glyph(true);
+                frame.udcs[base + 1] = segment_glyph(false);
+
+                for i in 0..cells {
+                    let udc = if i < lit { base } else { base + 1 };
+                    frame.chars[first_cell + i] = hdsp::udc_char(udc as u8);
+                }
+            }
+            WidgetKind::Sparkline => {
+                let samples = &self.samples[SPARKLINE_MAX_SAMPLES - cells * UDC_COLUMNS..];
+
+                for (i, columns) in samples.chunks(UDC_COLUMNS).enumerate() {
+                    let mut glyph = hdsp::UDC_BLANK;
+                    for (column, &height) in columns.iter().enumerate() {
+                        for row in glyph[UDC_ROWS - height as usize..].iter_mut() {
+                            *row |= hdsp::udc_column_mask(column);
+                        }
+                    }
+
+                    frame.udcs[base + i] = glyph;
+                    frame.chars[first_cell + i] = hdsp::udc_char((base + i) as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Bar cell with the `filled` leftmost columns lit and the top and bottom
+/// rows of the rest as a track.
+fn bar_glyph(filled: usize) -> UdcBitmap {
+    let mut fill = 0x00;
+    for column in 0..filled {
+        fill |= hdsp::udc_column_mask(column);
+    }
+
+    let mut glyph = [fill; UDC_ROWS];
+    glyph[0] = hdsp::UDC_ROW_MASK;
+    glyph[UDC_ROWS - 1] = hdsp::UDC_ROW_MASK;
+    glyph
+}
+
+/// Gauge segment, a block when lit and a dash on the bottom row otherwise.
+/// The rightmost column is left off to separate segments.
+fn segment_glyph(lit: bool) -> UdcBitmap {
+    let segment = hdsp::UDC_ROW_MASK & !hdsp::udc_column_mask(UDC_COLUMNS - 1);
+    if lit {
+        [segment; UDC_ROWS]
+    } else {
+        let mut glyph = hdsp::UDC_BLANK;
+        glyph[UDC_ROWS - 1] = segment;
+        glyph
+    }
+}
+
+/// Set of widgets shown on top of the text
+#[derive(Clone)]
+pub struct Widgets {
+    slots: [Option<Widget>; MAX_WIDGETS],
+}
+
+impl Widgets {
+    pub fn new() -> Self {
+        Self {
+            slots: [None; MAX_WIDGETS],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(|slot| slot.is_none())
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Widget> {
+        self.slots.iter().flatten().find(|w| w.config.id == id)
+    }
+
+    fn get_mut(&mut self, id: u8) -> Option<&mut Widget> {
+        self.slots.iter_mut().flatten().find(|w| w.config.id == id)
+    }
+
+    /// Adds or replaces the widget with the id of `config`. Returns false if
+    /// the configuration is invalid or there is no free slot.
+    pub fn configure(&mut self, config: WidgetConfig) -> bool {
+        if !config.is_valid() {
+            return false;
+        }
+
+        if let Some(widget) = self.get_mut(config.id) {
+            *widget = Widget::new(config);
+            return true;
+        }
+
+        match self.slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(Widget::new(config));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_value(&mut self, id: u8, value: u16) -> bool {
+        match self.get_mut(id) {
+            Some(widget) => {
+                widget.set_value(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: u8) -> bool {
+        match self.slots.iter_mut().find(|slot| matches!(slot, Some(w) if w.config.id == id)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        for widget in self.slots.iter().flatten() {
+            widget.render(frame);
+        }
+    }
+}
+
+impl Default for Widgets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_widgets() {
+    let config = WidgetConfig::new(1, WidgetKind::Bar, 0, 8, 0, 40).with_label(b"CPU");
+    let packet = config.to_packet();
+    assert_eq!(packet.command(), Command::CMD_WIDGET_CONFIG.into());
+    assert_eq!(WidgetConfig::from_payload(packet.get_payload()), Some(config));
+
+    let mut widgets = Widgets::new();
+    assert!(widgets.configure(config));
+
+    // 5 cells after the label, 25 columns: 19/40 is 12 columns, 2 full cells and 2 columns
+    let (id, value) = value_from_payload(value_to_packet(1, 19).get_payload()).unwrap();
+    assert!(widgets.set_value(id, value));
+
+    let mut frame = Frame::new();
+    widgets.render(&mut frame);
+    assert_eq!(&frame.chars[..3], b"CPU");
+    assert_eq!(&frame.chars[3..], &[0x80, 0x80, 0x82, 0x81, 0x81]);
+    assert_eq!(frame.udcs[2][3], 0x18);
+
+    // A sparkline needs a UDC per cell, which does not fit from slot 10
+    let config = WidgetConfig::new(2, WidgetKind::Sparkline, 0, 8, 10, 100);
+    assert!(!widgets.configure(config));
+
+    assert!(widgets.remove(1));
+    assert!(widgets.is_empty());
+}